@@ -0,0 +1,276 @@
+//! `malloc`, `free`, and friends, implemented with `stack_alloc`
+//!
+//! This builds a shared library that can replace the C allocator in existing programs:
+//!
+//! ```sh
+//! cargo build --release -p stack_alloc_malloc
+//! LD_PRELOAD=target/release/libstack_alloc_malloc.so some-program
+//! ```
+//!
+//! C's `free` doesn't get told the size of the allocation, so it uses `Allocator::free`, which
+//! finds it out from the allocator's own bookkeeping.
+//!
+//! Built with the `trace` feature, it records a trace of the program's allocations to the file
+//! named by `STACK_ALLOC_TRACE`, for `stack_alloc-replay`:
+//!
+//! ```sh
+//! cargo build --release -p stack_alloc_malloc --features stack_alloc_malloc/trace
+//! STACK_ALLOC_TRACE=program.trace LD_PRELOAD=target/release/libstack_alloc_malloc.so some-program
+//! ```
+
+#![warn(
+    missing_docs,
+    missing_debug_implementations,
+    trivial_casts,
+    trivial_numeric_casts,
+    unused_import_braces,
+    unused_qualifications
+)]
+
+extern crate libc;
+extern crate stack_alloc;
+
+use std::alloc::{GlobalAlloc, Layout};
+use std::cmp;
+use std::ptr::{self, NonNull};
+
+use libc::{c_int, c_void, size_t};
+use stack_alloc::{Allocator, MmapSource};
+
+/// The alignment of `malloc`'s memory, enough for any C type
+const MIN_ALIGN: usize = 16;
+
+static HEAP: Allocator<MmapSource> = Allocator::new(MmapSource::new());
+
+/// Allocates memory with the given size and alignment
+unsafe fn alloc(size: usize, align: usize) -> *mut c_void {
+    // `malloc(0)` still has to return a unique pointer
+    match Layout::from_size_align(cmp::max(size, 1), cmp::max(align, MIN_ALIGN)) {
+        Ok(layout) => HEAP.alloc(layout) as *mut c_void,
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Allocates `size` bytes.
+///
+/// # Safety
+///
+/// Same as the C function.
+#[no_mangle]
+pub unsafe extern "C" fn malloc(size: size_t) -> *mut c_void {
+    alloc(size, MIN_ALIGN)
+}
+
+/// Allocates zeroed memory for `count` things of `size` bytes.
+///
+/// # Safety
+///
+/// Same as the C function.
+#[no_mangle]
+pub unsafe extern "C" fn calloc(count: size_t, size: size_t) -> *mut c_void {
+    let size = match count.checked_mul(size) {
+        Some(size) => size,
+        None => return ptr::null_mut(),
+    };
+    let ptr = alloc(size, MIN_ALIGN);
+    if !ptr.is_null() {
+        ptr::write_bytes(ptr as *mut u8, 0, size);
+    }
+    ptr
+}
+
+/// Frees memory from any of the allocation functions.
+///
+/// # Safety
+///
+/// Same as the C function.
+#[no_mangle]
+pub unsafe extern "C" fn free(ptr: *mut c_void) {
+    if let Some(ptr) = NonNull::new(ptr) {
+        HEAP.free(ptr.cast());
+    }
+}
+
+/// Changes the size of the allocation, moving it if necessary.
+///
+/// # Safety
+///
+/// Same as the C function.
+#[no_mangle]
+pub unsafe extern "C" fn realloc(ptr: *mut c_void, size: size_t) -> *mut c_void {
+    if ptr.is_null() {
+        return malloc(size);
+    }
+    if size == 0 {
+        free(ptr);
+        return ptr::null_mut();
+    }
+    if Layout::from_size_align(size, MIN_ALIGN).is_err() {
+        return ptr::null_mut();
+    }
+
+    // C's `realloc` only promises `malloc`'s alignment, even for memory from `aligned_alloc`
+    let usable_size = match HEAP.usable_size(NonNull::new_unchecked(ptr).cast()) {
+        Some(usable_size) => usable_size,
+        None => return ptr::null_mut(),
+    };
+    let layout = Layout::from_size_align_unchecked(usable_size, MIN_ALIGN);
+    HEAP.realloc(ptr as *mut u8, layout, size) as *mut c_void
+}
+
+/// Allocates aligned memory, storing the pointer in `*out`.
+///
+/// # Safety
+///
+/// Same as the C function.
+#[no_mangle]
+pub unsafe extern "C" fn posix_memalign(
+    out: *mut *mut c_void,
+    align: size_t,
+    size: size_t,
+) -> c_int {
+    if !align.is_power_of_two() || !align.is_multiple_of(size_of::<*mut c_void>()) {
+        return libc::EINVAL;
+    }
+    let ptr = alloc(size, align);
+    if ptr.is_null() {
+        return libc::ENOMEM;
+    }
+    *out = ptr;
+    0
+}
+
+/// Allocates aligned memory.
+///
+/// # Safety
+///
+/// Same as the C function.
+#[no_mangle]
+pub unsafe extern "C" fn aligned_alloc(align: size_t, size: size_t) -> *mut c_void {
+    if !align.is_power_of_two() {
+        return ptr::null_mut();
+    }
+    alloc(size, align)
+}
+
+/// Allocates aligned memory.  It's the obsolete version of `aligned_alloc`.
+///
+/// # Safety
+///
+/// Same as the C function.
+#[no_mangle]
+pub unsafe extern "C" fn memalign(align: size_t, size: size_t) -> *mut c_void {
+    aligned_alloc(align, size)
+}
+
+/// Returns how many bytes of the allocation can really be used.
+///
+/// # Safety
+///
+/// Same as the C function.
+#[no_mangle]
+pub unsafe extern "C" fn malloc_usable_size(ptr: *mut c_void) -> size_t {
+    NonNull::new(ptr)
+        .and_then(|ptr| HEAP.usable_size(ptr.cast()))
+        .unwrap_or(0)
+}
+
+/// Writes a dump of the heap to the file descriptor `fd`, for `stack_alloc-dump` to read.
+/// Returns 0 on success, or -1 if writing failed.
+///
+/// It's meant to be called from a debugger, like `call stack_alloc_dump(fd)` in gdb, to see what a
+/// misbehaving process's heap looks like.
+///
+/// # Safety
+///
+/// `fd` must be open for writing.
+#[no_mangle]
+pub unsafe extern "C" fn stack_alloc_dump(fd: c_int) -> c_int {
+    let written = HEAP.dump(|mut bytes| {
+        while !bytes.is_empty() {
+            let n = libc::write(fd, bytes.as_ptr().cast(), bytes.len());
+            if n < 0 {
+                return Err(());
+            }
+            bytes = &bytes[n as usize..];
+        }
+        Ok(())
+    });
+    match written {
+        Ok(()) => 0,
+        Err(()) => -1,
+    }
+}
+
+/// Runs `start_trace` when the library is loaded
+#[cfg(all(feature = "trace", target_os = "linux"))]
+#[used]
+#[link_section = ".init_array"]
+static START_TRACE: extern "C" fn() = start_trace;
+
+/// Starts tracing to the file named by `STACK_ALLOC_TRACE`, if it's set, and stops when the program
+/// exits
+#[cfg(all(feature = "trace", target_os = "linux"))]
+extern "C" fn start_trace() {
+    extern "C" fn stop_trace() {
+        HEAP.stop_trace();
+    }
+
+    unsafe {
+        let path = libc::getenv(b"STACK_ALLOC_TRACE\0".as_ptr().cast());
+        if path.is_null() {
+            return;
+        }
+        let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_CLOEXEC;
+        let fd = libc::open(path, flags, 0o644);
+        if fd >= 0 {
+            HEAP.start_trace(fd);
+            libc::atexit(stop_trace);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn over_aligned() {
+        for &align in &[4096, 8192, 65536, 1 << 20] {
+            let ptrs: Vec<_> = (0..20)
+                .flat_map(|i| unsafe {
+                    let mut out = ptr::null_mut();
+                    assert_eq!(posix_memalign(&mut out, align, 1 + i * 100), 0);
+                    [out, aligned_alloc(align, i * 100), memalign(align, 1 + i)]
+                })
+                .collect();
+            for &ptr in &ptrs {
+                assert!(!ptr.is_null() && (ptr as usize).is_multiple_of(align));
+                unsafe {
+                    assert!(malloc_usable_size(ptr) >= 1);
+                    ptr.cast::<u8>().write(1);
+                }
+            }
+            for ptr in ptrs {
+                unsafe {
+                    let ptr = realloc(ptr, 10_000);
+                    assert_eq!(ptr.cast::<u8>().read(), 1);
+                    free(ptr);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn realloc_too_big() {
+        unsafe {
+            let ptr = malloc(100);
+            ptr.cast::<u8>().write(1);
+            assert!(realloc(ptr, usize::MAX).is_null());
+            assert!(realloc(ptr, isize::MAX as usize).is_null());
+            // The old memory is left alone
+            assert_eq!(ptr.cast::<u8>().read(), 1);
+            free(ptr);
+        }
+    }
+}
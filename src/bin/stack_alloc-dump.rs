@@ -0,0 +1,146 @@
+//! Prints what's in a heap dump from `Allocator::dump`: how full and fragmented each size of stack
+//! is, where the allocator's records are, and a map of every stack.
+//!
+//! ```sh
+//! stack_alloc-dump heap.dump
+//! ```
+
+extern crate stack_alloc;
+
+use std::env;
+use std::fs;
+use std::process;
+use std::str;
+
+use stack_alloc::check::Bucket;
+use stack_alloc::dump::{Reader, Record, StackRecord};
+
+const BUCKETS: [Bucket; 6] = [
+    Bucket::VerySmall,
+    Bucket::Small,
+    Bucket::Medium,
+    Bucket::Large,
+    Bucket::VeryLarge,
+    Bucket::Metadata,
+];
+
+fn percent(part: usize, whole: usize) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        100.0 * part as f64 / whole as f64
+    }
+}
+
+/// Prints the totals for one bucket
+fn summarize(bucket: Bucket, stacks: &[StackRecord]) {
+    let stacks: Vec<_> = stacks.iter().filter(|s| s.bucket == bucket).collect();
+    let chunks = stacks.len() * 64;
+    let used: usize = stacks.iter().map(|s| s.used_chunks()).sum();
+    let holes: usize = stacks.iter().map(|s| s.hole_chunks()).sum();
+    let free = chunks - used;
+    let largest = stacks
+        .iter()
+        .map(|s| s.largest_free_run())
+        .max()
+        .unwrap_or(0);
+    println!(
+        "{:<10} {:>7} stacks  {:>9}/{:<9} chunks used ({:5.1}%)  {:5.1}% of free chunks in holes  \
+         longest free run {}",
+        format!("{:?}", bucket),
+        stacks.len(),
+        used,
+        chunks,
+        percent(used, chunks),
+        percent(holes, free),
+        largest,
+    );
+}
+
+fn main() {
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("usage: stack_alloc-dump <heap dump>");
+            process::exit(2);
+        }
+    };
+    let bytes = fs::read(&path).unwrap_or_else(|err| {
+        eprintln!("can't read {}: {}", path, err);
+        process::exit(1);
+    });
+
+    let mut stacks = Vec::new();
+    let mut huge = Vec::new();
+    let reader = Reader::new(&bytes).and_then(|reader| {
+        for record in reader {
+            match record? {
+                Record::Stack(stack) => stacks.push(stack),
+                Record::Huge {
+                    record,
+                    address,
+                    size,
+                } => huge.push((record, address, size)),
+            }
+        }
+        Ok(())
+    });
+    if let Err(err) = reader {
+        eprintln!("{}: {}", path, err);
+        process::exit(1);
+    }
+
+    println!("== Occupancy ==");
+    for &bucket in BUCKETS.iter() {
+        summarize(bucket, &stacks);
+    }
+    let huge_bytes: usize = huge.iter().map(|&(_, _, size)| size).sum();
+    println!(
+        "Huge       {:>7} allocations, {} bytes",
+        huge.len(),
+        huge_bytes
+    );
+
+    // Every record should be in one of the metadata stacks
+    let in_metadata = |addr: usize| {
+        stacks
+            .iter()
+            .position(|s| s.bucket == Bucket::Metadata && s.contains(addr))
+    };
+    let misplaced = stacks
+        .iter()
+        .map(|s| s.record)
+        .chain(huge.iter().map(|&(record, _, _)| record))
+        .filter(|&record| in_metadata(record).is_none())
+        .count();
+    println!();
+    println!("== Metadata ==");
+    println!("{} records outside the metadata chain", misplaced);
+
+    println!();
+    println!("== Stacks ==");
+    println!("# starts an allocation, = continues it, . is a hole, and blank is above the top");
+    for &bucket in BUCKETS.iter() {
+        println!("{:?}:", bucket);
+        for stack in stacks.iter().filter(|s| s.bucket == bucket) {
+            let map = stack.map();
+            let record = match in_metadata(stack.record) {
+                Some(index) => format!("record in metadata stack {:#x}", stacks[index].bottom),
+                None => "record outside the metadata chain".to_string(),
+            };
+            println!(
+                "  {:#014x} x{:<5} [{}]  {}",
+                stack.bottom,
+                stack.chunk_size,
+                str::from_utf8(&map).unwrap(),
+                record
+            );
+        }
+    }
+    if !huge.is_empty() {
+        println!("Huge:");
+        for &(_, address, size) in huge.iter() {
+            println!("  {:#014x} {} bytes", address, size);
+        }
+    }
+}
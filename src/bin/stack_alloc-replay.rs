@@ -0,0 +1,236 @@
+//! Replays a trace from `Allocator::start_trace` against a fresh heap, and prints how long it took
+//! and how much memory it needed.
+//!
+//! ```sh
+//! stack_alloc-replay program.trace
+//! ```
+//!
+//! The events are sorted by their times, and replayed in that order on one thread, with a
+//! `TestMemorySource`.  Frees of memory allocated before the trace started are skipped, and
+//! reallocations of it are replayed as allocations.  If the replay runs out of memory where the
+//! trace didn't, that allocation's skipped too, and a reallocation leaves the old memory where it
+//! was.
+
+extern crate stack_alloc;
+
+use std::alloc::{GlobalAlloc, Layout};
+use std::cmp;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::process;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use stack_alloc::lock::NoLock;
+use stack_alloc::memory_source::BLOCK_SIZE;
+use stack_alloc::trace::{Event, Op, Reader};
+use stack_alloc::{Allocator, MemorySource, TestMemorySource};
+
+/// A `TestMemorySource` that keeps track of how much memory it's handed out
+#[derive(Debug, Default)]
+struct MeasuredSource {
+    in_use: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl MeasuredSource {
+    fn add(&self, bytes: usize) {
+        let in_use = self.in_use.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        self.peak.fetch_max(in_use, Ordering::Relaxed);
+    }
+
+    fn remove(&self, bytes: usize) {
+        self.in_use.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+unsafe impl MemorySource for MeasuredSource {
+    unsafe fn get_block(&self) -> Option<NonNull<u8>> {
+        let block = TestMemorySource.get_block()?;
+        self.add(BLOCK_SIZE);
+        Some(block)
+    }
+
+    unsafe fn return_block(&self, block: NonNull<u8>) {
+        self.remove(BLOCK_SIZE);
+        TestMemorySource.return_block(block);
+    }
+
+    unsafe fn get_huge(&self, layout: Layout) -> Option<NonNull<u8>> {
+        let memory = TestMemorySource.get_huge(layout)?;
+        self.add(layout.size());
+        Some(memory)
+    }
+
+    unsafe fn return_huge(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.remove(layout.size());
+        TestMemorySource.return_huge(ptr, layout);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Replay {
+    /// The memory from the replay for each pointer in the trace that's still live
+    live: HashMap<usize, (*mut u8, Layout)>,
+    live_bytes: usize,
+    peak_live_bytes: usize,
+    /// Frees and reallocations of memory that the trace never saw allocated
+    unmatched: usize,
+    /// Allocations that failed in the trace, and weren't replayed
+    failed: usize,
+    /// Allocations that worked in the trace, but the replay ran out of memory for
+    out_of_memory: usize,
+}
+
+impl Replay {
+    fn insert(&mut self, traced: usize, ptr: *mut u8, layout: Layout) {
+        if ptr.is_null() {
+            self.out_of_memory += 1;
+            return;
+        }
+        self.live_bytes += layout.size();
+        self.peak_live_bytes = cmp::max(self.peak_live_bytes, self.live_bytes);
+        self.live.insert(traced, (ptr, layout));
+    }
+
+    fn remove(&mut self, traced: usize) -> Option<(*mut u8, Layout)> {
+        let (ptr, layout) = self.live.remove(&traced)?;
+        self.live_bytes -= layout.size();
+        Some((ptr, layout))
+    }
+
+    unsafe fn run<S: MemorySource>(&mut self, heap: &Allocator<S, NoLock>, event: &Event) {
+        match event.op {
+            Op::Alloc if event.ptr == 0 => self.failed += 1,
+            Op::Alloc => {
+                let layout = Layout::from_size_align(event.size, event.align).unwrap();
+                self.insert(event.ptr, heap.alloc(layout), layout);
+            }
+            Op::Dealloc => match self.remove(event.ptr) {
+                Some((ptr, layout)) => heap.dealloc(ptr, layout),
+                None => self.unmatched += 1,
+            },
+            Op::Realloc if event.new_ptr == 0 => self.failed += 1,
+            Op::Realloc => {
+                let new_layout = Layout::from_size_align(event.new_size, event.new_align).unwrap();
+                let (ptr, layout) = match self.remove(event.ptr) {
+                    Some(old) => old,
+                    None => {
+                        // The old memory is from before the trace, so there's nothing to move
+                        self.unmatched += 1;
+                        self.insert(event.new_ptr, heap.alloc(new_layout), new_layout);
+                        return;
+                    }
+                };
+                let new_ptr = if layout.align() == new_layout.align() {
+                    heap.realloc(ptr, layout, new_layout.size())
+                } else {
+                    // `realloc` can't change the alignment, so it's moved by hand, like `grow` does
+                    let new_ptr = heap.alloc(new_layout);
+                    if !new_ptr.is_null() {
+                        ptr::copy_nonoverlapping(
+                            ptr,
+                            new_ptr,
+                            cmp::min(layout.size(), new_layout.size()),
+                        );
+                        heap.dealloc(ptr, layout);
+                    }
+                    new_ptr
+                };
+                if new_ptr.is_null() {
+                    // The old memory's still there, so it's what the trace frees later
+                    self.out_of_memory += 1;
+                    self.insert(event.new_ptr, ptr, layout);
+                } else {
+                    self.insert(event.new_ptr, new_ptr, new_layout);
+                }
+            }
+        }
+    }
+}
+
+fn main() {
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("usage: stack_alloc-replay <trace>");
+            process::exit(2);
+        }
+    };
+    let bytes = fs::read(&path).unwrap_or_else(|err| {
+        eprintln!("can't read {}: {}", path, err);
+        process::exit(1);
+    });
+    let events = Reader::new(&bytes).and_then(|reader| reader.collect::<Result<Vec<_>, _>>());
+    let mut events = events.unwrap_or_else(|err| {
+        eprintln!("{}: {}", path, err);
+        process::exit(1);
+    });
+    // Events from different threads can be written out of order
+    events.sort_by_key(|event| event.time);
+
+    let count = |op| events.iter().filter(|event| event.op == op).count();
+    let mut threads: Vec<_> = events.iter().map(|event| event.thread).collect();
+    threads.sort_unstable();
+    threads.dedup();
+    let traced_time = match (events.first(), events.last()) {
+        (Some(first), Some(last)) => last.time - first.time,
+        _ => 0,
+    };
+    println!(
+        "{} events from {} threads over {:.3} s: {} allocations, {} frees, {} reallocations",
+        events.len(),
+        threads.len(),
+        traced_time as f64 / 1e9,
+        count(Op::Alloc),
+        count(Op::Dealloc),
+        count(Op::Realloc),
+    );
+
+    let heap: Allocator<_, NoLock> = Allocator::with_lock(MeasuredSource::default());
+    let mut replay = Replay::default();
+    let start = Instant::now();
+    for event in events.iter() {
+        unsafe { replay.run(&heap, event) };
+    }
+    let elapsed = start.elapsed();
+
+    println!(
+        "Replayed in {:.3} ms, {:.1} ns per event",
+        elapsed.as_secs_f64() * 1e3,
+        elapsed.as_nanos() as f64 / cmp::max(events.len(), 1) as f64,
+    );
+    let peak = heap.source().peak.load(Ordering::Relaxed);
+    println!(
+        "Peak memory from the source: {} bytes, for at most {} bytes live ({:.1}% overhead)",
+        peak,
+        replay.peak_live_bytes,
+        100.0 * (peak as f64 / cmp::max(replay.peak_live_bytes, 1) as f64 - 1.0),
+    );
+    println!(
+        "Still live at the end: {} allocations, {} bytes",
+        replay.live.len(),
+        replay.live_bytes
+    );
+    if replay.out_of_memory > 0 {
+        println!(
+            "{} allocations and reallocations that the replay ran out of memory for",
+            replay.out_of_memory
+        );
+    }
+    if replay.unmatched > 0 || replay.failed > 0 {
+        println!(
+            "{} frees and reallocations of memory from before the trace, and {} failed allocations",
+            replay.unmatched, replay.failed
+        );
+    }
+    match heap.check() {
+        Ok(()) => println!("The heap checks out"),
+        Err(err) => {
+            println!("The heap is broken: {}", err);
+            process::exit(1);
+        }
+    }
+}
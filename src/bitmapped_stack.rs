@@ -2,13 +2,29 @@
 //!
 //! This way it can actually de-allocate things.
 
-use alloc::alloc::{self, AllocErr, Layout};
+use alloc::alloc::{AllocError, Layout};
+use core::cmp;
+use core::iter;
 use core::ops;
 use core::ptr::NonNull;
 
+use check::Problem;
+
 /// The size, in chunks, of each bitmapped stack
 pub const STACK_SIZE: usize = 64;
 
+/// Returns the length of the longest run of free chunks in a stack's bitmap
+pub(crate) fn largest_free_run(bitmap: u64) -> usize {
+    // Each step shortens every run of free chunks by one
+    let mut free = !bitmap;
+    let mut run = 0;
+    while free != 0 {
+        free &= free << 1;
+        run += 1;
+    }
+    run
+}
+
 /// Rounds the given number up to fit the alignment.
 /// `alignment` must be a power of 2.
 fn round_up_to_alignment(x: usize, alignment: usize) -> usize {
@@ -21,6 +37,16 @@ fn round_up_to_alignment(x: usize, alignment: usize) -> usize {
     }
 }
 
+/// Returns a bitmap with the bits for the chunks in the range set
+fn chunk_mask(chunk_range: ops::Range<usize>) -> u64 {
+    let num_chunks = chunk_range.size_hint().0;
+    if num_chunks == 0 {
+        return 0;
+    }
+    // Shifting right, since `(1 << 64) - 1` would overflow for a full stack
+    (!0_u64 >> (64 - num_chunks)) << chunk_range.start
+}
+
 /// An upwards-growing stack
 #[derive(Debug)]
 pub struct BitmappedStack {
@@ -32,6 +58,10 @@ pub struct BitmappedStack {
     chunk_size: usize,
     /// Each bit is one chunk
     bitmap: u64,
+    /// Each bit is set if an allocation starts at that chunk
+    ///
+    /// Together with `bitmap`, this tells where each allocation starts and ends.
+    starts: u64,
 }
 
 impl BitmappedStack {
@@ -42,6 +72,7 @@ impl BitmappedStack {
             current_height: 0,
             chunk_size,
             bitmap: 0x0000000000000000,
+            starts: 0x0000000000000000,
         }
     }
 
@@ -63,75 +94,162 @@ impl BitmappedStack {
         self.bottom
     }
 
+    /// Returns the height of the stack, in chunks
+    pub fn height(&self) -> usize {
+        self.current_height
+    }
+
+    /// Returns the bitmap of allocated chunks
+    pub fn bitmap(&self) -> u64 {
+        self.bitmap
+    }
+
+    /// Returns the bitmap of the chunks where allocations start
+    pub fn starts(&self) -> u64 {
+        self.starts
+    }
+
     /// Returns true iff there are no allocations on the stack
     pub fn is_empty(&self) -> bool {
         self.current_height == 0
     }
 
-    /// Returns the number of chunks left in the stack
-    pub fn chunks_left(&self) -> usize {
-        debug_assert!(STACK_SIZE >= self.current_height);
-        STACK_SIZE - self.current_height
+    /// Returns the number of chunks that are allocated
+    #[cfg(feature = "stats")]
+    pub fn used_chunks(&self) -> usize {
+        self.bitmap.count_ones() as usize
+    }
+
+    /// Returns the length of the longest run of free chunks, counting the holes below the top of
+    /// the stack as well as the room above it
+    pub fn largest_free_run(&self) -> usize {
+        largest_free_run(self.bitmap)
+    }
+
+    /// Checks that the height and the starts of the allocations agree with the bitmap
+    pub fn check(&self) -> Result<(), Problem> {
+        let expected = 64 - self.bitmap.leading_zeros() as usize;
+        if self.current_height != expected {
+            return Err(Problem::WrongHeight {
+                height: self.current_height,
+                expected,
+            });
+        }
+        if self.starts & !self.bitmap != 0 {
+            return Err(Problem::StartOutsideBitmap);
+        }
+        // The first chunk of each run of allocated chunks has to be the start of an allocation
+        let run_starts = self.bitmap & !(self.bitmap << 1);
+        if run_starts & !self.starts != 0 {
+            return Err(Problem::UnstartedChunks);
+        }
+        Ok(())
     }
 
     /// `debug_assert`s that the allocator is completely deallocated
     pub fn debug_assert_empty(&self) {
         debug_assert_eq!(self.bitmap, 0, "The mask is not zero :(");
+        debug_assert_eq!(self.starts, 0, "The starts mask is not zero :(");
         debug_assert_eq!(self.current_height, 0, "The height is not zero :(");
     }
 
+    /// Returns whether the chunk that `pointer` is in is allocated
+    #[cfg(feature = "checked")]
+    pub fn is_allocated(&self, pointer: *const u8) -> bool {
+        let chunk = (pointer as usize - self.bottom.as_ptr() as usize) / self.chunk_size;
+        self.owns(pointer) && self.bitmap & (1 << chunk) != 0
+    }
+
+    /// Returns the number of chunks in the allocation that starts at `pointer`, or `None` if no
+    /// allocation starts there.
+    pub fn allocation_chunks(&self, pointer: *const u8) -> Option<usize> {
+        if !self.owns(pointer) {
+            return None;
+        }
+        let offset = pointer as usize - self.bottom.as_ptr() as usize;
+        if !offset.is_multiple_of(self.chunk_size) {
+            return None;
+        }
+        let start_chunk = offset / self.chunk_size;
+        if self.starts & (1 << start_chunk) == 0 {
+            return None;
+        }
+        // The allocation ends at the next free chunk or the next start of an allocation,
+        // whichever comes first
+        let boundaries = (!self.bitmap | self.starts)
+            .checked_shr(start_chunk as u32 + 1)
+            .unwrap_or(0);
+        let chunks = 1 + boundaries.trailing_zeros() as usize;
+        Some(cmp::min(chunks, STACK_SIZE - start_chunk))
+    }
+
+    /// Returns an iterator over the allocations on the stack: where each one starts, and how many
+    /// chunks it has
+    pub fn allocations(&self) -> impl Iterator<Item = (NonNull<u8>, usize)> + '_ {
+        let mut starts = self.starts;
+        iter::from_fn(move || {
+            if starts == 0 {
+                return None;
+            }
+            let ptr = self.chunk_to_ptr(starts.trailing_zeros() as usize);
+            starts &= starts - 1;
+            Some((ptr, self.allocation_chunks(ptr.as_ptr())?))
+        })
+    }
+
+    /// Returns a mask of the chunks whose addresses are aligned to `align`
+    fn aligned_chunks(&self, align: usize) -> u64 {
+        if align <= self.chunk_size {
+            return !0;
+        }
+        let step = align / self.chunk_size;
+        let bottom = self.bottom.as_ptr() as usize;
+        let mut chunk = (round_up_to_alignment(bottom, align) - bottom) / self.chunk_size;
+        let mut mask = 0;
+        while chunk < STACK_SIZE {
+            mask |= 1 << chunk;
+            chunk += step;
+        }
+        mask
+    }
+
+    /// Finds the first run of `chunks` free chunks that starts at an address aligned to `align`
+    fn find_hole(&self, chunks: usize, align: usize) -> Option<usize> {
+        // Each step leaves only the chunks that are followed by one more free chunk
+        let mut starts = !self.bitmap;
+        for _ in 1..chunks {
+            starts &= starts >> 1;
+        }
+        starts &= self.aligned_chunks(align);
+        if starts == 0 {
+            None
+        } else {
+            Some(starts.trailing_zeros() as usize)
+        }
+    }
+
     /// Returns the number of chunks required for the given number of bytes
     fn chunks_for(&self, bytes: usize) -> usize {
         // Divide by chunk size, rounding up
-        let mut res = bytes / self.chunk_size;
-        if bytes % self.chunk_size != 0 {
-            res += 1;
-        }
-        res
+        bytes.div_ceil(self.chunk_size)
     }
 
     /// Mark the chunks as allocated in the bitmap
     unsafe fn bitmap_allocate(&mut self, chunk_range: ops::Range<usize>) {
         debug_assert!(chunk_range.end <= STACK_SIZE);
-
-        let num_chunks = chunk_range.size_hint().0;
-        if num_chunks > 0 {
-            let mask = {
-                // Wrapping ops in case of (1 << 64) - 1 which overflows
-                let mask_base = 1_u64.wrapping_shl(num_chunks as u32).wrapping_sub(1);
-                mask_base << chunk_range.start
-            };
-
-            self.bitmap |= mask;
-        }
+        self.bitmap |= chunk_mask(chunk_range);
     }
 
     /// Mark the chunks as deallocated in the bitmap
     unsafe fn bitmap_deallocate(&mut self, chunk_range: ops::Range<usize>) {
         debug_assert!(chunk_range.end <= STACK_SIZE);
-
-        let num_chunks = chunk_range.size_hint().0;
-        if num_chunks > 0 {
-            let mask = {
-                // Wrapping ops in case of (1 << 64) - 1 which overflows
-                let mask_base = 1_u64.wrapping_shl(num_chunks as u32).wrapping_sub(1);
-                mask_base << chunk_range.start
-            };
-
-            self.bitmap &= !mask;
-        }
+        self.bitmap &= !chunk_mask(chunk_range);
     }
 
     /// Returns `true` if all the chunks in the range are marked as deallocated in the bitmap
     fn all_deallocated(&self, chunk_range: ops::Range<usize>) -> bool {
         debug_assert!(chunk_range.end <= STACK_SIZE);
-        let mask = {
-            let num_chunks = chunk_range.size_hint().0;
-            let mask_base = (1 << num_chunks) - 1;
-            mask_base << chunk_range.start
-        };
-
-        self.bitmap & mask == 0
+        self.bitmap & chunk_mask(chunk_range) == 0
     }
 
     /// Returns the chunk number associated with the pointer.
@@ -158,7 +276,7 @@ impl BitmappedStack {
         self.current_height = 64 - self.bitmap.leading_zeros() as usize;
     }
 
-    pub unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+    pub unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
         debug_log!(
             "Allocing: align %zu, size %zu\n\0",
             layout.align(),
@@ -171,18 +289,28 @@ impl BitmappedStack {
             self.ptr_to_chunk(aligned_stack_ptr as *mut u8)
         };
 
-        if bottom_of_alloc * self.chunk_size + layout.size() > STACK_SIZE * self.chunk_size {
+        let chunks = self.chunks_for(layout.size());
+
+        // Bumping the top of the stack is the fast case, but if there's no room there, a hole
+        // left by an earlier deallocation might fit it
+        let bottom_of_alloc = if bottom_of_alloc + chunks <= STACK_SIZE {
+            bottom_of_alloc
+        } else if let Some(hole) = self.find_hole(chunks, layout.align()) {
+            debug_log!("    Reusing the hole at chunk %zu\n\0", hole);
+            hole
+        } else {
             debug_log!("Exhausted BitmappedStack:\n  chunk_size: %zu\n  current_height: %zu\n  bitmap: %#018zx\n\0",
                 self.chunk_size,
                 self.current_height,
                 self.bitmap
                 );
-            return Err(AllocErr);
-        }
+            return Err(AllocError);
+        };
 
-        let new_height = bottom_of_alloc + self.chunks_for(layout.size());
-        self.bitmap_allocate(bottom_of_alloc..new_height);
-        self.current_height = new_height;
+        let end_of_alloc = bottom_of_alloc + chunks;
+        self.bitmap_allocate(bottom_of_alloc..end_of_alloc);
+        self.starts |= 1 << bottom_of_alloc;
+        self.current_height = cmp::max(self.current_height, end_of_alloc);
         debug_log!("    Bitmap is now %#018jx\n\0", self.bitmap);
         Ok(self.chunk_to_ptr(bottom_of_alloc))
     }
@@ -197,6 +325,7 @@ impl BitmappedStack {
         let start_chunk = self.ptr_to_chunk(ptr.as_ptr());
         let end_chunk = start_chunk + self.chunks_for(layout.size());
         self.bitmap_deallocate(start_chunk..end_chunk);
+        self.starts &= !(1 << start_chunk);
         if self.current_height == end_chunk {
             self.current_height = start_chunk;
             self.shrink_height();
@@ -216,6 +345,9 @@ impl BitmappedStack {
         let new_end = self.ptr_to_chunk(ptr.as_ptr()) + new_chunks;
         let old_end = self.ptr_to_chunk(ptr.as_ptr()) + old_chunks;
         self.bitmap_deallocate(new_end..old_end);
+        if new_chunks == 0 {
+            self.starts &= !(1 << self.ptr_to_chunk(ptr.as_ptr()));
+        }
         if self.current_height == old_end {
             self.current_height = new_end;
             if new_size == 0 {
@@ -230,7 +362,7 @@ impl BitmappedStack {
         ptr: NonNull<u8>,
         layout: Layout,
         new_size: usize,
-    ) -> Result<(), alloc::CannotReallocInPlace> {
+    ) -> Result<(), AllocError> {
         debug_log!(
             "Growing: align %zu, size %zu to %zu\n\0",
             layout.align(),
@@ -246,7 +378,7 @@ impl BitmappedStack {
             return Ok(());
         }
         if new_end > STACK_SIZE {
-            return Err(alloc::CannotReallocInPlace);
+            return Err(AllocError);
         }
         debug_assert!(old_end < new_end);
         if old_end == self.current_height {
@@ -260,7 +392,7 @@ impl BitmappedStack {
                 debug_log!("    Bitmap is now %#018jx\n\0", self.bitmap);
                 Ok(())
             } else {
-                Err(alloc::CannotReallocInPlace)
+                Err(AllocError)
             }
         }
     }
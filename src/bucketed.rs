@@ -4,15 +4,53 @@
 //! ranging from 1 byte to 4 KiB.
 //!
 //! TODO better docs
+//!
+//! # Locking
+//!
+//! The `very_small`, `small`, `medium` and `large` chains each have their own lock, so that threads
+//! allocating different sizes don't wait for each other.  The rest of the heap, that is the
+//! `very_large` and `metadata` chains, the huge allocations and the page map's spare nodes, is
+//! behind one more lock, the core lock.  The `very_large` and `metadata` chains share it because
+//! extending either one can need the other.
+//!
+//! A chain that runs out of room gets a new stack from the chain above it while still holding its
+//! own lock (see the `extend_*` functions), and then takes the core lock to store the new
+//! allocator's metadata.  So the locks are always taken in this order:
+//!
+//!  1. `very_small`
+//!  2. `small`
+//!  3. `medium`
+//!  4. `large`
+//!  5. the core lock
+//!
+//! Any of them can be skipped, but a thread holding one must never wait for one earlier in the
+//! list.  The page map can be read without any locks, and only changes with the core lock held.
+//! With the `quarantine` feature, the quarantine has a lock too, which comes after all the others.
+//! With the `checked` feature, the `Allocator` holds one more lock from when it checks a free until
+//! the free's done, and that one comes before all the others.
 
-use core::alloc::{self, Alloc, Layout};
-use core::ops::DerefMut;
+use core::alloc::{self, Layout};
+use core::cmp;
 use core::ptr;
 
 use bitmapped_stack::STACK_SIZE;
-use memory_source::MemorySource;
+use check::{Bucket, CheckError, Problem};
+#[cfg(feature = "checked")]
+use checked::FreeError;
+use dump::{self, Record, StackRecord};
+use huge::{self, HugeAllocation};
+use lock::{Locked, RawLock};
+use memory_source::{MemorySource, BLOCK_SIZE};
 use metadata_box::MetadataBox;
-use sized_allocator::{DeallocResponse, SizedAllocator};
+use page_map::{NodePool, Owner, PageEntry, PageMap, PAGE_SIZE};
+#[cfg(feature = "quarantine")]
+use quarantine::{self, Quarantine};
+#[cfg(feature = "red_zones")]
+use red_zone::{self, RedZoneError};
+use sized_allocator::{Chain, DeallocResponse, SizedAllocator};
+#[cfg(feature = "stats")]
+use stats::{BucketStats, Counters, Stats};
+use visit::Allocation;
 
 const VERY_SMALL_CHUNK_SIZE: usize = 1;
 const SMALL_CHUNK_SIZE: usize = 8;
@@ -30,8 +68,20 @@ enum SizeCategory {
     Medium,
     Large,
     VeryLarge,
+    Huge,
 }
 impl SizeCategory {
+    /// Chooses the category for an allocation with this layout.
+    ///
+    /// Over-aligned allocations go by their alignment instead of their size, since the stacks of
+    /// the smaller categories mostly have nowhere that aligned.
+    fn of(layout: Layout) -> Option<Self> {
+        if layout.size() == 0 {
+            return None;
+        }
+        Self::choose(cmp::max(layout.size(), layout.align()))
+    }
+
     fn choose(size: usize) -> Option<Self> {
         match size {
             0 => None,
@@ -39,271 +89,429 @@ impl SizeCategory {
             8..=63 => Some(SizeCategory::Small),
             64..=511 => Some(SizeCategory::Medium),
             512..=4095 => Some(SizeCategory::Large),
-            4096..=262144 => Some(SizeCategory::VeryLarge),
-            _ => None,
+            4096..=BLOCK_SIZE => Some(SizeCategory::VeryLarge),
+            _ => Some(SizeCategory::Huge),
+        }
+    }
+
+    /// The size of the largest allocation in this category
+    fn max_size(self) -> usize {
+        match self {
+            SizeCategory::VerySmall => SMALL_CHUNK_SIZE - 1,
+            SizeCategory::Small => MEDIUM_CHUNK_SIZE - 1,
+            SizeCategory::Medium => LARGE_CHUNK_SIZE - 1,
+            SizeCategory::Large => VERY_LARGE_CHUNK_SIZE - 1,
+            SizeCategory::VeryLarge => BLOCK_SIZE,
+            SizeCategory::Huge => usize::MAX,
+        }
+    }
+
+    /// The chunk size of the allocators in this category
+    fn chunk_size(self) -> usize {
+        match self {
+            SizeCategory::VerySmall => VERY_SMALL_CHUNK_SIZE,
+            SizeCategory::Small => SMALL_CHUNK_SIZE,
+            SizeCategory::Medium => MEDIUM_CHUNK_SIZE,
+            SizeCategory::Large => LARGE_CHUNK_SIZE,
+            SizeCategory::VeryLarge => VERY_LARGE_CHUNK_SIZE,
+            SizeCategory::Huge => 1,
+        }
+    }
+
+    /// Which owner the page map records the allocators in this category as
+    fn page_owner(self) -> Option<Owner> {
+        match self {
+            SizeCategory::VerySmall | SizeCategory::Small => Some(Owner::Child),
+            SizeCategory::Medium => Some(Owner::Medium),
+            SizeCategory::Large => Some(Owner::Large),
+            SizeCategory::VeryLarge => Some(Owner::Block),
+            SizeCategory::Huge => None,
         }
     }
 }
 
+/// Returns how many bytes of an allocation with the given layout can really be used.
+///
+/// Allocations take up a whole number of chunks, so there's usually some room left over at the end.
+/// It's never rounded past the size category, though, so the allocation can still be freed with
+/// any size between the requested size and the usable size.
+pub(crate) fn usable_size(layout: Layout) -> usize {
+    match SizeCategory::of(layout) {
+        // Allocations that are only huge because they're so aligned are padded out past a block,
+        // so that any size they're freed with still says they're huge
+        Some(SizeCategory::Huge) => cmp::max(layout.size(), BLOCK_SIZE + 1),
+        Some(category) => {
+            let chunk_size = category.chunk_size();
+            let rounded = layout.size().div_ceil(chunk_size) * chunk_size;
+            cmp::min(rounded, category.max_size())
+        }
+        None => 0,
+    }
+}
+
+/// Returns how many bytes of an allocation with the given layout the caller gets to use.  That's
+/// the usable size, except with red zones, where everything past what was asked for is canaries.
+pub(crate) fn visible_size(layout: Layout) -> usize {
+    if cfg!(feature = "red_zones") {
+        layout.size()
+    } else {
+        usable_size(layout)
+    }
+}
+
 /// The `BucketedAllocator` buckets allocations into small (size < 64 bytes), medium (64 bytes < size <
 /// 4 KiB) and large (4 KiB < size).
 ///
 /// The associated lifetime is for references to both the `Buckets` and to the `MemorySource`.
 #[derive(Debug)]
-pub(crate) struct BucketedAllocator<'a, B: DerefMut<Target = Buckets> + 'a, S: MemorySource + 'a> {
-    buckets: B,
+pub(crate) struct BucketedAllocator<'a, L: RawLock + 'a, S: MemorySource + 'a> {
+    buckets: &'a Buckets<L>,
     source: &'a S,
 }
 
-/// The `Buckets` struct contains all the `SizedAllocator`s of different sizes
-#[derive(Debug, Default)]
-pub(crate) struct Buckets {
+/// The `Buckets` struct contains all the `SizedAllocator`s of different sizes, each behind its lock
+#[derive(Debug)]
+pub(crate) struct Buckets<L: RawLock> {
     /// 1 byte chunk size
-    very_small: Option<MetadataBox<SizedAllocator>>,
+    very_small: Locked<Chain, L>,
     /// 8 byte chunk size
-    small: Option<MetadataBox<SizedAllocator>>,
+    small: Locked<Chain, L>,
     /// 64 byte chunk size
-    medium: Option<MetadataBox<SizedAllocator>>,
-    /// Another 64-byte chunk size, for the metadata
-    metadata: Option<MetadataBox<SizedAllocator>>,
+    medium: Locked<Chain, L>,
     /// 512 byte chunk size
-    large: Option<MetadataBox<SizedAllocator>>,
+    large: Locked<Chain, L>,
+    /// Everything else
+    core: Locked<Core, L>,
+    /// Where to find the allocator that owns each page
+    page_map: PageMap,
+    /// Freed memory that's being held back, to catch use after free
+    #[cfg(feature = "quarantine")]
+    quarantine: Locked<Quarantine, L>,
+    /// Running totals for `Allocator::stats`
+    #[cfg(feature = "stats")]
+    counters: Counters,
+}
+
+/// The parts of the heap behind the core lock
+#[derive(Debug, Default)]
+struct Core {
     /// 4 KiB chunk size
-    very_large: Option<MetadataBox<SizedAllocator>>,
+    very_large: Chain,
+    /// Another 64-byte chunk size, for the metadata
+    metadata: Chain,
+    /// Allocations bigger than a block
+    huge: Option<MetadataBox<HugeAllocation>>,
+    /// Memory for new nodes in the page map
+    nodes: NodePool,
 }
 
-impl Buckets {
+impl<L: RawLock> Buckets<L> {
     pub(crate) const fn new() -> Self {
         Buckets {
-            very_small: None,
-            small: None,
-            medium: None,
-            metadata: None,
-            large: None,
-            very_large: None,
+            very_small: Locked::new(Chain::new()),
+            small: Locked::new(Chain::new()),
+            medium: Locked::new(Chain::new()),
+            large: Locked::new(Chain::new()),
+            core: Locked::new(Core {
+                very_large: Chain::new(),
+                metadata: Chain::new(),
+                huge: None,
+                nodes: NodePool::new(),
+            }),
+            page_map: PageMap::new(),
+            #[cfg(feature = "quarantine")]
+            quarantine: Locked::new(Quarantine::new()),
+            #[cfg(feature = "stats")]
+            counters: Counters::new(),
         }
     }
+
+    #[cfg(feature = "stats")]
+    pub(crate) fn counters(&self) -> &Counters {
+        &self.counters
+    }
+}
+
+/// Which stack the page is part of, if it's in a medium or large one
+fn stack_category(entry: &PageEntry) -> Option<SizeCategory> {
+    if entry.medium().is_some() {
+        Some(SizeCategory::Medium)
+    } else if entry.large().is_some() {
+        Some(SizeCategory::Large)
+    } else {
+        None
+    }
+}
+
+/// Changes the size of an allocation without moving it, if there's room.  Returns whether it
+/// worked.
+unsafe fn resize_in_chain(
+    chain: &mut Chain,
+    alloc: ptr::NonNull<SizedAllocator>,
+    ptr: ptr::NonNull<u8>,
+    layout: Layout,
+    new_size: usize,
+) -> bool {
+    if new_size <= layout.size() {
+        chain.shrink_in_place(alloc, ptr, layout, new_size);
+        true
+    } else {
+        chain.grow_in_place(alloc, ptr, layout, new_size).is_ok()
+    }
 }
 
-impl<'a, B: DerefMut<Target = Buckets>, S: MemorySource + 'a> BucketedAllocator<'a, B, S> {
+impl<'a, L: RawLock, S: MemorySource + 'a> BucketedAllocator<'a, L, S> {
     /// Creates a new `BucketedAllocator<T>`, without allocating any memory.
     ///
     /// The first allocation with get a block from the memory source an initialize the necessary
     /// allocators.
-    pub const fn new(buckets: B, source: &'a S) -> Self {
+    pub const fn new(buckets: &'a Buckets<L>, source: &'a S) -> Self {
         BucketedAllocator { buckets, source }
     }
 
-    fn very_small_mut(&mut self) -> Option<&mut SizedAllocator> {
-        self.buckets.very_small.as_mut().map(|x| &mut **x)
-    }
-    fn small_mut(&mut self) -> Option<&mut SizedAllocator> {
-        self.buckets.small.as_mut().map(|x| &mut **x)
-    }
-    fn medium_mut(&mut self) -> Option<&mut SizedAllocator> {
-        self.buckets.medium.as_mut().map(|x| &mut **x)
-    }
-    fn metadata_mut(&mut self) -> Option<&mut SizedAllocator> {
-        self.buckets.metadata.as_mut().map(|x| &mut **x)
-    }
-    fn large_mut(&mut self) -> Option<&mut SizedAllocator> {
-        self.buckets.large.as_mut().map(|x| &mut **x)
-    }
-    fn very_large_mut(&mut self) -> Option<&mut SizedAllocator> {
-        self.buckets.very_large.as_mut().map(|x| &mut **x)
-    }
-
-    unsafe fn get_very_large(&mut self) -> Result<&mut SizedAllocator, alloc::AllocErr> {
-        if self.buckets.very_large.is_none() {
-            self.extend_very_large()
-        } else {
-            self.very_large_mut().ok_or(alloc::AllocErr)
-        }
-    }
-    unsafe fn get_large(&mut self) -> Result<&mut SizedAllocator, alloc::AllocErr> {
-        if self.buckets.large.is_none() {
-            self.extend_large()
-        } else {
-            self.large_mut().ok_or(alloc::AllocErr)
-        }
-    }
-    unsafe fn get_medium(&mut self) -> Result<&mut SizedAllocator, alloc::AllocErr> {
-        if self.buckets.medium.is_none() {
-            self.extend_medium()
-        } else {
-            self.medium_mut().ok_or(alloc::AllocErr)
-        }
-    }
-    unsafe fn get_metadata(&mut self) -> Result<&mut SizedAllocator, alloc::AllocErr> {
-        if self.buckets.metadata.is_none() {
-            self.extend_metadata()
-        } else {
-            self.metadata_mut().ok_or(alloc::AllocErr)
-        }
-    }
-    unsafe fn get_small(&mut self) -> Result<&mut SizedAllocator, alloc::AllocErr> {
-        if self.buckets.small.is_none() {
-            self.extend_small()
-        } else {
-            self.small_mut().ok_or(alloc::AllocErr)
-        }
-    }
-    unsafe fn get_very_small(&mut self) -> Result<&mut SizedAllocator, alloc::AllocErr> {
-        if self.buckets.very_small.is_none() {
-            self.extend_very_small()
-        } else {
-            self.very_small_mut().ok_or(alloc::AllocErr)
+    /// Returns the size category's chain, if it has its own lock
+    fn chain(&self, category: SizeCategory) -> Option<&'a Locked<Chain, L>> {
+        match category {
+            SizeCategory::VerySmall => Some(&self.buckets.very_small),
+            SizeCategory::Small => Some(&self.buckets.small),
+            SizeCategory::Medium => Some(&self.buckets.medium),
+            SizeCategory::Large => Some(&self.buckets.large),
+            SizeCategory::VeryLarge | SizeCategory::Huge => None,
         }
     }
 
     /// Returns the owner of the given pointer, or `None` if no allocator claims to own it
-    fn owner_of(&mut self, _ptr: ptr::NonNull<u8>, layout: Layout) -> Option<&mut SizedAllocator> {
-        match SizeCategory::choose(layout.size()) {
-            Some(SizeCategory::VerySmall) => {
-                debug_log!("BucketedAllocator: very small owns pointer %#zx\n\0", _ptr);
-                debug_assert!(
-                    self.buckets
-                        .very_small
-                        .as_ref()
-                        .map_or(false, |vs| vs.owns(_ptr))
-                );
-                self.very_small_mut()
-            }
-            Some(SizeCategory::Small) => {
-                debug_log!("BucketedAllocator: small owns pointer %#zx\n\0", _ptr);
-                debug_assert!(self.buckets.small.as_ref().map_or(false, |s| s.owns(_ptr)));
-                self.small_mut()
-            }
-            Some(SizeCategory::Medium) => {
-                debug_log!("BucketedAllocator: medium owns pointer %#zx\n\0", _ptr);
-                debug_assert!(self.buckets.medium.as_ref().map_or(false, |m| m.owns(_ptr)));
-                self.medium_mut()
-            }
-            Some(SizeCategory::Large) => {
-                debug_log!("BucketedAllocator: large owns pointer %#zx\n\0", _ptr);
-                debug_assert!(self.buckets.large.as_ref().map_or(false, |l| l.owns(_ptr)));
-                self.large_mut()
-            }
-            Some(SizeCategory::VeryLarge) => {
-                debug_log!("BucketedAllocator: very large owns pointer %#zx\n\0", _ptr);
-                debug_assert!(
-                    self.buckets
-                        .very_large
-                        .as_ref()
-                        .map_or(false, |vl| vl.owns(_ptr))
-                );
-                self.very_large_mut()
+    ///
+    /// It's looked up in the page map, so it doesn't matter how far down its chain the owner is.
+    fn owner_of(
+        &self,
+        ptr: ptr::NonNull<u8>,
+        layout: Layout,
+    ) -> Option<ptr::NonNull<SizedAllocator>> {
+        let category = SizeCategory::of(layout);
+        let owner = category.and_then(|category| {
+            let stack_size = category.chunk_size() * STACK_SIZE;
+            let owner = category.page_owner()?;
+            self.buckets
+                .page_map
+                .find(ptr.as_ptr() as usize, owner, stack_size)
+        });
+        match owner {
+            Some(owner) => {
+                debug_log!("BucketedAllocator: found owner of pointer %#zx\n\0", ptr);
+                debug_assert!(unsafe { owner.as_ref() }.owns(ptr));
+                Some(owner)
             }
             None => {
-                debug_log!("BucketedAllocator: no one owns pointer %#zx!\n\0", _ptr);
+                debug_log!("BucketedAllocator: no one owns pointer %#zx!\n\0", ptr);
                 None
             }
         }
     }
 
-    // FIXME (unimportant) these discard the entire chain of allocators on some failures
-    /// Tries to add a new allocator to start of the `very_small` chain.  Returns that allocator on
-    /// success, `AllocErr` on failure.
-    unsafe fn extend_very_small(&mut self) -> Result<&mut SizedAllocator, alloc::AllocErr> {
+    /// Finds the live allocation that starts at `ptr`, returning its size category and usable
+    /// size, or `None` if there isn't one.
+    ///
+    /// The stacks of the smaller chains are allocations in the bigger chains, so the smallest
+    /// stack on the page that has the memory is the one that owns it.  Metadata isn't an
+    /// allocation anyone can free, so its pages are skipped.
+    ///
+    /// Which locks it needs depends on which stacks the page is in, which can change until they're
+    /// taken, so it checks again afterwards.
+    fn find_allocation(&self, ptr: ptr::NonNull<u8>) -> Option<(SizeCategory, usize)> {
+        let map = &self.buckets.page_map;
+        let addr = ptr.as_ptr() as usize;
+        let (category, chunks) = loop {
+            let guess = map.get(addr).and_then(stack_category);
+            if let Some(category) = guess {
+                let child_category = if category == SizeCategory::Medium {
+                    SizeCategory::VerySmall
+                } else {
+                    SizeCategory::Small
+                };
+                let _child_lock = self.chain(child_category).unwrap().lock();
+                let _lock = self.chain(category).unwrap().lock();
+                let entry = map.get(addr).unwrap();
+                if stack_category(entry) != guess {
+                    continue;
+                }
+                let stack = if category == SizeCategory::Medium {
+                    entry.medium()
+                } else {
+                    entry.large()
+                };
+                let child_stack_size = child_category.chunk_size() * STACK_SIZE;
+                let (category, owner) = match map.find(addr, Owner::Child, child_stack_size) {
+                    Some(child) => (child_category, child),
+                    None => (category, stack.unwrap()),
+                };
+                break (category, unsafe { owner.as_ref() }.allocation_chunks(ptr)?);
+            }
+
+            let core = self.buckets.core.lock();
+            match map.get(addr) {
+                // A stack was put there before the lock was taken
+                Some(entry) if stack_category(entry).is_some() => continue,
+                Some(entry) if entry.metadata().is_some() => return None,
+                Some(entry) if entry.block().is_some() => {
+                    let block = entry.block().unwrap();
+                    let chunks = unsafe { block.as_ref() }.allocation_chunks(ptr)?;
+                    break (SizeCategory::VeryLarge, chunks);
+                }
+                _ => {
+                    let record = huge::find(&core.huge, ptr)?;
+                    return Some((SizeCategory::Huge, record.layout().size()));
+                }
+            }
+        };
+        let size = cmp::min(chunks * category.chunk_size(), category.max_size());
+        Some((category, size))
+    }
+
+    /// Gets a new block from the memory source
+    unsafe fn get_block(&self) -> Result<ptr::NonNull<u8>, alloc::AllocError> {
+        let block = self.source.get_block().ok_or(alloc::AllocError)?;
+        count!(self.buckets.counters, blocks_obtained);
+        Ok(block)
+    }
+
+    /// Records a newly boxed allocator's stack in the page map.
+    ///
+    /// The page map must have had nodes reserved for it beforehand.
+    unsafe fn register(
+        &self,
+        core: &mut Core,
+        alloc: &mut MetadataBox<SizedAllocator>,
+        owner: Owner,
+    ) {
+        self.buckets.page_map.set(
+            &mut core.nodes,
+            ptr::NonNull::from(&mut **alloc),
+            owner,
+            false,
+        );
+    }
+
+    /// Stores a new allocator in the metadata chain and records it in the page map, taking the
+    /// core lock to do it.
+    unsafe fn store_allocator(
+        &self,
+        alloc: SizedAllocator,
+        owner: Owner,
+    ) -> Result<MetadataBox<SizedAllocator>, alloc::AllocError> {
+        let mut core = self.buckets.core.lock();
+        core.nodes.reserve(|| self.get_block())?;
+        let mut alloc_box = self.store_metadata(&mut core, alloc)?;
+        self.register(&mut core, &mut alloc_box, owner);
+        Ok(alloc_box)
+    }
+
+    /// Tries to add a new allocator to start of the `very_small` chain, which must be locked.
+    /// Returns the chain on success, `AllocError` on failure.
+    unsafe fn extend_very_small<'c>(
+        &self,
+        chain: &'c mut Chain,
+    ) -> Result<&'c mut Chain, alloc::AllocError> {
         let alloc_box = {
             let layout = Layout::from_size_align_unchecked(
                 VERY_SMALL_CHUNK_SIZE * STACK_SIZE,
                 VERY_SMALL_CHUNK_SIZE,
             );
-            let memory = self.alloc_medium(layout)?;
-            let old_very_small = self.buckets.very_small.take();
-            let new_alloc =
-                SizedAllocator::from_memory_chunk(VERY_SMALL_CHUNK_SIZE, memory, old_very_small);
-            self.store_metadata(new_alloc)?
+            let memory = self.alloc_size(layout, SizeCategory::Medium)?;
+            let new_alloc = SizedAllocator::from_memory_chunk(VERY_SMALL_CHUNK_SIZE, memory);
+            self.store_allocator(new_alloc, Owner::Child)?
         };
-        self.buckets.very_small = Some(alloc_box);
-        self.very_small_mut().ok_or(alloc::AllocErr)
+        chain.push(alloc_box);
+        Ok(chain)
     }
-    /// Tries to add a new allocator to start of the `small` chain.  Returns that allocator on
-    /// success, `AllocErr` on failure.
-    unsafe fn extend_small(&mut self) -> Result<&mut SizedAllocator, alloc::AllocErr> {
+    /// Tries to add a new allocator to start of the `small` chain, which must be locked.  Returns
+    /// the chain on success, `AllocError` on failure.
+    unsafe fn extend_small<'c>(
+        &self,
+        chain: &'c mut Chain,
+    ) -> Result<&'c mut Chain, alloc::AllocError> {
         let alloc_box = {
             let layout =
                 Layout::from_size_align_unchecked(SMALL_CHUNK_SIZE * STACK_SIZE, SMALL_CHUNK_SIZE);
-            let memory = self.alloc_large(layout)?;
-            let old_small = self.buckets.small.take();
-            let new_alloc = SizedAllocator::from_memory_chunk(SMALL_CHUNK_SIZE, memory, old_small);
-            self.store_metadata(new_alloc)?
+            let memory = self.alloc_size(layout, SizeCategory::Large)?;
+            let new_alloc = SizedAllocator::from_memory_chunk(SMALL_CHUNK_SIZE, memory);
+            self.store_allocator(new_alloc, Owner::Child)?
         };
-        self.buckets.small = Some(alloc_box);
-        self.small_mut().ok_or(alloc::AllocErr)
+        chain.push(alloc_box);
+        Ok(chain)
     }
-    /// Tries to add a new allocator to start of the `medium` chain.  Returns that allocator on
-    /// success, `AllocErr` on failure.
-    unsafe fn extend_medium(&mut self) -> Result<&mut SizedAllocator, alloc::AllocErr> {
+    /// Tries to add a new allocator to start of the `medium` chain, which must be locked.  Returns
+    /// the chain on success, `AllocError` on failure.
+    unsafe fn extend_medium<'c>(
+        &self,
+        chain: &'c mut Chain,
+    ) -> Result<&'c mut Chain, alloc::AllocError> {
         let alloc_box = {
             let layout = Layout::from_size_align_unchecked(
                 MEDIUM_CHUNK_SIZE * STACK_SIZE,
                 MEDIUM_CHUNK_SIZE,
             );
-            let memory = self.alloc_very_large(layout)?;
-            let old_medium = self.buckets.medium.take();
-            let new_alloc =
-                SizedAllocator::from_memory_chunk(MEDIUM_CHUNK_SIZE, memory, old_medium);
-            self.store_metadata(new_alloc)?
+            let memory = self.alloc_size(layout, SizeCategory::VeryLarge)?;
+            let new_alloc = SizedAllocator::from_memory_chunk(MEDIUM_CHUNK_SIZE, memory);
+            self.store_allocator(new_alloc, Owner::Medium)?
         };
-        self.buckets.medium = Some(alloc_box);
-        self.medium_mut().ok_or(alloc::AllocErr)
+        chain.push(alloc_box);
+        Ok(chain)
     }
-    /// Tries to add a new allocator to start of the `metadata` chain.  Returns that allocator on
-    /// success, `AllocErr` on failure.
-    unsafe fn extend_metadata(&mut self) -> Result<&mut SizedAllocator, alloc::AllocErr> {
+    /// Tries to add a new allocator to start of the `large` chain, which must be locked.  Returns
+    /// the chain on success, `AllocError` on failure.
+    unsafe fn extend_large<'c>(
+        &self,
+        chain: &'c mut Chain,
+    ) -> Result<&'c mut Chain, alloc::AllocError> {
         let alloc_box = {
-            let (mut metadata_alloc, more_metadata) = {
-                let layout = Layout::from_size_align_unchecked(
-                    METADATA_CHUNK_SIZE * STACK_SIZE,
-                    METADATA_CHUNK_SIZE,
-                );
-                let (memory, more_metadata) = self.alloc_very_large_no_metadata(layout)?;
-                let old_metadata = self.buckets.metadata.take();
-                (
-                    SizedAllocator::from_memory_chunk(METADATA_CHUNK_SIZE, memory, old_metadata),
-                    more_metadata,
-                )
-            };
+            let layout =
+                Layout::from_size_align_unchecked(LARGE_CHUNK_SIZE * STACK_SIZE, LARGE_CHUNK_SIZE);
+            let memory = self.alloc_size(layout, SizeCategory::VeryLarge)?;
+            let new_alloc = SizedAllocator::from_memory_chunk(LARGE_CHUNK_SIZE, memory);
+            self.store_allocator(new_alloc, Owner::Large)?
+        };
+        chain.push(alloc_box);
+        Ok(chain)
+    }
+    /// Tries to add a new allocator to start of the `metadata` chain.  Returns the chain on
+    /// success, `AllocError` on failure.
+    unsafe fn extend_metadata<'c>(
+        &self,
+        core: &'c mut Core,
+    ) -> Result<&'c mut Chain, alloc::AllocError> {
+        core.nodes.reserve(|| self.get_block())?;
+        let mut alloc_box = {
+            let layout = Layout::from_size_align_unchecked(
+                METADATA_CHUNK_SIZE * STACK_SIZE,
+                METADATA_CHUNK_SIZE,
+            );
+            let (memory, more_metadata) = self.alloc_very_large_no_metadata(core, layout)?;
+            let mut metadata_alloc = SizedAllocator::from_memory_chunk(METADATA_CHUNK_SIZE, memory);
             if let Some(more_metadata) = more_metadata {
                 let mem = metadata_alloc.alloc(Layout::new::<SizedAllocator>())?;
-                self.buckets.very_large = Some(MetadataBox::from_pointer_data(mem, more_metadata));
+                let mut very_large_box = MetadataBox::from_pointer_data(mem, more_metadata);
+                self.register(core, &mut very_large_box, Owner::Block);
+                core.very_large.push(very_large_box);
             }
             let mem = metadata_alloc.alloc(Layout::new::<SizedAllocator>())?;
             MetadataBox::from_pointer_data(mem, metadata_alloc)
         };
-        self.buckets.metadata = Some(alloc_box);
-        self.metadata_mut().ok_or(alloc::AllocErr)
-    }
-    /// Tries to add a new allocator to start of the `large` chain.  Returns that allocator on
-    /// success, `AllocErr` on failure.
-    unsafe fn extend_large(&mut self) -> Result<&mut SizedAllocator, alloc::AllocErr> {
-        let alloc_box = {
-            let layout =
-                Layout::from_size_align_unchecked(LARGE_CHUNK_SIZE * STACK_SIZE, LARGE_CHUNK_SIZE);
-            let memory = self.alloc_very_large(layout)?;
-            let old_large = self.buckets.large.take();
-            let new_alloc = SizedAllocator::from_memory_chunk(LARGE_CHUNK_SIZE, memory, old_large);
-            self.store_metadata(new_alloc)?
-        };
-        self.buckets.large = Some(alloc_box);
-        self.large_mut().ok_or(alloc::AllocErr)
+        self.register(core, &mut alloc_box, Owner::Metadata);
+        core.metadata.push(alloc_box);
+        Ok(&mut core.metadata)
     }
-    /// Tries to add a new allocator to start of the `large` chain.  Returns that allocator on
-    /// success, `AllocErr` on failure.
-    unsafe fn extend_very_large(&mut self) -> Result<&mut SizedAllocator, alloc::AllocErr> {
-        let alloc_box = {
-            let memory = self.source.get_block().ok_or(alloc::AllocErr)?;
-            let old_very_large = self.buckets.very_large.take();
-            let mut new_alloc =
-                SizedAllocator::from_memory_chunk(VERY_LARGE_CHUNK_SIZE, memory, old_very_large);
-            if let Some(new_alloc_place) = self
-                .buckets
-                .metadata
-                .as_mut()
-                .and_then(|ma| ma.alloc(Layout::new::<SizedAllocator>()).ok())
-            {
+    /// Tries to add a new allocator to start of the `very_large` chain.  Returns the chain on
+    /// success, `AllocError` on failure.
+    unsafe fn extend_very_large<'c>(
+        &self,
+        core: &'c mut Core,
+    ) -> Result<&'c mut Chain, alloc::AllocError> {
+        core.nodes.reserve(|| self.get_block())?;
+        let mut alloc_box = {
+            let memory = self.get_block()?;
+            let mut new_alloc = SizedAllocator::from_memory_chunk(VERY_LARGE_CHUNK_SIZE, memory);
+            if let Ok(new_alloc_place) = core.metadata.alloc(Layout::new::<SizedAllocator>()) {
                 MetadataBox::from_pointer_data(new_alloc_place, new_alloc)
             } else {
                 let mut metadata_alloc_box = {
@@ -311,11 +519,8 @@ impl<'a, B: DerefMut<Target = Buckets>, S: MemorySource + 'a> BucketedAllocator<
                         METADATA_CHUNK_SIZE * STACK_SIZE,
                         METADATA_CHUNK_SIZE,
                     ))?;
-                    let mut metadata_alloc = SizedAllocator::from_memory_chunk(
-                        METADATA_CHUNK_SIZE,
-                        metadata_memory,
-                        self.buckets.metadata.take(),
-                    );
+                    let mut metadata_alloc =
+                        SizedAllocator::from_memory_chunk(METADATA_CHUNK_SIZE, metadata_memory);
                     let metadata_alloc_place = metadata_alloc
                         .alloc(Layout::new::<SizedAllocator>())
                         .unwrap(); // unwrap bc it shouldn't fail
@@ -325,202 +530,444 @@ impl<'a, B: DerefMut<Target = Buckets>, S: MemorySource + 'a> BucketedAllocator<
                     .alloc(Layout::new::<SizedAllocator>())
                     .unwrap(); // unwrap bc it shouldn't fail
                 let res = MetadataBox::from_pointer_data(new_alloc_place, new_alloc);
-                self.buckets.metadata = Some(metadata_alloc_box);
+                self.register(core, &mut metadata_alloc_box, Owner::Metadata);
+                core.metadata.push(metadata_alloc_box);
                 res
             }
         };
-        self.buckets.very_large = Some(alloc_box);
-        self.very_large_mut().ok_or(alloc::AllocErr)
+        self.register(core, &mut alloc_box, Owner::Block);
+        core.very_large.push(alloc_box);
+        Ok(&mut core.very_large)
     }
 
-    /// Tries to allocate from the `very_small` chain, extending it if necessary.
-    unsafe fn alloc_very_small(
-        &mut self,
+    /// Tries to allocate from a chain with its own lock, which must be held, extending it if
+    /// necessary.
+    unsafe fn alloc_from(
+        &self,
+        chain: &mut Chain,
+        category: SizeCategory,
         layout: Layout,
-    ) -> Result<ptr::NonNull<u8>, alloc::AllocErr> {
-        debug_assert!(layout.size() <= VERY_SMALL_CHUNK_SIZE * STACK_SIZE);
-        match self.get_very_small()?.alloc(layout) {
-            Ok(mem) => Ok(mem),
-            Err(_) => self.extend_very_small()?.alloc(layout),
-        }
-    }
-    /// Tries to allocate from the `small` chain, extending it if necessary.
-    unsafe fn alloc_small(&mut self, layout: Layout) -> Result<ptr::NonNull<u8>, alloc::AllocErr> {
-        debug_assert!(layout.size() <= SMALL_CHUNK_SIZE * STACK_SIZE);
-        match self.get_small()?.alloc(layout) {
-            Ok(mem) => Ok(mem),
-            Err(_) => self.extend_small()?.alloc(layout),
+    ) -> Result<ptr::NonNull<u8>, alloc::AllocError> {
+        debug_assert!(layout.size() <= category.chunk_size() * STACK_SIZE);
+        if let Ok(mem) = chain.alloc(layout) {
+            return Ok(mem);
         }
-    }
-    /// Tries to allocate from the `medium` chain, extending it if necessary.
-    unsafe fn alloc_medium(&mut self, layout: Layout) -> Result<ptr::NonNull<u8>, alloc::AllocErr> {
-        debug_assert!(layout.size() <= MEDIUM_CHUNK_SIZE * STACK_SIZE);
-        match self.get_medium()?.alloc(layout) {
-            Ok(mem) => Ok(mem),
-            Err(_) => self.extend_medium()?.alloc(layout),
+        match category {
+            SizeCategory::VerySmall => self.extend_very_small(chain)?.alloc(layout),
+            SizeCategory::Small => self.extend_small(chain)?.alloc(layout),
+            SizeCategory::Medium => self.extend_medium(chain)?.alloc(layout),
+            SizeCategory::Large => self.extend_large(chain)?.alloc(layout),
+            SizeCategory::VeryLarge | SizeCategory::Huge => unreachable!(),
         }
     }
     /// Tries to allocate from the `metadata` chain, extending it if necessary.
     unsafe fn alloc_metadata(
-        &mut self,
+        &self,
+        core: &mut Core,
         layout: Layout,
-    ) -> Result<ptr::NonNull<u8>, alloc::AllocErr> {
+    ) -> Result<ptr::NonNull<u8>, alloc::AllocError> {
         debug_assert!(layout.size() <= METADATA_CHUNK_SIZE * STACK_SIZE);
-        match self.get_metadata()?.alloc(layout) {
-            Ok(mem) => Ok(mem),
-            Err(_) => self.extend_metadata()?.alloc(layout),
-        }
-    }
-    /// Tries to allocate from the `large` chain, extending it if necessary.
-    unsafe fn alloc_large(&mut self, layout: Layout) -> Result<ptr::NonNull<u8>, alloc::AllocErr> {
-        debug_assert!(layout.size() <= LARGE_CHUNK_SIZE * STACK_SIZE);
-        match self.get_large()?.alloc(layout) {
+        match core.metadata.alloc(layout) {
             Ok(mem) => Ok(mem),
-            Err(_) => self.extend_large()?.alloc(layout),
+            Err(_) => self.extend_metadata(core)?.alloc(layout),
         }
     }
     /// Tries to allocate from the `very_large` chain, extending it if necessary.
     unsafe fn alloc_very_large(
-        &mut self,
+        &self,
+        core: &mut Core,
         layout: Layout,
-    ) -> Result<ptr::NonNull<u8>, alloc::AllocErr> {
+    ) -> Result<ptr::NonNull<u8>, alloc::AllocError> {
         debug_assert!(layout.size() <= VERY_LARGE_CHUNK_SIZE * STACK_SIZE);
-        match self.get_very_large()?.alloc(layout) {
+        match core.very_large.alloc(layout) {
             Ok(mem) => Ok(mem),
-            Err(_) => self.extend_very_large()?.alloc(layout),
+            Err(_) => self.extend_very_large(core)?.alloc(layout),
         }
     }
 
     /// Tries to allocate from the chain that corresponds to the size category, extending it if
-    /// neccessary
+    /// neccessary.  Takes the lock that the chain is behind.
     unsafe fn alloc_size(
-        &mut self,
+        &self,
         layout: Layout,
         size_category: SizeCategory,
-    ) -> Result<ptr::NonNull<u8>, alloc::AllocErr> {
+    ) -> Result<ptr::NonNull<u8>, alloc::AllocError> {
+        if let Some(chain) = self.chain(size_category) {
+            return self.alloc_from(&mut chain.lock(), size_category, layout);
+        }
+        let mut core = self.buckets.core.lock();
         match size_category {
-            SizeCategory::VerySmall => self.alloc_very_small(layout),
-            SizeCategory::Small => self.alloc_small(layout),
-            SizeCategory::Medium => self.alloc_medium(layout),
-            SizeCategory::Large => self.alloc_large(layout),
-            SizeCategory::VeryLarge => self.alloc_very_large(layout),
+            SizeCategory::VeryLarge => self.alloc_very_large(&mut core, layout),
+            _ => self.alloc_huge(&mut core, layout),
         }
     }
 
-    /// Tries to allocate from the `large` chain, extending it if necessary, but doesn't store away
-    /// any extra metadata created
+    /// Gets memory for a huge allocation straight from the memory source, and keeps a record of it
+    unsafe fn alloc_huge(
+        &self,
+        core: &mut Core,
+        layout: Layout,
+    ) -> Result<ptr::NonNull<u8>, alloc::AllocError> {
+        let layout = Layout::from_size_align_unchecked(usable_size(layout), layout.align());
+        let memory = self.source.get_huge(layout).ok_or(alloc::AllocError)?;
+        let old_huge = core.huge.take();
+        match self.alloc_metadata(core, Layout::new::<HugeAllocation>()) {
+            Ok(place) => {
+                let record = HugeAllocation::new(memory, layout, old_huge);
+                core.huge = Some(MetadataBox::from_pointer_data(place, record));
+                Ok(memory)
+            }
+            Err(err) => {
+                core.huge = old_huge;
+                self.source.return_huge(memory, layout);
+                Err(err)
+            }
+        }
+    }
+
+    /// Gives the memory of a huge allocation back to the memory source, and forgets about it
+    unsafe fn dealloc_huge(&self, core: &mut Core, ptr: ptr::NonNull<u8>) {
+        let record = huge::remove(&mut core.huge, ptr)
+            .expect("No huge allocation owns the memory to deallocate");
+        self.source.return_huge(record.pointer(), record.layout());
+        self.dealloc_metadata(
+            core,
+            record.into_raw().cast(),
+            Layout::new::<HugeAllocation>(),
+        );
+    }
+
+    /// Gives back memory from the metadata chain.
+    ///
+    /// Metadata stacks are never freed, even when they're empty, since they can hold the records
+    /// of other metadata stacks.
+    unsafe fn dealloc_metadata(&self, core: &mut Core, ptr: ptr::NonNull<u8>, layout: Layout) {
+        let owner = self
+            .buckets
+            .page_map
+            .find(
+                ptr.as_ptr() as usize,
+                Owner::Metadata,
+                METADATA_CHUNK_SIZE * STACK_SIZE,
+            )
+            .expect("No allocator owns the metadata to deallocate");
+        core.metadata.dealloc(owner, ptr, layout);
+    }
+
+    /// Tries to allocate from the `very_large` chain, extending it if necessary, but doesn't store
+    /// away any extra metadata created
     unsafe fn alloc_very_large_no_metadata(
-        &mut self,
+        &self,
+        core: &mut Core,
         layout: Layout,
-    ) -> Result<(ptr::NonNull<u8>, Option<SizedAllocator>), alloc::AllocErr> {
+    ) -> Result<(ptr::NonNull<u8>, Option<SizedAllocator>), alloc::AllocError> {
         debug_assert!(layout.size() <= VERY_LARGE_CHUNK_SIZE * STACK_SIZE);
 
-        if let Some(ref mut very_large) = self.buckets.very_large {
-            if let Ok(mem) = very_large.alloc(layout) {
-                Ok((mem, None))
-            } else {
-                // Extend it without storing metadata...
-                let mut new_very_large = {
-                    let new_mem = self.source.get_block().ok_or(alloc::AllocErr)?;
-                    let old_very_large = self.buckets.very_large.take();
-                    SizedAllocator::from_memory_chunk(
-                        VERY_LARGE_CHUNK_SIZE,
-                        new_mem,
-                        old_very_large,
-                    )
-                };
-                if let Ok(mem) = new_very_large.alloc(layout) {
-                    Ok((mem, Some(new_very_large)))
-                } else {
-                    // FIXME (unimportant) discards entire `very_large` chain
-                    Err(alloc::AllocErr)
-                }
-            }
-        } else {
-            // Extend it without storing metadata...
-            let mut new_very_large = {
-                let new_mem = self.source.get_block().ok_or(alloc::AllocErr)?;
-                let old_very_large = self.buckets.very_large.take();
-                SizedAllocator::from_memory_chunk(VERY_LARGE_CHUNK_SIZE, new_mem, old_very_large)
-            };
-            if let Ok(mem) = new_very_large.alloc(layout) {
-                Ok((mem, Some(new_very_large)))
-            } else {
-                // FIXME (unimportant) discards entire `large` chain
-                Err(alloc::AllocErr)
-            }
+        if let Ok(mem) = core.very_large.alloc(layout) {
+            return Ok((mem, None));
         }
+        // Extend it without storing metadata...
+        let new_mem = self.get_block()?;
+        let mut new_very_large = SizedAllocator::from_memory_chunk(VERY_LARGE_CHUNK_SIZE, new_mem);
+        let mem = new_very_large.alloc(layout)?;
+        Ok((mem, Some(new_very_large)))
     }
 
     unsafe fn store_metadata(
-        &mut self,
+        &self,
+        core: &mut Core,
         alloc: SizedAllocator,
-    ) -> Result<MetadataBox<SizedAllocator>, alloc::AllocErr> {
+    ) -> Result<MetadataBox<SizedAllocator>, alloc::AllocError> {
         let layout: Layout = Layout::new::<SizedAllocator>();
-        self.alloc_metadata(layout)
+        self.alloc_metadata(core, layout)
             .map(|ptr| MetadataBox::from_pointer_data(ptr, alloc))
     }
 }
 
-unsafe impl<'a, B: DerefMut<Target = Buckets> + 'a, S: MemorySource + 'a> Alloc
-    for BucketedAllocator<'a, B, S>
-{
-    unsafe fn alloc(&mut self, layout: Layout) -> Result<ptr::NonNull<u8>, alloc::AllocErr> {
+impl<'a, L: RawLock, S: MemorySource + 'a> BucketedAllocator<'a, L, S> {
+    pub unsafe fn alloc(&self, layout: Layout) -> Result<ptr::NonNull<u8>, alloc::AllocError> {
         debug_log!(
             "BucketedAllocator: allocating size %zu align %zu\n\0",
             layout.size(),
             layout.align()
         );
-        if let Some(category) = SizeCategory::choose(layout.size()) {
-            self.alloc_size(layout, category)
-        } else {
-            Err(alloc::AllocErr)
+        #[cfg(feature = "red_zones")]
+        let (layout, wanted) = match red_zone::padded(layout) {
+            Some(padded) if layout.size() != 0 => (padded, layout),
+            _ => return Err(alloc::AllocError),
+        };
+        let category = SizeCategory::of(layout).ok_or(alloc::AllocError)?;
+        let ptr = self.alloc_size(layout, category)?;
+        #[cfg(feature = "red_zones")]
+        let ptr = red_zone::write(ptr, usable_size(layout), wanted);
+        Ok(ptr)
+    }
+
+    #[cfg(feature = "thread_cache")]
+    /// Fills `chunks` with allocations of the same layout, only taking the lock once.  Returns how
+    /// many it got, which is only less than all of them if it runs out of memory.
+    ///
+    /// The layout has to be small enough to have its own chain.
+    pub unsafe fn alloc_batch(&self, layout: Layout, chunks: &mut [*mut u8]) -> usize {
+        let category = SizeCategory::of(layout).unwrap();
+        let mut chain = self.chain(category).unwrap().lock();
+        for (i, chunk) in chunks.iter_mut().enumerate() {
+            match self.alloc_from(&mut chain, category, layout) {
+                Ok(ptr) => *chunk = ptr.as_ptr(),
+                Err(alloc::AllocError) => return i,
+            }
         }
+        chunks.len()
     }
 
-    unsafe fn dealloc(&mut self, ptr: ptr::NonNull<u8>, layout: Layout) {
-        if layout.size() == 0 {
+    pub unsafe fn dealloc(&self, ptr: ptr::NonNull<u8>, layout: Layout) {
+        #[cfg(feature = "red_zones")]
+        let (ptr, layout) = self.strip_red_zones(ptr, layout);
+        self.release(ptr, layout);
+    }
+
+    /// Frees memory that the program's done with.  With the quarantine, it's poisoned and held
+    /// back for a while first.
+    unsafe fn release(&self, ptr: ptr::NonNull<u8>, layout: Layout) {
+        #[cfg(feature = "quarantine")]
+        {
+            let layout = Layout::from_size_align_unchecked(usable_size(layout), layout.align());
+            quarantine::poison(ptr, layout.size());
+            let oldest = self.buckets.quarantine.lock().push(ptr, layout);
+            if let Some((oldest, layout)) = oldest {
+                self.unquarantine(oldest, layout);
+            }
+            self.drain_quarantine();
+        }
+        #[cfg(not(feature = "quarantine"))]
+        self.dealloc_raw(ptr, layout);
+    }
+
+    /// Changes how many bytes of freed memory the quarantine holds back, freeing the oldest if
+    /// there's too much now
+    #[cfg(feature = "quarantine")]
+    pub fn set_quarantine_size(&self, size: usize) {
+        self.buckets.quarantine.lock().set_limit(size);
+        unsafe { self.drain_quarantine() };
+    }
+
+    /// Really frees the oldest memory in the quarantine until it's under its limit.  The lock is
+    /// only held to take each one out, since freeing it takes the chains' locks.
+    #[cfg(feature = "quarantine")]
+    unsafe fn drain_quarantine(&self) {
+        loop {
+            let oldest = self.buckets.quarantine.lock().pop_excess();
+            match oldest {
+                Some((ptr, layout)) => self.unquarantine(ptr, layout),
+                None => return,
+            }
+        }
+    }
+
+    /// Frees memory that's come out of the quarantine, after checking nothing's written to it
+    #[cfg(feature = "quarantine")]
+    unsafe fn unquarantine(&self, ptr: ptr::NonNull<u8>, layout: Layout) {
+        quarantine::assert_poisoned(ptr, layout.size());
+        self.dealloc_raw(ptr, layout);
+    }
+
+    /// Returns whether `ptr` is memory that's been freed, but is being held in the quarantine.  It
+    /// should be the start of an allocation, or with red zones, what was handed out from one.
+    fn is_quarantined(&self, ptr: ptr::NonNull<u8>) -> bool {
+        #[cfg(feature = "quarantine")]
+        {
+            let quarantine = self.buckets.quarantine.lock();
+            let address = ptr.as_ptr() as usize;
+            // The quarantine has the padded allocation, which starts a front red zone earlier
+            #[cfg(feature = "red_zones")]
+            let inside = red_zone::fronts(address).any(|front| {
+                quarantine
+                    .find(address - front)
+                    .is_some_and(|size| front < size)
+            });
+            #[cfg(not(feature = "red_zones"))]
+            let inside = false;
+            inside || quarantine.find(address).is_some()
+        }
+        #[cfg(not(feature = "quarantine"))]
+        {
+            let _ = ptr;
+            false
+        }
+    }
+
+    /// Checks the red zones around the memory at `ptr`, aborting if they've been written over,
+    /// and returns the padded allocation it's in
+    #[cfg(feature = "red_zones")]
+    unsafe fn strip_red_zones(
+        &self,
+        ptr: ptr::NonNull<u8>,
+        layout: Layout,
+    ) -> (ptr::NonNull<u8>, Layout) {
+        let padded = match red_zone::padded(layout) {
+            Some(padded) if layout.size() != 0 => padded,
+            _ => return (ptr, layout),
+        };
+        let front = red_zone::front(layout.align());
+        let base = ptr::NonNull::new_unchecked(ptr.as_ptr().sub(front));
+        if red_zone::header(base).0 == front {
+            red_zone::assert_intact(base, usable_size(padded));
+            return (base, padded);
+        }
+        // C's `realloc` only knows `malloc`'s alignment, so memory allocated with more has a
+        // bigger front red zone than the layout says
+        let (base, usable) = self
+            .find_padded(ptr)
+            .expect("No allocator owns the memory to deallocate");
+        red_zone::assert_intact(base, usable);
+        (base, Layout::from_size_align_unchecked(usable, 1))
+    }
+
+    /// Finds the padded allocation that `ptr` was handed out from: where it starts, and its usable
+    /// size.
+    ///
+    /// The front red zone's size depends on the alignment, which isn't known, so each size it
+    /// could be is tried in turn.  Only a live allocation whose header says it's that big counts.
+    #[cfg(feature = "red_zones")]
+    fn find_padded(&self, ptr: ptr::NonNull<u8>) -> Option<(ptr::NonNull<u8>, usize)> {
+        let address = ptr.as_ptr() as usize;
+        red_zone::fronts(address).find_map(|front| {
+            let base = ptr::NonNull::new((address - front) as *mut u8)?;
+            let (_, usable) = self.find_allocation(base)?;
+            let (address, _) = unsafe { red_zone::handed_out(base, usable) }?;
+            (address == ptr.as_ptr() as usize).then_some((base, usable))
+        })
+    }
+
+    /// Deallocates memory that isn't padded with red zones, like the allocator's own stacks
+    unsafe fn dealloc_raw(&self, ptr: ptr::NonNull<u8>, layout: Layout) {
+        let category = match SizeCategory::of(layout) {
+            Some(category) => category,
+            None => return,
+        };
+        if let Some(chain) = self.chain(category) {
+            self.dealloc_from(&mut chain.lock(), category, ptr, layout);
             return;
         }
+        let mut core = self.buckets.core.lock();
+        match category {
+            SizeCategory::VeryLarge => self.dealloc_very_large(&mut core, ptr, layout),
+            _ => self.dealloc_huge(&mut core, ptr),
+        }
+    }
+
+    #[cfg(feature = "thread_cache")]
+    /// Deallocates several allocations with the same layout, only taking the lock once.
+    ///
+    /// The layout has to be small enough to have its own chain.
+    pub unsafe fn dealloc_batch(&self, chunks: &[ptr::NonNull<u8>], layout: Layout) {
+        let category = SizeCategory::of(layout).unwrap();
+        let mut chain = self.chain(category).unwrap().lock();
+        for &ptr in chunks {
+            self.dealloc_from(&mut chain, category, ptr, layout);
+        }
+    }
+
+    /// Deallocates into a chain with its own lock, which must be held
+    unsafe fn dealloc_from(
+        &self,
+        chain: &mut Chain,
+        category: SizeCategory,
+        ptr: ptr::NonNull<u8>,
+        layout: Layout,
+    ) {
         let owner = self
             .owner_of(ptr, layout)
             .expect("No allocator owns the memory to deallocate");
-        if let DeallocResponse::FreeAllocator(allocator) = owner.dealloc(ptr, layout) {
-            let stack_layout = {
-                let size = allocator.chunk_size() * STACK_SIZE;
-                let layout = allocator.chunk_size();
-                Layout::from_size_align_unchecked(size, layout)
-            };
-            let stack_ptr = allocator.stack_pointer();
-            self.dealloc(stack_ptr, stack_layout);
-            self.buckets
-                .metadata
-                .as_mut()
-                .unwrap()
-                .dealloc(allocator.into_raw().cast(), Layout::new::<SizedAllocator>());
+        if let DeallocResponse::Collapse = chain.dealloc(owner, ptr, layout) {
+            // The head of each chain stays around even when it's empty, so that allocating and
+            // freeing one thing over and over doesn't keep getting and freeing a stack
+            if !owner.as_ref().is_head() {
+                let allocator = chain.unlink(owner);
+                self.free_allocator(allocator, category);
+            }
         }
     }
 
-    unsafe fn realloc(
-        &mut self,
+    /// Deallocates into the `very_large` chain, giving empty blocks back to the memory source
+    unsafe fn dealloc_very_large(&self, core: &mut Core, ptr: ptr::NonNull<u8>, layout: Layout) {
+        let owner = self
+            .owner_of(ptr, layout)
+            .expect("No allocator owns the memory to deallocate");
+        if let DeallocResponse::Collapse = core.very_large.dealloc(owner, ptr, layout) {
+            if !owner.as_ref().is_head() {
+                let allocator = core.very_large.unlink(owner);
+                let block = allocator.stack_pointer();
+                self.forget_allocator(core, allocator, Owner::Block);
+                // Very large stacks are whole blocks, straight from the memory source
+                debug_log!(
+                    "BucketedAllocator: returning block %#zx to the memory source\n\0",
+                    block
+                );
+                self.source.return_block(block);
+                count!(self.buckets.counters, blocks_returned);
+            }
+        }
+    }
+
+    /// Takes an allocator that's been unlinked from its chain out of the page map, and frees its
+    /// metadata
+    unsafe fn forget_allocator(
+        &self,
+        core: &mut Core,
+        allocator: MetadataBox<SizedAllocator>,
+        owner: Owner,
+    ) {
+        self.buckets.page_map.set(
+            &mut core.nodes,
+            ptr::NonNull::from(&*allocator),
+            owner,
+            true,
+        );
+        self.dealloc_metadata(
+            core,
+            allocator.into_raw().cast(),
+            Layout::new::<SizedAllocator>(),
+        );
+    }
+
+    /// Frees both the stack and the metadata of an empty allocator that's been taken out of its
+    /// chain.  The chain's lock is still held, so this takes the core lock, and then the lock of
+    /// the chain the stack came from.
+    unsafe fn free_allocator(
+        &self,
+        allocator: MetadataBox<SizedAllocator>,
+        category: SizeCategory,
+    ) {
+        let stack_ptr = allocator.stack_pointer();
+        let stack_layout = {
+            let size = allocator.chunk_size() * STACK_SIZE;
+            let layout = allocator.chunk_size();
+            Layout::from_size_align_unchecked(size, layout)
+        };
+        self.forget_allocator(
+            &mut self.buckets.core.lock(),
+            allocator,
+            category.page_owner().unwrap(),
+        );
+        self.dealloc_raw(stack_ptr, stack_layout);
+    }
+
+    pub unsafe fn realloc(
+        &self,
         ptr: ptr::NonNull<u8>,
         layout: Layout,
         new_size: usize,
-    ) -> Result<ptr::NonNull<u8>, alloc::AllocErr> {
+    ) -> Result<ptr::NonNull<u8>, alloc::AllocError> {
         let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
 
-        // Try to expand it in place if the size category hasn't changed
-        if SizeCategory::choose(layout.size()) == SizeCategory::choose(new_size) {
-            let alloc = self
-                .owner_of(ptr, layout)
-                .expect("No allocator owns the memory to realloc");
-            if new_size <= layout.size() {
-                alloc.shrink_in_place(ptr, layout, new_size);
+        let size_category = SizeCategory::of(layout);
+        // With red zones, the canaries after it would have to move, so it's always moved
+        let in_place = |c| !cfg!(feature = "red_zones") && Some(c) == SizeCategory::of(new_layout);
+        if let Some(category) = size_category.filter(|&c| in_place(c)) {
+            // Try to change it in place if the size category hasn't changed
+            if self.resize_in_place(ptr, layout, new_size, category) {
                 return Ok(ptr);
-            } else {
-                if alloc.grow_in_place(ptr, layout, new_size).is_ok() {
-                    return Ok(ptr);
-                }
             }
         }
 
@@ -530,10 +977,481 @@ unsafe impl<'a, B: DerefMut<Target = Buckets> + 'a, S: MemorySource + 'a> Alloc
             ptr::copy_nonoverlapping(
                 ptr.as_ptr(),
                 new_memory.as_ptr(),
-                ::core::cmp::min(layout.size(), new_size),
+                cmp::min(layout.size(), new_size),
             );
             self.dealloc(ptr, layout);
         }
         new_memory
     }
+
+    /// Tries to change the size of an allocation without moving it, when the new size is in the
+    /// same category.  Returns whether it worked.
+    unsafe fn resize_in_place(
+        &self,
+        ptr: ptr::NonNull<u8>,
+        layout: Layout,
+        new_size: usize,
+        category: SizeCategory,
+    ) -> bool {
+        if let Some(chain) = self.chain(category) {
+            let mut chain = chain.lock();
+            let alloc = self
+                .owner_of(ptr, layout)
+                .expect("No allocator owns the memory to realloc");
+            return resize_in_chain(&mut chain, alloc, ptr, layout, new_size);
+        }
+        let mut core = self.buckets.core.lock();
+        if category == SizeCategory::Huge {
+            // Huge allocations can be resized in place as long as they still fit in the memory
+            // the source gave
+            let record =
+                huge::find(&core.huge, ptr).expect("No huge allocation owns the memory to realloc");
+            new_size <= record.layout().size()
+        } else {
+            let alloc = self
+                .owner_of(ptr, layout)
+                .expect("No allocator owns the memory to realloc");
+            resize_in_chain(&mut core.very_large, alloc, ptr, layout, new_size)
+        }
+    }
+
+    /// Returns the usable size of the live allocation that starts at `ptr`, or `None` if there
+    /// isn't one.
+    ///
+    /// The usable size is always in the allocation's size category, so it can be used in the
+    /// layout to deallocate or reallocate it.  With red zones, it's exactly the size that was
+    /// asked for.  Memory in the quarantine has been freed, so it doesn't count.
+    pub fn usable_size_of(&self, ptr: ptr::NonNull<u8>) -> Option<usize> {
+        if self.is_quarantined(ptr) {
+            return None;
+        }
+        #[cfg(feature = "red_zones")]
+        return self
+            .find_padded(ptr)
+            .and_then(|(base, usable)| unsafe { red_zone::handed_out(base, usable) })
+            .map(|(_, size)| size);
+        #[cfg(not(feature = "red_zones"))]
+        self.find_allocation(ptr).map(|(_, size)| size)
+    }
+
+    /// Deallocates the allocation that starts at `ptr`, without needing to know its layout.
+    pub unsafe fn dealloc_unsized(&self, ptr: ptr::NonNull<u8>) {
+        #[cfg(feature = "red_zones")]
+        let ptr = {
+            let (base, usable) = self
+                .find_padded(ptr)
+                .expect("No allocator owns the memory to deallocate");
+            red_zone::assert_intact(base, usable);
+            base
+        };
+        let (_, size) = self
+            .find_allocation(ptr)
+            .expect("No allocator owns the memory to deallocate");
+        self.release(ptr, Layout::from_size_align_unchecked(size, 1));
+    }
+
+    /// Checks that the page map points back to the stack on each of its pages, and that the stack
+    /// is exactly one allocation in the chain it came from.
+    ///
+    /// Since every stack is checked against its parent, and every page against the stack on it, no
+    /// two stacks can overlap without one of them failing.
+    fn check_stack(&self, bucket: Bucket, alloc: &SizedAllocator) -> Result<(), Problem> {
+        let map = &self.buckets.page_map;
+        let this = ptr::NonNull::from(alloc);
+        let bottom = alloc.stack_pointer();
+        let size = alloc.chunk_size() * STACK_SIZE;
+        let (owner, parent) = match bucket {
+            Bucket::VerySmall => (Owner::Child, Some(Owner::Medium)),
+            Bucket::Small => (Owner::Child, Some(Owner::Large)),
+            Bucket::Medium => (Owner::Medium, Some(Owner::Block)),
+            Bucket::Large => (Owner::Large, Some(Owner::Block)),
+            Bucket::VeryLarge => (Owner::Block, None),
+            Bucket::Metadata => (Owner::Metadata, Some(Owner::Block)),
+            Bucket::Huge => unreachable!(),
+        };
+
+        let pages = if owner == Owner::Child {
+            1
+        } else {
+            size / PAGE_SIZE
+        };
+        for page in 0..pages {
+            let addr = bottom.as_ptr() as usize + page * PAGE_SIZE;
+            if map.find(addr, owner, size) != Some(this) {
+                return Err(Problem::NotInPageMap);
+            }
+        }
+
+        if let Some(parent) = parent {
+            let parent = map
+                .find(bottom.as_ptr() as usize, parent, 0)
+                .ok_or(Problem::NotAllocatedFromParent)?;
+            let parent = unsafe { parent.as_ref() };
+            if parent.allocation_chunks(bottom) != Some(size / parent.chunk_size()) {
+                return Err(Problem::NotAllocatedFromParent);
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the heap's bookkeeping is all consistent, and returns the first problem found.
+    ///
+    /// Like `stats`, it holds all the locks at once.  The metadata chain is checked first, so that
+    /// the page map can be trusted to find the metadata stack that each record is in.
+    pub fn check(&self) -> Result<(), CheckError> {
+        let very_small = self.buckets.very_small.lock();
+        let small = self.buckets.small.lock();
+        let medium = self.buckets.medium.lock();
+        let large = self.buckets.large.lock();
+        let core = self.buckets.core.lock();
+        let chains = [
+            (Bucket::Metadata, &core.metadata),
+            (Bucket::VerySmall, &*very_small),
+            (Bucket::Small, &*small),
+            (Bucket::Medium, &*medium),
+            (Bucket::Large, &*large),
+            (Bucket::VeryLarge, &core.very_large),
+        ];
+        let error = |bucket, stack: ptr::NonNull<u8>, problem| CheckError {
+            bucket,
+            stack: stack.as_ptr() as usize,
+            problem,
+        };
+
+        for &(bucket, chain) in &chains {
+            chain
+                .check()
+                .map_err(|(alloc, problem)| error(bucket, alloc.stack_pointer(), problem))?;
+            for alloc in chain.iter() {
+                self.check_stack(bucket, alloc)
+                    .map_err(|problem| error(bucket, alloc.stack_pointer(), problem))?;
+            }
+        }
+
+        // Every record has to be an allocation of its own in the metadata chain
+        let in_metadata = |record: ptr::NonNull<u8>| {
+            self.buckets
+                .page_map
+                .find(record.as_ptr() as usize, Owner::Metadata, 0)
+                .is_some_and(|stack| {
+                    unsafe { stack.as_ref() }
+                        .allocation_chunks(record)
+                        .is_some()
+                })
+        };
+        for &(bucket, chain) in &chains {
+            for alloc in chain.iter() {
+                if !in_metadata(ptr::NonNull::from(alloc).cast()) {
+                    let problem = Problem::MetadataOutsideMetadataChain;
+                    return Err(error(bucket, alloc.stack_pointer(), problem));
+                }
+            }
+        }
+        for record in huge::iter(&core.huge) {
+            if !in_metadata(ptr::NonNull::from(record).cast()) {
+                let problem = Problem::MetadataOutsideMetadataChain;
+                return Err(error(Bucket::Huge, record.pointer(), problem));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns whether the allocation at `ptr`, of `chunks` chunks in a stack of the given
+    /// category, is really the stack of a smaller allocator
+    fn is_stack(&self, category: SizeCategory, ptr: ptr::NonNull<u8>, chunks: usize) -> bool {
+        let map = &self.buckets.page_map;
+        let addr = ptr.as_ptr() as usize;
+        let is_this = |stack: Option<ptr::NonNull<SizedAllocator>>| {
+            stack.is_some_and(|stack| {
+                let stack = unsafe { stack.as_ref() };
+                stack.stack_pointer() == ptr
+                    && stack.chunk_size() * STACK_SIZE == chunks * category.chunk_size()
+            })
+        };
+        match category {
+            SizeCategory::Medium => {
+                is_this(map.find(addr, Owner::Child, VERY_SMALL_CHUNK_SIZE * STACK_SIZE))
+            }
+            SizeCategory::Large => {
+                is_this(map.find(addr, Owner::Child, SMALL_CHUNK_SIZE * STACK_SIZE))
+            }
+            SizeCategory::VeryLarge => [Owner::Medium, Owner::Large, Owner::Metadata]
+                .iter()
+                .any(|&owner| is_this(map.find(addr, owner, 0))),
+            _ => false,
+        }
+    }
+
+    /// Calls `visitor` on every live allocation in the heap, without allocating anything.  With red
+    /// zones, it's given what was handed out, rather than the whole padded allocation.
+    ///
+    /// Like `stats`, it holds all the locks at once, so `visitor` can't use this heap.
+    pub fn visit<F: FnMut(&Allocation)>(&self, mut visitor: F) {
+        self.visit_padded(|allocation| {
+            #[cfg(feature = "red_zones")]
+            let allocation = &{
+                let base = unsafe { ptr::NonNull::new_unchecked(allocation.address as *mut u8) };
+                let handed_out = unsafe { red_zone::handed_out(base, allocation.size) };
+                handed_out.map_or(*allocation, |(address, size)| Allocation {
+                    address,
+                    size,
+                    ..*allocation
+                })
+            };
+            visitor(allocation)
+        })
+    }
+
+    /// Checks the red zones around every live allocation, and returns the first one that's been
+    /// written over.
+    ///
+    /// Like `stats`, it holds all the locks at once.
+    #[cfg(feature = "red_zones")]
+    pub fn check_red_zones(&self) -> Result<(), RedZoneError> {
+        let mut result = Ok(());
+        self.visit_padded(|allocation| {
+            if result.is_ok() {
+                let base = unsafe { ptr::NonNull::new_unchecked(allocation.address as *mut u8) };
+                result = unsafe { red_zone::check(base, allocation.size) };
+            }
+        });
+        result
+    }
+
+    /// Calls `visitor` on every live allocation in the heap, red zones and all
+    fn visit_padded<F: FnMut(&Allocation)>(&self, mut visitor: F) {
+        let very_small = self.buckets.very_small.lock();
+        let small = self.buckets.small.lock();
+        let medium = self.buckets.medium.lock();
+        let large = self.buckets.large.lock();
+        let core = self.buckets.core.lock();
+        let chains = [
+            (SizeCategory::VerySmall, Bucket::VerySmall, &*very_small),
+            (SizeCategory::Small, Bucket::Small, &*small),
+            (SizeCategory::Medium, Bucket::Medium, &*medium),
+            (SizeCategory::Large, Bucket::Large, &*large),
+            (SizeCategory::VeryLarge, Bucket::VeryLarge, &core.very_large),
+        ];
+
+        for &(category, bucket, chain) in &chains {
+            for alloc in chain.iter() {
+                for (start, chunks) in alloc.allocations() {
+                    // Memory in the quarantine is still allocated, but it's been freed
+                    if self.is_stack(category, start, chunks) || self.is_quarantined(start) {
+                        continue;
+                    }
+                    visitor(&Allocation {
+                        address: start.as_ptr() as usize,
+                        size: cmp::min(chunks * category.chunk_size(), category.max_size()),
+                        bucket,
+                        stack: alloc.stack_pointer().as_ptr() as usize,
+                    });
+                }
+            }
+        }
+        for record in huge::iter(&core.huge) {
+            if self.is_quarantined(record.pointer()) {
+                continue;
+            }
+            let address = record.pointer().as_ptr() as usize;
+            visitor(&Allocation {
+                address,
+                size: record.layout().size(),
+                bucket: Bucket::Huge,
+                stack: address,
+            });
+        }
+    }
+
+    /// Writes a dump of the heap's layout to `out`, a few bytes at a time, stopping at the first
+    /// error.
+    ///
+    /// Like `stats`, it holds all the locks at once, so `out` can't use this heap.
+    pub fn dump<E, W: FnMut(&[u8]) -> Result<(), E>>(&self, mut out: W) -> Result<(), E> {
+        let very_small = self.buckets.very_small.lock();
+        let small = self.buckets.small.lock();
+        let medium = self.buckets.medium.lock();
+        let large = self.buckets.large.lock();
+        let core = self.buckets.core.lock();
+        let chains = [
+            (Bucket::VerySmall, &*very_small),
+            (Bucket::Small, &*small),
+            (Bucket::Medium, &*medium),
+            (Bucket::Large, &*large),
+            (Bucket::VeryLarge, &core.very_large),
+            (Bucket::Metadata, &core.metadata),
+        ];
+
+        dump::write_header(&mut out)?;
+        for &(bucket, chain) in &chains {
+            for alloc in chain.iter() {
+                let stack = alloc.stack();
+                let record = Record::Stack(StackRecord {
+                    bucket,
+                    record: ptr::from_ref(alloc) as usize,
+                    bottom: stack.pointer().as_ptr() as usize,
+                    chunk_size: stack.chunk_size(),
+                    height: stack.height(),
+                    bitmap: stack.bitmap(),
+                    starts: stack.starts(),
+                });
+                dump::write_record(&mut out, Some(record))?;
+            }
+        }
+        for record in huge::iter(&core.huge) {
+            let huge = Record::Huge {
+                record: ptr::from_ref(record) as usize,
+                address: record.pointer().as_ptr() as usize,
+                size: record.layout().size(),
+            };
+            dump::write_record(&mut out, Some(huge))?;
+        }
+        dump::write_record(&mut out, None)
+    }
+
+    /// Checks that freeing `ptr` with the given layout is safe: that it's the start of a live
+    /// allocation, and that the size matches.  Without a layout, only the start is checked.
+    #[cfg(feature = "checked")]
+    pub fn check_free(
+        &self,
+        ptr: ptr::NonNull<u8>,
+        layout: Option<Layout>,
+    ) -> Result<(), FreeError> {
+        let address = ptr.as_ptr() as usize;
+        // It's still allocated as far as the stacks know, but it's been freed
+        if self.is_quarantined(ptr) {
+            return Err(FreeError::DoubleFree { address });
+        }
+        let usable = match self.usable_size_of(ptr) {
+            Some(usable) => usable,
+            None => return Err(self.diagnose_free(ptr)),
+        };
+        // A huge allocation that was shrunk in place still has all its memory, so it can be freed
+        // with any size that's still huge
+        let shrunk_huge = |layout| {
+            SizeCategory::of(layout) == Some(SizeCategory::Huge) && visible_size(layout) <= usable
+        };
+        match layout {
+            // The usable size of each category is different, so this checks the category too
+            Some(layout) if visible_size(layout) != usable && !shrunk_huge(layout) => {
+                Err(FreeError::WrongSize {
+                    address,
+                    size: layout.size(),
+                    usable_size: usable,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Works out what's wrong with freeing `ptr`, which isn't the start of a live allocation.
+    ///
+    /// If the smallest stack it's in has it allocated, it's in the middle of an allocation, and
+    /// otherwise it's already been freed.  It holds all the locks, like `check`, so that nothing
+    /// changes while it looks.
+    #[cfg(feature = "checked")]
+    fn diagnose_free(&self, ptr: ptr::NonNull<u8>) -> FreeError {
+        let _very_small = self.buckets.very_small.lock();
+        let _small = self.buckets.small.lock();
+        let _medium = self.buckets.medium.lock();
+        let _large = self.buckets.large.lock();
+        let core = self.buckets.core.lock();
+        let map = &self.buckets.page_map;
+        let address = ptr.as_ptr() as usize;
+
+        let entry = map.get(address);
+        let child_size = match entry.and_then(stack_category) {
+            Some(SizeCategory::Medium) => VERY_SMALL_CHUNK_SIZE * STACK_SIZE,
+            _ => SMALL_CHUNK_SIZE * STACK_SIZE,
+        };
+        let child = map
+            .find(address, Owner::Child, child_size)
+            .filter(|child| unsafe { child.as_ref() }.owns(ptr));
+        let stack = child
+            .or_else(|| entry.and_then(PageEntry::medium))
+            .or_else(|| entry.and_then(PageEntry::large))
+            .or_else(|| entry.and_then(PageEntry::block));
+        if entry.and_then(PageEntry::metadata).is_some() {
+            return FreeError::NotOwned { address };
+        }
+        match stack {
+            Some(stack) if unsafe { stack.as_ref() }.stack().is_allocated(ptr.as_ptr()) => {
+                FreeError::InteriorPointer { address }
+            }
+            Some(_) => FreeError::DoubleFree { address },
+            None => {
+                let in_huge = huge::iter(&core.huge).any(|record| {
+                    let start = record.pointer().as_ptr() as usize;
+                    start <= address && address < start + record.layout().size()
+                });
+                if in_huge {
+                    FreeError::InteriorPointer { address }
+                } else {
+                    FreeError::NotOwned { address }
+                }
+            }
+        }
+    }
+
+    /// Takes a snapshot of the heap.
+    ///
+    /// All the locks are held at once, in order, so that the stacks in every bucket are counted at
+    /// the same moment.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> Stats {
+        fn bucket(chain: &Chain) -> BucketStats {
+            let mut stats = BucketStats {
+                missed_probes: chain.missed_probes(),
+                ..BucketStats::default()
+            };
+            for alloc in chain.iter() {
+                stats.stacks += 1;
+                stats.chunks_in_use += alloc.used_chunks();
+            }
+            stats
+        }
+
+        let very_small = self.buckets.very_small.lock();
+        let small = self.buckets.small.lock();
+        let medium = self.buckets.medium.lock();
+        let large = self.buckets.large.lock();
+        let core = self.buckets.core.lock();
+        let mut stats = Stats {
+            very_small: bucket(&very_small),
+            small: bucket(&small),
+            medium: bucket(&medium),
+            large: bucket(&large),
+            very_large: bucket(&core.very_large),
+            metadata: bucket(&core.metadata),
+            huge_allocations: huge::count(&core.huge),
+            ..Stats::default()
+        };
+        self.buckets.counters.read(&mut stats);
+        stats
+    }
+
+    /// Gives all of the heap's memory back to the memory source: the huge allocations, the very
+    /// large stacks' blocks, which everything else is in, and then the page map's nodes.
+    ///
+    /// Nothing that was allocated can be used afterwards, and neither can the heap, until the
+    /// `Buckets` are replaced with new ones.
+    pub unsafe fn release_all(&self) {
+        let mut core = self.buckets.core.lock();
+        for record in huge::iter(&core.huge) {
+            self.source.return_huge(record.pointer(), record.layout());
+        }
+        // The page map is in blocks of its own, so it's still there while the others go
+        self.buckets.page_map.each_block(|block| {
+            debug_log!(
+                "BucketedAllocator: returning block %#zx to the memory source\n\0",
+                block
+            );
+            self.source.return_block(block);
+            count!(self.buckets.counters, blocks_returned);
+        });
+        core.nodes.release(|block| {
+            self.source.return_block(block);
+            count!(self.buckets.counters, blocks_returned);
+        });
+    }
 }
@@ -0,0 +1,88 @@
+//! Checking the heap's bookkeeping, for `Allocator::check`.
+//!
+//! The allocator mostly just assumes that its stacks, chains and page map agree with each other.
+//! Checking them all takes a while, since it goes through every stack in the heap, but it finds
+//! the corruption where it is instead of in some later `dealloc`.
+
+use core::fmt;
+
+/// Which part of the heap a problem was found in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bucket {
+    /// The chain of stacks with 1-byte chunks
+    VerySmall,
+    /// The chain of stacks with 8-byte chunks
+    Small,
+    /// The chain of stacks with 64-byte chunks
+    Medium,
+    /// The chain of stacks with 512-byte chunks
+    Large,
+    /// The chain of stacks with 4 KiB chunks, which are whole blocks
+    VeryLarge,
+    /// The chain of stacks that the allocator keeps its own bookkeeping in
+    Metadata,
+    /// The records of allocations too big for a block
+    Huge,
+}
+
+/// Something that's wrong with a stack
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// The top of the stack isn't just above its highest allocated chunk
+    WrongHeight {
+        /// Where the stack thinks its top is, in chunks
+        height: usize,
+        /// Where it should be
+        expected: usize,
+    },
+    /// An allocation is marked as starting on a chunk that isn't allocated
+    StartOutsideBitmap,
+    /// Some chunks are allocated, but no allocation starts at the first of them
+    UnstartedChunks,
+    /// The stack's chunk size is different from the rest of its chain's
+    WrongChunkSize {
+        /// The stack's chunk size
+        chunk_size: usize,
+        /// The chunk size of the rest of the chain
+        expected: usize,
+    },
+    /// The stack is in the wrong free space bin for its longest run of free chunks
+    WrongBin {
+        /// The bin it's in
+        bin: Option<usize>,
+        /// The bin it should be in
+        expected: Option<usize>,
+    },
+    /// The lists of stacks in each free space bin don't match the stacks in the chain
+    BrokenBin,
+    /// The links between the stacks in the chain don't match up
+    BrokenChain,
+    /// The stack's record isn't an allocation in the metadata chain
+    MetadataOutsideMetadataChain,
+    /// The page map doesn't point back to the stack
+    NotInPageMap,
+    /// The stack isn't exactly one allocation in the chain it should have come from, so it might
+    /// overlap with other memory
+    NotAllocatedFromParent,
+}
+
+/// What `Allocator::check` found wrong with the heap
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CheckError {
+    /// The part of the heap with the problem
+    pub bucket: Bucket,
+    /// The address of the bottom of the broken stack, or of the huge allocation
+    pub stack: usize,
+    /// What's wrong with it
+    pub problem: Problem,
+}
+
+impl fmt::Display for CheckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} stack at {:#x} is broken: {:?}",
+            self.bucket, self.stack, self.problem
+        )
+    }
+}
@@ -0,0 +1,76 @@
+//! Checking frees before they happen, with the `checked` feature.
+//!
+//! Normally, freeing memory just clears its bits in its stack's bitmap, so a bad free quietly
+//! corrupts the heap.  With the feature on, every free is checked against where the allocation
+//! really starts and how big it is first.  If it's wrong, the `Allocator`'s error handler is
+//! called, and the free is skipped, so the heap stays as it was.
+//!
+//! Frees don't go through the thread caches in this mode, since a chunk sitting in a cache still
+//! looks allocated to the heap.
+
+use core::fmt;
+
+/// Something wrong with a free, found before it could do any damage
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FreeError {
+    /// The memory is in the heap, but isn't allocated, so it's probably been freed already
+    DoubleFree {
+        /// The address that was freed
+        address: usize,
+    },
+    /// The memory doesn't belong to the heap at all
+    NotOwned {
+        /// The address that was freed
+        address: usize,
+    },
+    /// The memory is in the middle of an allocation, rather than at its start
+    InteriorPointer {
+        /// The address that was freed
+        address: usize,
+    },
+    /// The size it was freed with doesn't match the size it was allocated with
+    WrongSize {
+        /// The address that was freed
+        address: usize,
+        /// The size it was freed with
+        size: usize,
+        /// The usable size of the allocation there
+        usable_size: usize,
+    },
+}
+
+impl fmt::Display for FreeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            FreeError::DoubleFree { address } => {
+                write!(f, "{:#x} was freed, but isn't allocated", address)
+            }
+            FreeError::NotOwned { address } => {
+                write!(f, "{:#x} was freed, but isn't from this heap", address)
+            }
+            FreeError::InteriorPointer { address } => write!(
+                f,
+                "{:#x} was freed, but is in the middle of an allocation",
+                address
+            ),
+            FreeError::WrongSize {
+                address,
+                size,
+                usable_size,
+            } => write!(
+                f,
+                "{:#x} was freed with size {}, but its usable size is {}",
+                address, size, usable_size
+            ),
+        }
+    }
+}
+
+/// Called with each bad free.  If it returns, the free is skipped.  It's called from inside
+/// `dealloc`, so it mustn't unwind.
+pub type ErrorHandler = fn(&FreeError);
+
+/// The error handler to use until another one's set: it prints the error and aborts
+pub fn abort_on_error(error: &FreeError) {
+    fatal!("Bad free: {}", error);
+}
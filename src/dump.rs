@@ -0,0 +1,281 @@
+//! A binary dump of the heap's layout, from `Allocator::dump`, and a reader for it.
+//!
+//! The dump is written straight out while the heap is locked, so it can't allocate anything.  It's
+//! a 16-byte header, then one 64-byte record for each stack and each huge allocation, then an end
+//! record.  Everything is a little-endian `u64`:
+//!
+//! ```text
+//! header:  "STACKDMP"  version
+//! stack:   1  bucket  record  bottom   chunk_size  height  bitmap  starts
+//! huge:    2  bucket  record  address  size        0       0       0
+//! end:     0  0       0       0        0           0       0       0
+//! ```
+//!
+//! `record` is where the allocator keeps its own record of the stack or huge allocation, which
+//! should always be in the metadata chain.  `bucket` is the index of the `Bucket`.
+
+use core::fmt;
+
+use bitmapped_stack::{self, STACK_SIZE};
+use check::Bucket;
+
+/// The first 8 bytes of every dump
+pub const MAGIC: [u8; 8] = *b"STACKDMP";
+
+/// The version of the format written by this version of the crate
+pub const VERSION: u64 = 1;
+
+/// The number of words in each record
+const RECORD_WORDS: usize = 8;
+
+const END: u64 = 0;
+const STACK: u64 = 1;
+const HUGE: u64 = 2;
+
+/// The buckets, in the order of their indices in the dump
+const BUCKETS: [Bucket; 7] = [
+    Bucket::VerySmall,
+    Bucket::Small,
+    Bucket::Medium,
+    Bucket::Large,
+    Bucket::VeryLarge,
+    Bucket::Metadata,
+    Bucket::Huge,
+];
+
+/// One stack, as it was when the dump was taken
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StackRecord {
+    /// The chain the stack is in
+    pub bucket: Bucket,
+    /// The address of the allocator's record of the stack
+    pub record: usize,
+    /// The address of the bottom of the stack
+    pub bottom: usize,
+    /// The size of each of its chunks, in bytes
+    pub chunk_size: usize,
+    /// The height of the stack, in chunks
+    pub height: usize,
+    /// Which chunks are allocated
+    pub bitmap: u64,
+    /// Which chunks an allocation starts at
+    pub starts: u64,
+}
+
+impl StackRecord {
+    /// Returns the number of chunks that are allocated
+    pub fn used_chunks(&self) -> usize {
+        self.bitmap.count_ones() as usize
+    }
+
+    /// Returns the number of free chunks below the top of the stack, which only allocations that
+    /// fit in the holes can use.  A broken dump can have more allocated than its height, which
+    /// counts as no holes.
+    pub fn hole_chunks(&self) -> usize {
+        self.height.saturating_sub(self.used_chunks())
+    }
+
+    /// Returns the length of the longest run of free chunks
+    pub fn largest_free_run(&self) -> usize {
+        bitmapped_stack::largest_free_run(self.bitmap)
+    }
+
+    /// Returns whether the memory at `addr` is in the stack
+    pub fn contains(&self, addr: usize) -> bool {
+        self.bottom <= addr && addr < self.bottom + self.chunk_size * STACK_SIZE
+    }
+
+    /// Draws the stack with a character for each chunk: `#` where an allocation starts, `=` for
+    /// the rest of it, `.` for a hole below the top, and a space above it
+    pub fn map(&self) -> [u8; STACK_SIZE] {
+        let mut map = [b' '; STACK_SIZE];
+        for (chunk, c) in map.iter_mut().enumerate() {
+            *c = if self.starts & (1 << chunk) != 0 {
+                b'#'
+            } else if self.bitmap & (1 << chunk) != 0 {
+                b'='
+            } else if chunk < self.height {
+                b'.'
+            } else {
+                b' '
+            };
+        }
+        map
+    }
+}
+
+/// One thing in the heap, as it was when the dump was taken
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Record {
+    /// A stack in one of the chains
+    Stack(StackRecord),
+    /// An allocation too big for a block
+    Huge {
+        /// The address of the allocator's record of it
+        record: usize,
+        /// The address of the allocation
+        address: usize,
+        /// Its size, in bytes
+        size: usize,
+    },
+}
+
+impl Record {
+    fn to_words(self) -> [u64; RECORD_WORDS] {
+        let index = |bucket| BUCKETS.iter().position(|&b| b == bucket).unwrap() as u64;
+        match self {
+            Record::Stack(stack) => [
+                STACK,
+                index(stack.bucket),
+                stack.record as u64,
+                stack.bottom as u64,
+                stack.chunk_size as u64,
+                stack.height as u64,
+                stack.bitmap,
+                stack.starts,
+            ],
+            Record::Huge {
+                record,
+                address,
+                size,
+            } => [
+                HUGE,
+                index(Bucket::Huge),
+                record as u64,
+                address as u64,
+                size as u64,
+                0,
+                0,
+                0,
+            ],
+        }
+    }
+
+    fn from_words(words: [u64; RECORD_WORDS]) -> Result<Option<Self>, DumpError> {
+        let bucket = *BUCKETS.get(words[1] as usize).ok_or(DumpError::BadRecord)?;
+        match words[0] {
+            END => Ok(None),
+            STACK if bucket != Bucket::Huge && words[5] <= STACK_SIZE as u64 => {
+                Ok(Some(Record::Stack(StackRecord {
+                    bucket,
+                    record: words[2] as usize,
+                    bottom: words[3] as usize,
+                    chunk_size: words[4] as usize,
+                    height: words[5] as usize,
+                    bitmap: words[6],
+                    starts: words[7],
+                })))
+            }
+            HUGE if bucket == Bucket::Huge => Ok(Some(Record::Huge {
+                record: words[2] as usize,
+                address: words[3] as usize,
+                size: words[4] as usize,
+            })),
+            _ => Err(DumpError::BadRecord),
+        }
+    }
+}
+
+/// Writes the header of a dump
+pub(crate) fn write_header<E, W>(out: &mut W) -> Result<(), E>
+where
+    W: FnMut(&[u8]) -> Result<(), E>,
+{
+    out(&MAGIC)?;
+    out(&VERSION.to_le_bytes())
+}
+
+/// Writes one record of a dump, or the end if it's `None`
+pub(crate) fn write_record<E, W>(out: &mut W, record: Option<Record>) -> Result<(), E>
+where
+    W: FnMut(&[u8]) -> Result<(), E>,
+{
+    let words = record.map_or([END; RECORD_WORDS], Record::to_words);
+    let mut bytes = [0; RECORD_WORDS * 8];
+    for (chunk, word) in bytes.chunks_exact_mut(8).zip(words.iter()) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    out(&bytes)
+}
+
+/// Reads a word out of 8 bytes
+fn read_word(bytes: &[u8]) -> u64 {
+    let mut word = [0; 8];
+    word.copy_from_slice(bytes);
+    u64::from_le_bytes(word)
+}
+
+/// What's wrong with a dump that can't be read
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DumpError {
+    /// It doesn't start with `MAGIC`, so it isn't a dump
+    BadMagic,
+    /// It's from a different version of the format
+    UnsupportedVersion(u64),
+    /// It ends before the end record
+    Truncated,
+    /// A record doesn't make sense
+    BadRecord,
+}
+
+impl fmt::Display for DumpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DumpError::BadMagic => write!(f, "not a heap dump"),
+            DumpError::UnsupportedVersion(version) => {
+                write!(f, "unsupported heap dump version {}", version)
+            }
+            DumpError::Truncated => write!(f, "the heap dump is cut off"),
+            DumpError::BadRecord => write!(f, "the heap dump has a broken record"),
+        }
+    }
+}
+
+/// Reads the records out of a dump, without copying it
+#[derive(Clone, Debug)]
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    done: bool,
+}
+
+impl<'a> Reader<'a> {
+    /// Starts reading a dump, checking its header
+    pub fn new(bytes: &'a [u8]) -> Result<Self, DumpError> {
+        if bytes.len() < 16 || bytes[..8] != MAGIC {
+            return Err(DumpError::BadMagic);
+        }
+        let version = read_word(&bytes[8..16]);
+        if version != VERSION {
+            return Err(DumpError::UnsupportedVersion(version));
+        }
+        Ok(Reader {
+            bytes: &bytes[16..],
+            done: false,
+        })
+    }
+}
+
+impl<'a> Iterator for Reader<'a> {
+    type Item = Result<Record, DumpError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        if self.bytes.len() < RECORD_WORDS * 8 {
+            self.done = true;
+            return Some(Err(DumpError::Truncated));
+        }
+        let (record, rest) = self.bytes.split_at(RECORD_WORDS * 8);
+        self.bytes = rest;
+        let mut words = [0; RECORD_WORDS];
+        for (word, chunk) in words.iter_mut().zip(record.chunks_exact(8)) {
+            *word = read_word(chunk);
+        }
+        let record = Record::from_words(words);
+        if !matches!(record, Ok(Some(_))) {
+            self.done = true;
+        }
+        record.transpose()
+    }
+}
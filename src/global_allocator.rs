@@ -1,81 +1,422 @@
 //! The `Allocator` type
 
-use core::alloc::{Alloc, GlobalAlloc, Layout};
-use core::cell;
-use core::ops;
+use core::alloc::{self, AllocError, GlobalAlloc, Layout};
+use core::cmp;
 use core::ptr;
-use core::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "checked")]
+use core::sync::atomic::AtomicPtr;
+#[cfg(feature = "periodic_check")]
+use core::sync::atomic::AtomicUsize;
+#[cfg(any(feature = "periodic_check", feature = "checked"))]
+use core::sync::atomic::Ordering;
 
-use bucketed::{BucketedAllocator, Buckets};
+use bucketed::{self, BucketedAllocator, Buckets};
+use check::CheckError;
+#[cfg(feature = "checked")]
+use checked::{self, ErrorHandler};
+#[cfg(feature = "checked")]
+use lock::{Guard, Locked};
+use lock::{RawLock, SpinLock};
 use memory_source::MemorySource;
+#[cfg(feature = "red_zones")]
+use red_zone::RedZoneError;
+#[cfg(feature = "stats")]
+use stats::Stats;
+#[cfg(feature = "thread_cache")]
+use thread_cache;
+use trace::Event;
+#[cfg(all(feature = "trace", target_os = "linux"))]
+use trace::Trace;
+use visit::Allocation;
+
+/// How many allocations and frees there are between checks, with the `periodic_check` feature
+#[cfg(feature = "periodic_check")]
+const CHECK_INTERVAL: usize = 1024;
 
 /// The `Allocator` type is the way to set up a global allocator.  It implements the
 /// `std::alloc::GlobalAlloc` trait, allowing it to be used as the allocator.
 ///
+/// It also implements the `std::alloc::Allocator` trait, so a (reference to an) `Allocator` can
+/// be used directly with collections, like `Vec::new_in(&heap)` or `Box::new_in(x, &heap)`.
+///
 /// For how to use it in your program, see the crate docs.  For how it works, see the `README.md`
 /// file.
 ///
+/// Each `Allocator` owns its own heap, so several of them can be used side by side, each getting
+/// its memory from its own `MemorySource`.
+///
 /// See the [`std`
 /// docs](https://doc.rust-lang.org/nightly/std/alloc/index.html#the-global_allocator-attribute)
 /// for more information on global allocators.
 #[derive(Debug)]
-pub struct Allocator<T: MemorySource>(pub T);
+pub struct Allocator<T: MemorySource, L: RawLock = SpinLock> {
+    source: T,
+    /// The heap, with a lock for each size of chunk
+    buckets: Buckets<L>,
+    /// Whether threads can keep caches of this allocator's memory
+    #[cfg(feature = "thread_cache")]
+    thread_cache: bool,
+    /// Allocations and frees since the heap was last checked
+    #[cfg(feature = "periodic_check")]
+    ops: AtomicUsize,
+    /// The trace being taken, if there is one
+    #[cfg(all(feature = "trace", target_os = "linux"))]
+    trace: Trace<L>,
+    /// What to do about bad frees, or null to abort
+    #[cfg(feature = "checked")]
+    error_handler: AtomicPtr<()>,
+    /// Held from when a free's checked until it's done, so that two threads freeing the same
+    /// memory can't both pass the check
+    #[cfg(feature = "checked")]
+    frees: Locked<(), L>,
+}
 
-/// The real behind-the-scenes allocator.
-/// It has a global lock over everything.
-#[derive(Debug)]
-struct LockedAllocator {
-    alloc: cell::UnsafeCell<Buckets>,
-    lock: AtomicBool,
+impl<S: MemorySource> Allocator<S> {
+    /// Creates a new `Allocator` with an empty heap, which will get its memory from `source`.
+    ///
+    /// No memory is requested from the source until the first allocation.  It uses `SpinLock`s;
+    /// to pick a different kind of lock, use `with_lock`.
+    pub const fn new(source: S) -> Self {
+        Allocator::with_lock(source)
+    }
 }
 
-static GLOBAL_LOCKED_ALLOCATOR: LockedAllocator = LockedAllocator {
-    alloc: cell::UnsafeCell::new(Buckets::new()),
-    lock: AtomicBool::new(false),
-};
+impl<S: MemorySource, L: RawLock> Allocator<S, L> {
+    /// Creates a new `Allocator` with an empty heap, like `new`, but using locks of type `L` to
+    /// keep each part of the heap to one thread at a time.
+    pub const fn with_lock(source: S) -> Self {
+        Allocator {
+            source,
+            buckets: Buckets::new(),
+            #[cfg(feature = "thread_cache")]
+            thread_cache: false,
+            #[cfg(feature = "periodic_check")]
+            ops: AtomicUsize::new(0),
+            #[cfg(all(feature = "trace", target_os = "linux"))]
+            trace: Trace::new(),
+            #[cfg(feature = "checked")]
+            error_handler: AtomicPtr::new(ptr::null_mut()),
+            #[cfg(feature = "checked")]
+            frees: Locked::new(()),
+        }
+    }
 
-unsafe impl Sync for LockedAllocator {}
+    /// Creates a new `Allocator`, like `with_lock`, but lets each thread keep a cache of small
+    /// chunks of its memory so that small allocations don't need the locks most of the time.
+    ///
+    /// Only one `Allocator` per thread gets a cache: the first one the thread uses.
+    ///
+    /// # Safety
+    ///
+    /// The caches point back to the allocator, so it can't be moved or dropped while any thread
+    /// that's used it is still running.  It's always fine for a `static`, like the
+    /// `#[global_allocator]`.
+    #[cfg(feature = "thread_cache")]
+    pub const unsafe fn with_thread_cache(source: S) -> Self {
+        Allocator {
+            source,
+            buckets: Buckets::new(),
+            thread_cache: true,
+            #[cfg(feature = "periodic_check")]
+            ops: AtomicUsize::new(0),
+            #[cfg(all(feature = "trace", target_os = "linux"))]
+            trace: Trace::new(),
+            #[cfg(feature = "checked")]
+            error_handler: AtomicPtr::new(ptr::null_mut()),
+            #[cfg(feature = "checked")]
+            frees: Locked::new(()),
+        }
+    }
 
-#[derive(Debug)]
-struct Lock<'a>(&'a LockedAllocator);
+    /// Returns a reference to the memory source
+    pub fn source(&self) -> &S {
+        &self.source
+    }
 
-impl<'a> Drop for Lock<'a> {
-    fn drop(&mut self) {
-        let prev = self.0.lock.swap(false, Ordering::SeqCst);
-        debug_assert_eq!(prev, true);
+    /// Returns the usable size of the live allocation at `ptr`, or `None` if there isn't one.
+    ///
+    /// This is at least as big as the size the memory was allocated with, since allocations are
+    /// rounded up to a whole number of chunks.  With the `red_zones` feature, it's exactly that
+    /// size, since the rest is canaries.
+    pub fn usable_size(&self, ptr: ptr::NonNull<u8>) -> Option<usize> {
+        self.get_alloc().usable_size_of(ptr)
     }
-}
 
-impl<'a> ops::Deref for Lock<'a> {
-    type Target = Buckets;
+    /// Frees the allocation at `ptr`, without needing to know its layout.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to a live allocation from this allocator.
+    pub unsafe fn free(&self, ptr: ptr::NonNull<u8>) {
+        debug_log!("Allocator: freeing pointer %#zx\n\0", ptr.as_ptr());
+        let Some(_checked) = self.check_free(ptr, None) else {
+            return;
+        };
+        count!(self.buckets.counters(), frees);
+        #[cfg(feature = "periodic_check")]
+        self.periodic_check();
+        self.traced(
+            || self.get_alloc().dealloc_unsized(ptr),
+            |_| Event::dealloc(ptr, Layout::from_size_align_unchecked(0, 1)),
+        );
+        debug_log!("Allocator: done freeing pointer %#zx\n\n\0", ptr.as_ptr());
+    }
 
-    fn deref(&self) -> &Buckets {
-        unsafe { &*self.0.alloc.get() }
+    /// Gives all of the heap's memory back to the memory source, even what's still allocated, and
+    /// leaves the heap empty, like a new one.  That way a heap for a single request, say, can be
+    /// done with all at once, instead of freeing everything in it.
+    ///
+    /// Memory only goes back to a source that takes it: with one that doesn't override
+    /// `return_block` and `return_huge`, like `Fallback`, it's just forgotten.
+    ///
+    /// # Safety
+    ///
+    /// Nothing allocated from the heap can be used afterwards, and no thread can still have any of
+    /// it in its thread cache.
+    pub unsafe fn release_all(&mut self) {
+        self.get_alloc().release_all();
+        self.buckets = Buckets::new();
     }
-}
-impl<'a> ops::DerefMut for Lock<'a> {
-    fn deref_mut(&mut self) -> &mut Buckets {
-        unsafe { &mut *self.0.alloc.get() }
+
+    pub(crate) fn get_alloc(&self) -> BucketedAllocator<'_, L, S> {
+        BucketedAllocator::new(&self.buckets, &self.source)
+    }
+
+    /// Returns a snapshot of what's in the heap, and counts of what's been done with it.  Only
+    /// with the `stats` feature.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> Stats {
+        self.get_alloc().stats()
+    }
+
+    /// Walks the whole heap, checking that its bookkeeping is consistent: that each stack's bitmap
+    /// agrees with its height and free space bin, that the chains are linked up properly, that every
+    /// record is in the metadata chain, and that no two stacks overlap.
+    ///
+    /// It takes all the locks for as long as it runs, which is a while for a big heap.  With the
+    /// `periodic_check` feature, it's run every so often by the allocator itself.
+    pub fn check(&self) -> Result<(), CheckError> {
+        self.get_alloc().check()
+    }
+
+    /// Checks the canaries around every live allocation, and returns the first one that something
+    /// wrote past the ends of.  Only with the `red_zones` feature, which also checks each
+    /// allocation's canaries when it's freed or reallocated, and aborts if they've changed.
+    ///
+    /// Like `check`, it takes all the locks for as long as it runs.
+    #[cfg(feature = "red_zones")]
+    pub fn check_red_zones(&self) -> Result<(), RedZoneError> {
+        self.get_alloc().check_red_zones()
+    }
+
+    /// Sets how many bytes of freed memory are held back before they can be reused, to catch use
+    /// after free.  Only with the `quarantine` feature.  The default is 1 MiB, and at most 1024
+    /// allocations are held back whatever their size.  If there's more than the new size already
+    /// held back, the oldest is freed straight away.
+    #[cfg(feature = "quarantine")]
+    pub fn set_quarantine_size(&self, size: usize) {
+        self.get_alloc().set_quarantine_size(size)
+    }
+
+    /// Calls `visitor` on every live allocation in the heap, with its address, usable size, and
+    /// the stack it's in.  Handy for finding leaks.
+    ///
+    /// Nothing is allocated while it runs.  It holds all the locks the whole time, though, so
+    /// `visitor` must not allocate or free with this `Allocator`, or it'll deadlock.
+    pub fn visit<F: FnMut(&Allocation)>(&self, visitor: F) {
+        self.get_alloc().visit(visitor)
+    }
+
+    /// Writes a binary dump of the heap's layout, for the `stack_alloc-dump` tool to look at
+    /// later.  Every stack is in it, with its bitmaps, and where its record is in the metadata.
+    ///
+    /// `out` is called with each piece of the dump in turn, and the first error it returns is
+    /// passed back.  Like `visit`, nothing is allocated, but it holds all the locks the whole time,
+    /// so `out` must not use this `Allocator`.
+    pub fn dump<E, W: FnMut(&[u8]) -> Result<(), E>>(&self, out: W) -> Result<(), E> {
+        self.get_alloc().dump(out)
+    }
+
+    /// Starts recording every allocation, free and reallocation, for the `stack_alloc-replay`
+    /// tool.  They're written to the file descriptor `fd` in batches, so the trace isn't complete
+    /// until it's flushed or stopped.  Only with the `trace` feature, on Linux.
+    #[cfg(all(feature = "trace", target_os = "linux"))]
+    pub fn start_trace(&self, fd: ::libc::c_int) {
+        self.trace.start(fd);
+    }
+
+    /// Writes out everything recorded so far, without stopping the trace
+    #[cfg(all(feature = "trace", target_os = "linux"))]
+    pub fn flush_trace(&self) {
+        self.trace.flush();
+    }
+
+    /// Writes out everything recorded so far, and stops recording.  Returns `false` if some of the
+    /// trace couldn't be written.
+    #[cfg(all(feature = "trace", target_os = "linux"))]
+    pub fn stop_trace(&self) -> bool {
+        self.trace.stop()
     }
-}
 
-impl LockedAllocator {
-    fn get_buckets(&self) -> Lock<'_> {
-        let mut spinning = false;
-        while self.lock.swap(true, Ordering::SeqCst) == true {
-            if !spinning {
-                spinning = true;
-                debug_log!("Spinning...\n\0");
+    /// Sets the function that's called when something's freed wrongly: a double free, a free of
+    /// memory from somewhere else, or of the middle of an allocation, or with the wrong size.  If
+    /// it returns, the free is skipped.  The default prints the error and aborts.  Only with the
+    /// `checked` feature.
+    ///
+    /// The handler is called from inside `dealloc`, so it mustn't unwind: a panic that got out of
+    /// a `#[global_allocator]` would be undefined behaviour.
+    #[cfg(feature = "checked")]
+    pub fn set_error_handler(&self, handler: ErrorHandler) {
+        self.error_handler
+            .store(handler as *mut (), Ordering::Release);
+    }
+
+    /// Checks that `ptr` can be freed, calling the error handler if it can't.  If the free should
+    /// go ahead, returns a guard to hold until it's done.
+    ///
+    /// The guard is a lock that comes before all of the heap's, so the free can take any of them.
+    /// It's let go before the handler's called, in case the handler frees something too.
+    #[cfg(feature = "checked")]
+    fn check_free(
+        &self,
+        ptr: ptr::NonNull<u8>,
+        layout: Option<Layout>,
+    ) -> Option<Guard<'_, (), L>> {
+        let guard = self.frees.lock();
+        let error = match self.get_alloc().check_free(ptr, layout) {
+            Ok(()) => return Some(guard),
+            Err(error) => error,
+        };
+        drop(guard);
+        let handler = self.error_handler.load(Ordering::Acquire);
+        let handler: ErrorHandler = if handler.is_null() {
+            checked::abort_on_error
+        } else {
+            // It was only ever set from an `ErrorHandler`
+            unsafe { core::mem::transmute::<*mut (), ErrorHandler>(handler) }
+        };
+        handler(&error);
+        None
+    }
+
+    #[cfg(not(feature = "checked"))]
+    fn check_free(&self, _ptr: ptr::NonNull<u8>, _layout: Option<Layout>) -> Option<()> {
+        Some(())
+    }
+
+    /// Runs `op`, recording the event it makes if a trace is being taken
+    #[cfg(all(feature = "trace", target_os = "linux"))]
+    fn traced<R>(&self, op: impl FnOnce() -> R, event: impl FnOnce(&R) -> Event) -> R {
+        self.trace.record(op, event)
+    }
+
+    #[cfg(not(all(feature = "trace", target_os = "linux")))]
+    fn traced<R>(&self, op: impl FnOnce() -> R, _event: impl FnOnce(&R) -> Event) -> R {
+        op()
+    }
+
+    /// Checks the heap every `CHECK_INTERVAL` allocations and frees, aborting if it's broken
+    #[cfg(feature = "periodic_check")]
+    fn periodic_check(&self) {
+        if self.ops.fetch_add(1, Ordering::Relaxed) % CHECK_INTERVAL == CHECK_INTERVAL - 1 {
+            if let Err(err) = self.check() {
+                fatal!("The heap is corrupted: {}", err);
             }
         }
-        Lock(self)
     }
-}
 
-impl<S: MemorySource> Allocator<S> {
-    fn get_alloc(&self) -> BucketedAllocator<'_, Lock<'_>, S> {
-        BucketedAllocator::new(GLOBAL_LOCKED_ALLOCATOR.get_buckets(), &self.0)
+    /// Counts an allocation, for `stats`
+    #[cfg_attr(not(feature = "stats"), allow(unused_variables))]
+    fn count_alloc(&self, layout: Layout) {
+        count!(self.buckets.counters(), allocs);
+        count!(self.buckets.counters(), bytes_requested, layout.size());
+        count!(
+            self.buckets.counters(),
+            bytes_allocated,
+            bucketed::usable_size(layout)
+        );
     }
+
+    /// Counts a reallocation, for `stats`
+    #[cfg_attr(not(feature = "stats"), allow(unused_variables))]
+    fn count_realloc(&self, ptr: ptr::NonNull<u8>, new_ptr: ptr::NonNull<u8>, new_layout: Layout) {
+        let moved = ptr != new_ptr;
+        count!(self.buckets.counters(), reallocs);
+        count!(
+            self.buckets.counters(),
+            reallocs_in_place,
+            usize::from(!moved)
+        );
+        count!(self.buckets.counters(), reallocs_moved, usize::from(moved));
+        count!(self.buckets.counters(), bytes_requested, new_layout.size());
+        count!(
+            self.buckets.counters(),
+            bytes_allocated,
+            bucketed::usable_size(new_layout)
+        );
+    }
+
+    /// Allocates from this thread's cache if there is one, or else from the heap
+    unsafe fn alloc_cached(&self, layout: Layout) -> Result<ptr::NonNull<u8>, AllocError> {
+        #[cfg(feature = "periodic_check")]
+        self.periodic_check();
+        let result = self.traced(
+            || self.alloc_uncounted(layout),
+            |result| Event::alloc(layout, result),
+        );
+        if result.is_ok() {
+            self.count_alloc(layout);
+        }
+        result
+    }
+
+    unsafe fn alloc_uncounted(&self, layout: Layout) -> Result<ptr::NonNull<u8>, AllocError> {
+        #[cfg(feature = "thread_cache")]
+        {
+            // The cached chunks have no red zones
+            if self.thread_cache && !cfg!(feature = "red_zones") {
+                if let Some(result) = thread_cache::alloc(self, layout) {
+                    return result;
+                }
+            }
+        }
+        self.get_alloc().alloc(layout)
+    }
+
+    /// Deallocates into this thread's cache if there is one, or else into the heap
+    unsafe fn dealloc_cached(&self, ptr: ptr::NonNull<u8>, layout: Layout) {
+        let Some(_checked) = self.check_free(ptr, Some(layout)) else {
+            return;
+        };
+        #[cfg(feature = "periodic_check")]
+        self.periodic_check();
+        count!(self.buckets.counters(), frees);
+        self.traced(
+            || {
+                #[cfg(feature = "thread_cache")]
+                {
+                    // A chunk in a cache still looks allocated, which would hide double frees, and
+                    // the debugging features all have to see the memory when it's freed
+                    if self.thread_cache
+                        && !cfg!(feature = "checked")
+                        && !cfg!(feature = "red_zones")
+                        && !cfg!(feature = "quarantine")
+                        && thread_cache::dealloc(self, ptr, layout)
+                    {
+                        return;
+                    }
+                }
+                self.get_alloc().dealloc(ptr, layout);
+            },
+            |_| Event::dealloc(ptr, layout),
+        );
+    }
+}
+
+/// Returns a dangling (but aligned) pointer, for zero-sized allocations
+fn dangling(layout: Layout) -> ptr::NonNull<[u8]> {
+    let ptr = unsafe { ptr::NonNull::new_unchecked(layout.align() as *mut u8) };
+    ptr::NonNull::slice_from_raw_parts(ptr, 0)
 }
 
 fn to_raw<E>(ptr: Result<ptr::NonNull<u8>, E>) -> *mut u8 {
@@ -85,7 +426,7 @@ fn to_raw<E>(ptr: Result<ptr::NonNull<u8>, E>) -> *mut u8 {
     }
 }
 
-unsafe impl<T: MemorySource> GlobalAlloc for Allocator<T> {
+unsafe impl<T: MemorySource, L: RawLock> GlobalAlloc for Allocator<T, L> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         debug_log!(
             "Allocator: allocating size %zu align %zu\n\0",
@@ -95,7 +436,7 @@ unsafe impl<T: MemorySource> GlobalAlloc for Allocator<T> {
         let ptr = if layout.size() == 0 {
             ptr::null_mut()
         } else {
-            to_raw(self.get_alloc().alloc(layout))
+            to_raw(self.alloc_cached(layout))
         };
         debug_log!("Allocator: done allocating pointer %#zx\n\n\0", ptr);
         ptr
@@ -108,7 +449,7 @@ unsafe impl<T: MemorySource> GlobalAlloc for Allocator<T> {
             ptr
         );
         if let Some(nonnull) = ptr::NonNull::new(ptr) {
-            self.get_alloc().dealloc(nonnull, layout);
+            self.dealloc_cached(nonnull, layout);
         }
         debug_log!("Allocator: done deallocating pointer %#zx\n\n\0", ptr);
     }
@@ -120,11 +461,21 @@ unsafe impl<T: MemorySource> GlobalAlloc for Allocator<T> {
             layout.align(),
             ptr
         );
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
         let new_ptr = if let Some(nonnull) = ptr::NonNull::new(ptr) {
-            self.get_alloc().realloc(nonnull, layout, new_size)
+            let Some(_checked) = self.check_free(nonnull, Some(layout)) else {
+                return ptr::null_mut();
+            };
+            let new_ptr = self.traced(
+                || self.get_alloc().realloc(nonnull, layout, new_size),
+                |new_ptr| Event::realloc(nonnull, layout, new_layout, new_ptr),
+            );
+            if let Ok(new_ptr) = new_ptr {
+                self.count_realloc(nonnull, new_ptr, new_layout);
+            }
+            new_ptr
         } else {
-            self.get_alloc()
-                .alloc(Layout::from_size_align_unchecked(new_size, layout.align()))
+            self.alloc_cached(new_layout)
         };
         let new_ptr = to_raw(new_ptr);
         debug_log!(
@@ -135,3 +486,112 @@ unsafe impl<T: MemorySource> GlobalAlloc for Allocator<T> {
         new_ptr
     }
 }
+
+unsafe impl<T: MemorySource, L: RawLock> alloc::Allocator for Allocator<T, L> {
+    fn allocate(&self, layout: Layout) -> Result<ptr::NonNull<[u8]>, AllocError> {
+        if layout.size() == 0 {
+            return Ok(dangling(layout));
+        }
+        let ptr = unsafe { self.alloc_cached(layout)? };
+        Ok(ptr::NonNull::slice_from_raw_parts(
+            ptr,
+            bucketed::visible_size(layout),
+        ))
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<ptr::NonNull<[u8]>, AllocError> {
+        // Memory from the stacks gets reused, so it has to be zeroed every time
+        let memory = self.allocate(layout)?;
+        unsafe {
+            memory.cast::<u8>().as_ptr().write_bytes(0, memory.len());
+        }
+        Ok(memory)
+    }
+
+    unsafe fn deallocate(&self, ptr: ptr::NonNull<u8>, layout: Layout) {
+        if layout.size() != 0 {
+            self.dealloc_cached(ptr, layout);
+        }
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: ptr::NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<ptr::NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: ptr::NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<ptr::NonNull<[u8]>, AllocError> {
+        let memory = self.resize(ptr, old_layout, new_layout)?;
+        memory
+            .cast::<u8>()
+            .as_ptr()
+            .add(old_layout.size())
+            .write_bytes(0, memory.len() - old_layout.size());
+        Ok(memory)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: ptr::NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<ptr::NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old_layout, new_layout)
+    }
+}
+
+impl<T: MemorySource, L: RawLock> Allocator<T, L> {
+    /// Changes the size of the allocation, in place if possible.  Used for both growing and
+    /// shrinking.
+    unsafe fn resize(
+        &self,
+        ptr: ptr::NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<ptr::NonNull<[u8]>, AllocError> {
+        use core::alloc::Allocator as _;
+
+        if old_layout.size() == 0 {
+            return self.allocate(new_layout);
+        }
+        if new_layout.size() == 0 {
+            self.deallocate(ptr, old_layout);
+            return Ok(dangling(new_layout));
+        }
+
+        let Some(_checked) = self.check_free(ptr, Some(old_layout)) else {
+            return Err(AllocError);
+        };
+        let new_ptr = self.traced(
+            || {
+                if new_layout.align() <= old_layout.align() {
+                    self.get_alloc().realloc(ptr, old_layout, new_layout.size())
+                } else {
+                    // `realloc` keeps the old alignment, so it has to be moved by hand
+                    let new_ptr = self.get_alloc().alloc(new_layout)?;
+                    ptr::copy_nonoverlapping(
+                        ptr.as_ptr(),
+                        new_ptr.as_ptr(),
+                        cmp::min(old_layout.size(), new_layout.size()),
+                    );
+                    self.get_alloc().dealloc(ptr, old_layout);
+                    Ok(new_ptr)
+                }
+            },
+            |new_ptr| Event::realloc(ptr, old_layout, new_layout, new_ptr),
+        )?;
+        self.count_realloc(ptr, new_ptr, new_layout);
+        Ok(ptr::NonNull::slice_from_raw_parts(
+            new_ptr,
+            bucketed::visible_size(new_layout),
+        ))
+    }
+}
@@ -0,0 +1,87 @@
+//! Bookkeeping for huge allocations: the ones too big to fit in a block.
+//!
+//! Huge allocations get their memory straight from the memory source, so the only thing the
+//! allocator has to do is remember them.  They're kept in a linked list of records stored in the
+//! metadata chain.  There usually aren't many of them, so a list is good enough.
+
+use core::alloc::Layout;
+use core::ptr::NonNull;
+
+use metadata_box::MetadataBox;
+
+/// The record of a single huge allocation
+#[derive(Debug)]
+pub struct HugeAllocation {
+    /// The memory, as returned by the memory source
+    ptr: NonNull<u8>,
+    /// The layout that the memory was requested with
+    layout: Layout,
+    /// The rest of the list
+    next: Option<MetadataBox<HugeAllocation>>,
+}
+
+impl HugeAllocation {
+    /// Creates a new record, to go at the head of the list `next`
+    pub fn new(
+        ptr: NonNull<u8>,
+        layout: Layout,
+        next: Option<MetadataBox<HugeAllocation>>,
+    ) -> Self {
+        HugeAllocation { ptr, layout, next }
+    }
+
+    /// Returns a pointer to the memory
+    pub fn pointer(&self) -> NonNull<u8> {
+        self.ptr
+    }
+
+    /// Returns the layout the memory was requested with
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+}
+
+/// Finds the record for the huge allocation at `ptr` in the list
+pub fn find(
+    list: &Option<MetadataBox<HugeAllocation>>,
+    ptr: NonNull<u8>,
+) -> Option<&HugeAllocation> {
+    let mut link = list;
+    while let Some(record) = link {
+        if record.ptr == ptr {
+            return Some(record);
+        }
+        link = &record.next;
+    }
+    None
+}
+
+/// Returns an iterator over the records in the list
+pub fn iter(list: &Option<MetadataBox<HugeAllocation>>) -> impl Iterator<Item = &HugeAllocation> {
+    let mut link = list;
+    core::iter::from_fn(move || {
+        let record = link.as_ref()?;
+        link = &record.next;
+        Some(&**record)
+    })
+}
+
+/// Returns the number of huge allocations in the list
+#[cfg(feature = "stats")]
+pub fn count(list: &Option<MetadataBox<HugeAllocation>>) -> usize {
+    iter(list).count()
+}
+
+/// Takes the record for the huge allocation at `ptr` out of the list
+pub fn remove(
+    list: &mut Option<MetadataBox<HugeAllocation>>,
+    ptr: NonNull<u8>,
+) -> Option<MetadataBox<HugeAllocation>> {
+    let mut link = list;
+    while link.as_ref().is_some_and(|record| record.ptr != ptr) {
+        link = &mut link.as_mut().unwrap().next;
+    }
+    let mut record = link.take()?;
+    *link = record.next.take();
+    Some(record)
+}
@@ -23,6 +23,14 @@
 //! }
 //! ```
 //!
+//! On Linux, the `mmap_source` feature has a couple of ready-made ones: `MmapSource`, which maps its
+//! memory straight from the kernel, and `ReservedRegionSource`, which reserves one big range of
+//! address space and commits blocks in it as they're needed.  Without an OS at all,
+//! `StaticRegionSource` hands out blocks from a fixed region of memory, like a `static` array or
+//! somewhere the linker script set aside, and `RegionListSource` does the same over a list of
+//! regions, like a bootloader's memory map.  And `memory_source::GlobalAllocSource` gets blocks
+//! from another allocator, like `std::alloc::System`.
+//!
 //! ## Setting the global allocator
 //!
 //! Now, you need to tell the compiler that you want to use this as your allocator:
@@ -37,21 +45,64 @@
 //! }
 //!
 //! #[global_allocator]
-//! static GLOBAL: Allocator<MyAmazingMemorySource> = Allocator(MyAmazingMemorySource);
+//! static GLOBAL: Allocator<MyAmazingMemorySource> = Allocator::new(MyAmazingMemorySource);
 //! ```
 //!
 //! ## Allocating things
 //!
 //! Now you can allocate all you want: all the memory used in `Box`, `Vec`, `String`, etc. will be
 //! obtained from `MyAmazingMemorySource` and then managed by the library.
+//!
+//! ## Multiple heaps
+//!
+//! Every `Allocator` has its own heap and its own locks, so it's fine to have more than one.  Memory
+//! from one is never handed out by another, so each can have a different memory source.
+//!
+//! ## Thread caches
+//!
+//! With the `thread_cache` feature, an `Allocator` made with `Allocator::with_thread_cache` gives
+//! each thread a cache of small chunks, so that most small allocations don't need to take a lock.
+//!
+//! ## Statistics
+//!
+//! With the `stats` feature, `Allocator::stats` returns a snapshot of the heap: how many blocks it's
+//! gotten from the memory source, how many stacks and chunks are in use for each size, how much of
+//! the memory handed out was rounded up, and how many allocations, frees and reallocations there
+//! have been.
+//!
+//! ## Checking the heap
+//!
+//! `Allocator::check` goes through the whole heap, and returns a `CheckError` saying what's wrong if
+//! its bookkeeping doesn't add up.  With the `periodic_check` feature, the allocator does it by
+//! itself every so often, and aborts if the heap is broken.
+//!
+//! `Allocator::visit` goes through every live allocation in the heap, without allocating, which is
+//! handy for finding leaks.
+//!
+//! `Allocator::dump` writes out the layout of the whole heap in a binary format, which the
+//! `stack_alloc-dump` tool can read later to show how full and fragmented each size is.
+//!
+//! With the `checked` feature, every free is checked against the allocation it's freeing first.
+//! Double frees, frees of memory from elsewhere or of the middle of an allocation, and frees with
+//! the wrong size are passed to the handler from `Allocator::set_error_handler` (which aborts by
+//! default, and mustn't unwind), and skipped.
+//!
+//! With the `red_zones` feature, every allocation is padded with canary bytes on both sides.  They
+//! are checked when it's freed or reallocated, which aborts if they've been written over, and
+//! `Allocator::check_red_zones` checks the whole heap's at once.
+//!
+//! With the `quarantine` feature, freed memory is filled with `quarantine::POISON` and held back for
+//! a while before it can be reused, so a use after free reads garbage.  When it's finally freed,
+//! it's checked that nothing wrote to it in the meantime, and the process aborts if something did.
+//! `Allocator::set_quarantine_size` sets how much is held back.
+//!
+//! ## Tracing
+//!
+//! With the `trace` feature, `Allocator::start_trace` records everything done with the heap to a
+//! file, which the `stack_alloc-replay` tool can play back against a fresh heap.
 
 #![no_std]
-#![feature(nll)]
-#![feature(alloc)]
 #![feature(allocator_api)]
-#![feature(ptr_offset_from)]
-#![feature(const_fn, const_let)]
-#![feature(cell_update)]
 #![warn(
     missing_docs,
     missing_debug_implementations,
@@ -63,22 +114,64 @@
 
 extern crate alloc;
 
-#[cfg(any(feature = "debug_logs", feature = "test_memory_source"))]
+#[cfg(any(
+    feature = "debug_logs",
+    feature = "test_memory_source",
+    all(feature = "trace", target_os = "linux"),
+    all(feature = "futex", target_os = "linux"),
+    all(feature = "mmap_source", target_os = "linux")
+))]
 extern crate libc;
+#[cfg(feature = "thread_cache")]
+extern crate std;
 
 #[macro_use]
 mod macros;
 mod bitmapped_stack;
 mod bucketed;
+pub mod check;
+#[cfg(feature = "checked")]
+pub mod checked;
+pub mod dump;
 pub mod global_allocator;
+mod huge;
+pub mod lock;
 pub mod memory_source;
 mod metadata_box;
+#[cfg(all(feature = "mmap_source", target_os = "linux"))]
+mod mmap_source;
+mod page_map;
+#[cfg(feature = "quarantine")]
+pub mod quarantine;
+#[cfg(feature = "red_zones")]
+pub mod red_zone;
+mod region_list_source;
+#[cfg(all(feature = "mmap_source", target_os = "linux"))]
+mod reserved_region_source;
 mod sized_allocator;
+mod static_region_source;
+#[cfg(feature = "stats")]
+pub mod stats;
+#[cfg(feature = "thread_cache")]
+mod thread_cache;
+pub mod trace;
+pub mod visit;
 
 #[cfg(feature = "test_memory_source")]
 mod test_memory_source;
 #[cfg(feature = "test_memory_source")]
 pub use test_memory_source::TestMemorySource;
 
+pub use check::CheckError;
 pub use global_allocator::Allocator;
+pub use lock::RawLock;
 pub use memory_source::MemorySource;
+#[cfg(all(feature = "mmap_source", target_os = "linux"))]
+pub use mmap_source::MmapSource;
+pub use region_list_source::RegionListSource;
+#[cfg(all(feature = "mmap_source", target_os = "linux"))]
+pub use reserved_region_source::{ReservedRegionSource, DEFAULT_RESERVATION};
+pub use static_region_source::StaticRegionSource;
+#[cfg(feature = "stats")]
+pub use stats::Stats;
+pub use visit::Allocation;
@@ -0,0 +1,273 @@
+//! The locks that an `Allocator` can use to keep its heap to one thread at a time.
+//!
+//! Which one is best depends on where it's running:
+//!  * `SpinLock` just spins until it gets the lock.  It's the default, and it's fine when there
+//!    isn't much contention.
+//!  * `BackoffLock` spins, but waits longer and longer between tries, so that threads fighting
+//!    over it don't keep hammering the same cache line.
+//!  * `FutexLock` puts waiting threads to sleep with Linux's `futex` system call, so they don't
+//!    burn CPU or starve the thread holding the lock.  It needs the `futex` feature.
+//!  * `NoLock` doesn't lock at all, for heaps that only one thread uses.
+//!
+//! An `Allocator` has several locks, one for each size of chunk it keeps separate chains of, so
+//! they're made with `RawLock::INIT` rather than passed in.
+
+use core::cell::{Cell, UnsafeCell};
+use core::hint;
+use core::marker::PhantomData;
+use core::ops;
+#[cfg(all(feature = "futex", target_os = "linux"))]
+use core::ptr;
+#[cfg(all(feature = "futex", target_os = "linux"))]
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// A lock that doesn't hold any data itself.
+///
+/// # Safety
+///
+/// Implementations must make sure that only one thread at a time can be between `lock` and
+/// `unlock`, or else not be `Sync`, so that only one thread can use them at all.
+pub unsafe trait RawLock {
+    /// A new, unlocked lock
+    const INIT: Self;
+
+    /// Waits until the lock is free, and takes it
+    fn lock(&self);
+
+    /// Releases the lock.
+    ///
+    /// # Safety
+    ///
+    /// The lock must be held by the caller.
+    unsafe fn unlock(&self);
+}
+
+/// A lock that spins until it's free.  It never yields, so it can waste a lot of CPU under
+/// contention.
+#[derive(Debug, Default)]
+pub struct SpinLock {
+    locked: AtomicBool,
+}
+
+impl SpinLock {
+    /// Creates a new, unlocked `SpinLock`
+    pub const fn new() -> Self {
+        SpinLock {
+            locked: AtomicBool::new(false),
+        }
+    }
+}
+
+unsafe impl RawLock for SpinLock {
+    const INIT: Self = SpinLock::new();
+
+    fn lock(&self) {
+        let mut spinning = false;
+        while self.locked.swap(true, Ordering::Acquire) {
+            if !spinning {
+                spinning = true;
+                debug_log!("Spinning...\n\0");
+            }
+            hint::spin_loop();
+        }
+    }
+
+    unsafe fn unlock(&self) {
+        let prev = self.locked.swap(false, Ordering::Release);
+        debug_assert!(prev);
+    }
+}
+
+/// The most times `BackoffLock` spins between tries
+const MAX_BACKOFF: u32 = 1 << 10;
+
+/// A spin lock that waits exponentially longer between each try
+#[derive(Debug, Default)]
+pub struct BackoffLock {
+    locked: AtomicBool,
+}
+
+impl BackoffLock {
+    /// Creates a new, unlocked `BackoffLock`
+    pub const fn new() -> Self {
+        BackoffLock {
+            locked: AtomicBool::new(false),
+        }
+    }
+}
+
+unsafe impl RawLock for BackoffLock {
+    const INIT: Self = BackoffLock::new();
+
+    fn lock(&self) {
+        let mut backoff = 1;
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            // Only try again once it looks free, so waiting doesn't keep taking the cache line
+            while self.locked.load(Ordering::Relaxed) {
+                for _ in 0..backoff {
+                    hint::spin_loop();
+                }
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+
+    unsafe fn unlock(&self) {
+        let prev = self.locked.swap(false, Ordering::Release);
+        debug_assert!(prev);
+    }
+}
+
+/// A lock that sleeps in the kernel while it waits, using Linux futexes.
+///
+/// This is the usual three-state futex mutex: 0 is unlocked, 1 is locked, and 2 is locked with
+/// (maybe) some threads waiting, so that unlocking only needs a system call if someone's asleep.
+#[cfg(all(feature = "futex", target_os = "linux"))]
+#[derive(Debug, Default)]
+pub struct FutexLock {
+    state: AtomicU32,
+}
+
+#[cfg(all(feature = "futex", target_os = "linux"))]
+impl FutexLock {
+    /// Creates a new, unlocked `FutexLock`
+    pub const fn new() -> Self {
+        FutexLock {
+            state: AtomicU32::new(0),
+        }
+    }
+
+    fn futex(&self, op: ::libc::c_int, val: u32) {
+        unsafe {
+            ::libc::syscall(
+                ::libc::SYS_futex,
+                self.state.as_ptr(),
+                op | ::libc::FUTEX_PRIVATE_FLAG,
+                val,
+                ptr::null::<::libc::timespec>(),
+            );
+        }
+    }
+}
+
+#[cfg(all(feature = "futex", target_os = "linux"))]
+unsafe impl RawLock for FutexLock {
+    const INIT: Self = FutexLock::new();
+
+    fn lock(&self) {
+        if self
+            .state
+            .compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            return;
+        }
+        // Mark it as having waiters, and sleep until it's unlocked
+        while self.state.swap(2, Ordering::Acquire) != 0 {
+            debug_log!("Waiting on the futex...\n\0");
+            self.futex(::libc::FUTEX_WAIT, 2);
+        }
+    }
+
+    unsafe fn unlock(&self) {
+        let prev = self.state.swap(0, Ordering::Release);
+        debug_assert_ne!(prev, 0);
+        if prev == 2 {
+            self.futex(::libc::FUTEX_WAKE, 1);
+        }
+    }
+}
+
+/// A lock that doesn't do anything, for heaps that only ever have one thread.
+///
+/// It can't be shared between threads, so neither can an `Allocator` that uses it.  That means it
+/// can't be the `#[global_allocator]`, but it's fine for a heap that's local to one thread.
+///
+/// In debug builds, it still checks that it's never taken twice at once.
+#[derive(Debug, Default)]
+pub struct NoLock {
+    #[cfg(debug_assertions)]
+    locked: Cell<bool>,
+    /// Keeps it from being `Sync`
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+impl NoLock {
+    /// Creates a new `NoLock`
+    pub const fn new() -> Self {
+        NoLock {
+            #[cfg(debug_assertions)]
+            locked: Cell::new(false),
+            _not_sync: PhantomData,
+        }
+    }
+}
+
+unsafe impl RawLock for NoLock {
+    const INIT: Self = NoLock::new();
+
+    fn lock(&self) {
+        #[cfg(debug_assertions)]
+        {
+            let prev = self.locked.replace(true);
+            debug_assert!(!prev, "A NoLock was taken twice at once");
+        }
+    }
+
+    unsafe fn unlock(&self) {
+        #[cfg(debug_assertions)]
+        self.locked.set(false);
+    }
+}
+
+/// Some data that can only be used while holding its lock
+#[derive(Debug)]
+pub(crate) struct Locked<T, L: RawLock> {
+    data: UnsafeCell<T>,
+    lock: L,
+}
+
+unsafe impl<T, L: RawLock + Sync> Sync for Locked<T, L> {}
+
+impl<T, L: RawLock> Locked<T, L> {
+    pub(crate) const fn new(data: T) -> Self {
+        Locked {
+            data: UnsafeCell::new(data),
+            lock: L::INIT,
+        }
+    }
+
+    /// Waits for the lock, and returns a guard that releases it when it's dropped
+    pub(crate) fn lock(&self) -> Guard<'_, T, L> {
+        self.lock.lock();
+        Guard(self)
+    }
+}
+
+/// Access to locked data, while the lock is held
+#[derive(Debug)]
+pub(crate) struct Guard<'a, T: 'a, L: RawLock + 'a>(&'a Locked<T, L>);
+
+impl<'a, T, L: RawLock> Drop for Guard<'a, T, L> {
+    fn drop(&mut self) {
+        unsafe { self.0.lock.unlock() };
+    }
+}
+
+impl<'a, T, L: RawLock> ops::Deref for Guard<'a, T, L> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.0.data.get() }
+    }
+}
+impl<'a, T, L: RawLock> ops::DerefMut for Guard<'a, T, L> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.0.data.get() }
+    }
+}
@@ -1,4 +1,12 @@
-//! Logging macro for debugging
+//! Macros for logging, counting, and giving up
+
+#[cfg(any(
+    feature = "periodic_check",
+    feature = "checked",
+    feature = "red_zones",
+    feature = "quarantine"
+))]
+use core::fmt;
 
 macro_rules! debug_log {
     ($format:expr, $($arg:tt)*) => (
@@ -12,3 +20,47 @@ macro_rules! debug_log {
         debug_log!($format, );
     );
 }
+
+/// Panics without unwinding, so it's safe from inside `GlobalAlloc`: the message is printed like
+/// any panic's, and then the process aborts
+#[cfg(any(
+    feature = "periodic_check",
+    feature = "checked",
+    feature = "red_zones",
+    feature = "quarantine"
+))]
+macro_rules! fatal {
+    ($($arg:tt)*) => {
+        ::macros::abort_with(format_args!($($arg)*))
+    };
+}
+
+/// Unwinding out of an `extern "C"` function aborts instead, so a panic in here can't get any
+/// further
+#[cfg(any(
+    feature = "periodic_check",
+    feature = "checked",
+    feature = "red_zones",
+    feature = "quarantine"
+))]
+#[cold]
+#[inline(never)]
+#[allow(improper_ctypes_definitions)]
+pub(crate) extern "C" fn abort_with(message: fmt::Arguments) -> ! {
+    panic!("{}", message)
+}
+
+/// Adds to one of an allocator's counters, if the `stats` feature is on
+macro_rules! count {
+    ($counters:expr, $counter:ident, $amount:expr) => {
+        #[cfg(feature = "stats")]
+        {
+            $counters
+                .$counter
+                .fetch_add($amount, ::core::sync::atomic::Ordering::Relaxed);
+        }
+    };
+    ($counters:expr, $counter:ident) => {
+        count!($counters, $counter, 1);
+    };
+}
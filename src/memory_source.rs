@@ -16,6 +16,8 @@
 //! type MyReliableMemorySource = Fallback<MyUnreliableMemorySource, TODO>;
 //! ```
 
+use core::alloc::{GlobalAlloc, Layout};
+use core::cmp;
 use core::ptr::NonNull;
 
 /// The size, in bytes, of a returned block
@@ -32,6 +34,12 @@ pub const BLOCK_ALIGN: usize = 4096;
 ///
 /// For example, in web assembly, the way to get memory is different from on Linux, and in a
 /// bare-metal situation you'd have to make your own stack or something.
+///
+/// # Safety
+///
+/// The allocator trusts the memory it gets completely.  Implementations must make sure that the
+/// memory they hand out fulfills the layout requirements, and isn't used by anything else until
+/// it's given back.
 pub unsafe trait MemorySource {
     /// Potentially returns a block of memory.
     ///
@@ -41,7 +49,53 @@ pub unsafe trait MemorySource {
     ///
     /// If it returns `Some(thing)`, then ownership of the block of memory pointed to by `thing` is
     /// transferred to the caller.
+    ///
+    /// # Safety
+    ///
+    /// The caller is responsible for the block, and should give it back with `return_block` (or
+    /// leak it).
     unsafe fn get_block(&self) -> Option<NonNull<u8>>;
+
+    /// Gives a block of memory back to the memory source.
+    ///
+    /// The allocator calls this when a block it got from `get_block` is completely empty, so the
+    /// memory can be reused elsewhere.  After this, the allocator won't touch the block again.
+    ///
+    /// The default implementation does nothing, so the block is leaked.
+    ///
+    /// # Safety
+    ///
+    /// The block must have come from `get_block` on this same source, and must not be used
+    /// anymore.
+    unsafe fn return_block(&self, _block: NonNull<u8>) {}
+
+    /// Potentially returns memory for an allocation that's too big to fit in a block.
+    ///
+    /// The memory needs to be at least `layout.size()` bytes large and aligned to
+    /// `layout.align()`.  It's only used for allocations bigger than `BLOCK_SIZE`.
+    ///
+    /// If it returns `Some(thing)`, then ownership of the memory pointed to by `thing` is
+    /// transferred to the caller.  The default implementation always returns `None`, so huge
+    /// allocations fail.
+    ///
+    /// # Safety
+    ///
+    /// The caller is responsible for the memory, and should give it back with `return_huge` (or
+    /// leak it).
+    unsafe fn get_huge(&self, _layout: Layout) -> Option<NonNull<u8>> {
+        None
+    }
+
+    /// Gives memory from `get_huge` back to the memory source.
+    ///
+    /// `layout` is the same layout that was passed to `get_huge`.  The default implementation does
+    /// nothing, so the memory is leaked.
+    ///
+    /// # Safety
+    ///
+    /// The memory must have come from `get_huge` on this same source, and must not be used
+    /// anymore.
+    unsafe fn return_huge(&self, _ptr: NonNull<u8>, _layout: Layout) {}
 }
 
 /// A memory source that is never successful in returning memory.
@@ -61,6 +115,8 @@ unsafe impl MemorySource for NoMemory {
 
 /// `Fallback<T, U>` first tries to get memory from `T`, but gets it from `U` if that is
 /// unsuccessful.
+///
+/// Since it doesn't keep track of which source memory came from, it never gives anything back.
 #[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
 pub struct Fallback<T, U>(pub T, pub U);
 
@@ -72,4 +128,46 @@ where
     unsafe fn get_block(&self) -> Option<NonNull<u8>> {
         self.0.get_block().or_else(|| self.1.get_block())
     }
+
+    unsafe fn get_huge(&self, layout: Layout) -> Option<NonNull<u8>> {
+        self.0.get_huge(layout).or_else(|| self.1.get_huge(layout))
+    }
+}
+
+/// `GlobalAllocSource<A>` gets its memory from another allocator, `A`, and gives it back there.
+///
+/// That way, a heap can be run on top of `std::alloc::System`, or any other `GlobalAlloc`, like
+/// one heap per request.  Blocks go back to `A` as soon as they're empty, and
+/// `Allocator::release_all` gives back the rest when the request's done.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
+pub struct GlobalAllocSource<A>(pub A);
+
+/// The layout of a block
+fn block_layout() -> Layout {
+    unsafe { Layout::from_size_align_unchecked(BLOCK_SIZE, BLOCK_ALIGN) }
+}
+
+/// Huge allocations are at least as aligned as a block
+fn huge_layout(layout: Layout) -> Layout {
+    unsafe {
+        Layout::from_size_align_unchecked(layout.size(), cmp::max(layout.align(), BLOCK_ALIGN))
+    }
+}
+
+unsafe impl<A: GlobalAlloc> MemorySource for GlobalAllocSource<A> {
+    unsafe fn get_block(&self) -> Option<NonNull<u8>> {
+        NonNull::new(self.0.alloc(block_layout()))
+    }
+
+    unsafe fn return_block(&self, block: NonNull<u8>) {
+        self.0.dealloc(block.as_ptr(), block_layout())
+    }
+
+    unsafe fn get_huge(&self, layout: Layout) -> Option<NonNull<u8>> {
+        NonNull::new(self.0.alloc(huge_layout(layout)))
+    }
+
+    unsafe fn return_huge(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.0.dealloc(ptr.as_ptr(), huge_layout(layout))
+    }
 }
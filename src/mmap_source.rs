@@ -0,0 +1,135 @@
+//! A memory source that gets its memory straight from the kernel, with `mmap`
+
+use core::alloc::Layout;
+use core::cmp;
+use core::ptr::{self, NonNull};
+
+use memory_source::{MemorySource, BLOCK_ALIGN, BLOCK_SIZE};
+
+/// Gets memory with `mmap`, and gives it back with `munmap`.  Only on Linux, with the
+/// `mmap_source` feature.
+///
+/// ```no_run
+/// extern crate stack_alloc;
+/// use stack_alloc::{Allocator, MmapSource};
+///
+/// #[global_allocator]
+/// static GLOBAL: Allocator<MmapSource> = Allocator::new(MmapSource::new().noreserve(true));
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MmapSource {
+    noreserve: bool,
+    populate: bool,
+}
+
+impl MmapSource {
+    /// Creates a source that maps plain private, anonymous memory
+    pub const fn new() -> Self {
+        MmapSource {
+            noreserve: false,
+            populate: false,
+        }
+    }
+
+    /// Sets whether to map with `MAP_NORESERVE`, so that no swap space is set aside for the memory.
+    /// With overcommit turned off, that lets a heap have more memory mapped than there's room
+    /// for, as long as it doesn't use it all.
+    pub const fn noreserve(self, noreserve: bool) -> Self {
+        MmapSource { noreserve, ..self }
+    }
+
+    /// Sets whether to map with `MAP_POPULATE`, so that every page is faulted in up front.  Getting
+    /// a block is slower, but nothing page faults the first time it touches the memory.
+    pub const fn populate(self, populate: bool) -> Self {
+        MmapSource { populate, ..self }
+    }
+
+    fn flags(&self) -> ::libc::c_int {
+        let mut flags = ::libc::MAP_PRIVATE | ::libc::MAP_ANONYMOUS;
+        if self.noreserve {
+            flags |= ::libc::MAP_NORESERVE;
+        }
+        if self.populate {
+            flags |= ::libc::MAP_POPULATE;
+        }
+        flags
+    }
+
+    /// Maps fresh memory for the layout.
+    ///
+    /// `mmap` only guarantees page alignment, so for bigger alignments it maps extra and trims the
+    /// ends off.
+    unsafe fn map(&self, layout: Layout) -> Option<NonNull<u8>> {
+        let page_size = page_size();
+        let align = cmp::max(layout.align(), page_size);
+        let size = round_up_to_alignment(layout.size(), page_size);
+        let mapped_size = size.checked_add(align - page_size)?;
+
+        let start = ::libc::mmap(
+            ptr::null_mut(),
+            mapped_size,
+            ::libc::PROT_READ | ::libc::PROT_WRITE,
+            self.flags(),
+            -1,
+            0,
+        );
+        if start == ::libc::MAP_FAILED {
+            debug_log!("MmapSource: mmap failed for %zu bytes\n\0", mapped_size);
+            return None;
+        }
+
+        let start = start as usize;
+        let aligned_start = round_up_to_alignment(start, align);
+        if aligned_start > start {
+            ::libc::munmap(start as *mut ::libc::c_void, aligned_start - start);
+        }
+        let end = aligned_start + size;
+        let mapped_end = start + mapped_size;
+        if mapped_end > end {
+            ::libc::munmap(end as *mut ::libc::c_void, mapped_end - end);
+        }
+        NonNull::new(aligned_start as *mut u8)
+    }
+}
+
+fn page_size() -> usize {
+    unsafe { ::libc::sysconf(::libc::_SC_PAGESIZE) as usize }
+}
+
+/// Rounds the given number up to fit the alignment.
+/// `alignment` must be a power of 2.
+fn round_up_to_alignment(x: usize, alignment: usize) -> usize {
+    (x + alignment - 1) & !(alignment - 1)
+}
+
+/// Unmaps memory from `map`
+unsafe fn unmap(ptr: NonNull<u8>, layout: Layout) {
+    let size = round_up_to_alignment(layout.size(), page_size());
+    ::libc::munmap(ptr.as_ptr() as *mut ::libc::c_void, size);
+}
+
+fn block_layout() -> Layout {
+    unsafe { Layout::from_size_align_unchecked(BLOCK_SIZE, BLOCK_ALIGN) }
+}
+
+unsafe impl MemorySource for MmapSource {
+    unsafe fn get_block(&self) -> Option<NonNull<u8>> {
+        debug_log!("MmapSource: mapping a block\n\0");
+        self.map(block_layout())
+    }
+
+    unsafe fn return_block(&self, block: NonNull<u8>) {
+        debug_log!("MmapSource: unmapping block %#zx\n\0", block);
+        unmap(block, block_layout())
+    }
+
+    unsafe fn get_huge(&self, layout: Layout) -> Option<NonNull<u8>> {
+        debug_log!("MmapSource: mapping %zu bytes\n\0", layout.size());
+        self.map(layout)
+    }
+
+    unsafe fn return_huge(&self, ptr: NonNull<u8>, layout: Layout) {
+        debug_log!("MmapSource: unmapping %zu bytes\n\0", layout.size());
+        unmap(ptr, layout)
+    }
+}
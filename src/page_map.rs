@@ -0,0 +1,429 @@
+//! The page map: an address-indexed map from each page of the heap to the allocators that own it.
+//!
+//! With it, the owner of a pointer can be found straight away, no matter how long the chains of
+//! `SizedAllocator`s get.
+//!
+//! Every stack is made of whole chunks of a bigger stack, so the stacks line up nicely with pages:
+//!  * Very large stacks are whole blocks, 64 pages each
+//!  * Medium, large, and metadata stacks are made of whole pages, since they're allocated from the
+//!    very large stacks, whose chunks are pages
+//!  * Very small and small stacks are smaller than a page, and are allocated from the medium and
+//!    large stacks.  Since their size is the chunk size of the stack they come from, they're
+//!    aligned to their own size.  They're kept in a per-page table of children.
+//!
+//! The map itself is a radix tree, keyed by page number.  Its nodes are a page each, and they get
+//! their memory straight from the memory source, so that updating the map never needs to
+//! allocate from the heap it's describing.
+//!
+//! Its entries are atomic, so that looking up a pointer doesn't need any locks.  Nodes are never
+//! taken out of the tree, so once a lookup finds a node, it stays valid.
+
+use core::alloc::AllocError;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use bitmapped_stack::STACK_SIZE;
+use memory_source::{BLOCK_ALIGN, BLOCK_SIZE};
+use sized_allocator::SizedAllocator;
+
+/// The size, in bytes, of a page, which is also the size of each node in the tree
+pub const PAGE_SIZE: usize = BLOCK_ALIGN;
+
+/// The size, in bytes, of the smallest stack: very small stacks are 64 chunks of 1 byte
+const SLOT_SIZE: usize = 64;
+
+/// The number of slots for children in each page
+const SLOTS: usize = PAGE_SIZE / SLOT_SIZE;
+
+const INTERIOR_FANOUT: usize = PAGE_SIZE / size_of::<usize>();
+const INTERIOR_BITS: u32 = INTERIOR_FANOUT.trailing_zeros();
+const LEAF_FANOUT: usize = PAGE_SIZE / size_of::<PageEntry>();
+const LEAF_BITS: u32 = LEAF_FANOUT.trailing_zeros();
+const KEY_BITS: u32 = usize::BITS - PAGE_SIZE.trailing_zeros();
+/// The number of levels of interior nodes above the leaves
+const INTERIOR_LEVELS: u32 = (KEY_BITS - LEAF_BITS).div_ceil(INTERIOR_BITS);
+
+/// Registering a stack can add a path of nodes from the root down to a leaf, or two if it spans two
+/// leaves.  Extending one chain can extend the chains it gets memory from too, so there's room for
+/// a handful of those.
+const RESERVED_NODES: usize = 8 * (INTERIOR_LEVELS as usize + 1);
+
+/// The allocators whose stacks start in a page, indexed by 64-byte slot
+pub type Children = [AtomicPtr<SizedAllocator>; SLOTS];
+
+/// Marks a page's stack as being a large one.  Allocators are word-aligned, so it's free.
+const LARGE_TAG: usize = 1;
+
+type Interior = [AtomicPtr<u8>; INTERIOR_FANOUT];
+type Leaf = [PageEntry; LEAF_FANOUT];
+
+/// Everything the page map knows about a single page.
+///
+/// The fields only change while the core lock is held, but they can be read at any time, so they're
+/// atomic.
+#[derive(Debug)]
+pub struct PageEntry {
+    /// The very large allocator whose block this page is in
+    block: AtomicPtr<SizedAllocator>,
+    /// The medium or large allocator whose stack this page is in.  Large ones have `LARGE_TAG` set,
+    /// so that lookups can tell which it is without reading the allocator.
+    stack: AtomicPtr<SizedAllocator>,
+    /// The metadata allocator whose stack this page is
+    metadata: AtomicPtr<SizedAllocator>,
+    /// The very small or small allocators whose stacks are in this page
+    children: AtomicPtr<Children>,
+}
+
+impl PageEntry {
+    /// The very large allocator whose block this page is in
+    pub fn block(&self) -> Option<NonNull<SizedAllocator>> {
+        NonNull::new(self.block.load(Ordering::Acquire))
+    }
+
+    /// The medium allocator whose stack this page is in
+    pub fn medium(&self) -> Option<NonNull<SizedAllocator>> {
+        let stack = self.stack.load(Ordering::Acquire);
+        if stack.addr() & LARGE_TAG == 0 {
+            NonNull::new(stack)
+        } else {
+            None
+        }
+    }
+
+    /// The large allocator whose stack this page is in
+    pub fn large(&self) -> Option<NonNull<SizedAllocator>> {
+        let stack = self.stack.load(Ordering::Acquire);
+        if stack.addr() & LARGE_TAG != 0 {
+            NonNull::new(stack.map_addr(|addr| addr & !LARGE_TAG))
+        } else {
+            None
+        }
+    }
+
+    /// The metadata allocator whose stack this page is
+    pub fn metadata(&self) -> Option<NonNull<SizedAllocator>> {
+        NonNull::new(self.metadata.load(Ordering::Acquire))
+    }
+
+    /// The very small or small allocators whose stacks are in this page
+    fn children(&self) -> Option<&Children> {
+        let children = self.children.load(Ordering::Acquire);
+        unsafe { children.as_ref() }
+    }
+}
+
+/// Which of the page entry's owners an allocator is
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Owner {
+    /// A very large allocator, owning a whole block
+    Block,
+    /// A medium allocator
+    Medium,
+    /// A large allocator
+    Large,
+    /// A metadata allocator
+    Metadata,
+    /// A very small or small allocator, smaller than a page
+    Child,
+}
+
+/// A node on one of the free lists
+struct FreeNode {
+    next: Option<NonNull<FreeNode>>,
+}
+
+/// The unused memory for the page map's nodes.
+///
+/// The map can be read without any locks, but changing it might need new nodes, so this is kept
+/// behind a lock.
+#[derive(Debug, Default)]
+pub struct NodePool {
+    /// Unused nodes
+    free_nodes: Option<NonNull<FreeNode>>,
+    /// The length of `free_nodes`
+    free_count: usize,
+    /// Unused tables of children
+    free_children: Option<NonNull<FreeNode>>,
+    /// The blocks the nodes came from, linked through the first table's worth of each
+    blocks: Option<NonNull<FreeNode>>,
+}
+
+impl NodePool {
+    /// Creates a new, empty `NodePool`
+    pub const fn new() -> Self {
+        NodePool {
+            free_nodes: None,
+            free_count: 0,
+            free_children: None,
+            blocks: None,
+        }
+    }
+
+    /// Makes sure there are enough unused nodes that registering the next few allocators won't
+    /// need any more memory from the source.  If there aren't, it calls `get_block` for more.
+    pub unsafe fn reserve(
+        &mut self,
+        get_block: impl FnOnce() -> Result<NonNull<u8>, AllocError>,
+    ) -> Result<(), AllocError> {
+        if self.free_count >= RESERVED_NODES {
+            return Ok(());
+        }
+        debug_log!("PageMap: getting more nodes from the memory source\n\0");
+        let block = get_block()?;
+        // The first page is split into tables of children, except for the first table, which
+        // keeps track of the block so it can be given back
+        let link = block.cast::<FreeNode>();
+        link.as_ptr().write(FreeNode { next: self.blocks });
+        self.blocks = Some(link);
+        for i in 1..PAGE_SIZE / size_of::<Children>() {
+            let table = block.as_ptr().add(i * size_of::<Children>());
+            self.give_children(NonNull::new_unchecked(table).cast());
+        }
+        for i in 1..BLOCK_SIZE / PAGE_SIZE {
+            self.push_node(NonNull::new_unchecked(block.as_ptr().add(i * PAGE_SIZE)));
+        }
+        Ok(())
+    }
+
+    /// Calls `return_block` with every block the nodes came from.  The page map can't be used
+    /// afterwards.
+    pub unsafe fn release(&mut self, mut return_block: impl FnMut(NonNull<u8>)) {
+        while let Some(block) = self.blocks {
+            self.blocks = block.as_ref().next;
+            return_block(block.cast());
+        }
+        self.free_nodes = None;
+        self.free_count = 0;
+        self.free_children = None;
+    }
+
+    unsafe fn push_node(&mut self, node: NonNull<u8>) {
+        let node = node.cast::<FreeNode>();
+        node.as_ptr().write(FreeNode {
+            next: self.free_nodes,
+        });
+        self.free_nodes = Some(node);
+        self.free_count += 1;
+    }
+
+    /// Takes a zeroed node off the free list
+    unsafe fn take_node(&mut self) -> NonNull<u8> {
+        let node = self
+            .free_nodes
+            .expect("The page map ran out of reserved nodes");
+        self.free_nodes = node.as_ref().next;
+        self.free_count -= 1;
+        let node = node.cast::<u8>();
+        node.as_ptr().write_bytes(0, PAGE_SIZE);
+        node
+    }
+
+    /// Takes an empty table of children, splitting up a node if there aren't any
+    unsafe fn take_children(&mut self) -> NonNull<Children> {
+        if self.free_children.is_none() {
+            let node = self.take_node();
+            for i in 0..PAGE_SIZE / size_of::<Children>() {
+                let table = node.as_ptr().add(i * size_of::<Children>());
+                self.give_children(NonNull::new_unchecked(table).cast());
+            }
+        }
+        let table = self.free_children.unwrap();
+        self.free_children = table.as_ref().next;
+        let table = table.cast::<u8>();
+        table.as_ptr().write_bytes(0, size_of::<Children>());
+        table.cast()
+    }
+
+    unsafe fn give_children(&mut self, table: NonNull<Children>) {
+        let table = table.cast::<FreeNode>();
+        table.as_ptr().write(FreeNode {
+            next: self.free_children,
+        });
+        self.free_children = Some(table);
+    }
+}
+
+/// The page map
+#[derive(Debug, Default)]
+pub struct PageMap {
+    root: AtomicPtr<u8>,
+}
+
+/// Returns the node that `slot` points to, adding a new one if there isn't one yet
+unsafe fn node_or_insert(slot: &AtomicPtr<u8>, nodes: &mut NodePool) -> NonNull<u8> {
+    match NonNull::new(slot.load(Ordering::Acquire)) {
+        Some(node) => node,
+        None => {
+            // The node is zeroed before anyone can see it
+            let node = nodes.take_node();
+            slot.store(node.as_ptr(), Ordering::Release);
+            node
+        }
+    }
+}
+
+impl PageMap {
+    /// Creates a new, empty `PageMap`
+    pub const fn new() -> Self {
+        PageMap {
+            root: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Returns the index into the node at the given level for the page
+    fn index(page: usize, level: u32) -> usize {
+        if level == 0 {
+            page & (LEAF_FANOUT - 1)
+        } else {
+            let shift = LEAF_BITS + (level - 1) * INTERIOR_BITS;
+            (page >> shift) & (INTERIOR_FANOUT - 1)
+        }
+    }
+
+    /// Returns the entry for the page containing `addr`, if there is one
+    pub fn get(&self, addr: usize) -> Option<&PageEntry> {
+        let page = addr / PAGE_SIZE;
+        let mut node = NonNull::new(self.root.load(Ordering::Acquire))?;
+        for level in (1..=INTERIOR_LEVELS).rev() {
+            let interior = unsafe { node.cast::<Interior>().as_ref() };
+            node = NonNull::new(interior[Self::index(page, level)].load(Ordering::Acquire))?;
+        }
+        let leaf = unsafe { node.cast::<Leaf>().as_ref() };
+        Some(&leaf[Self::index(page, 0)])
+    }
+
+    /// Calls `f` with every entry under `node`, which is at `level`, and its page number, going up
+    /// through the pages
+    unsafe fn each_entry(
+        node: NonNull<u8>,
+        level: u32,
+        first_page: usize,
+        f: &mut impl FnMut(usize, &PageEntry),
+    ) {
+        if level == 0 {
+            for (i, entry) in node.cast::<Leaf>().as_ref().iter().enumerate() {
+                f(first_page + i, entry);
+            }
+            return;
+        }
+        let shift = LEAF_BITS + (level - 1) * INTERIOR_BITS;
+        for (i, child) in node.cast::<Interior>().as_ref().iter().enumerate() {
+            if let Some(child) = NonNull::new(child.load(Ordering::Acquire)) {
+                Self::each_entry(child, level - 1, first_page + (i << shift), f);
+            }
+        }
+    }
+
+    /// Calls `f` with the start of every very large allocator's block.
+    ///
+    /// A block starts wherever a page's very large allocator isn't the same as the page before's.
+    /// The allocators are only compared, never read, so `f` can give the blocks back as it goes,
+    /// even the ones with the allocators in them.
+    pub unsafe fn each_block(&self, mut f: impl FnMut(NonNull<u8>)) {
+        let Some(root) = NonNull::new(self.root.load(Ordering::Acquire)) else {
+            return;
+        };
+        let mut previous = ptr::null_mut();
+        Self::each_entry(root, INTERIOR_LEVELS, 0, &mut |page, entry| {
+            let block = entry.block.load(Ordering::Acquire);
+            if !block.is_null() && block != previous {
+                f(NonNull::new_unchecked((page * PAGE_SIZE) as *mut u8));
+            }
+            previous = block;
+        });
+    }
+
+    /// Returns the entry for the page containing `addr`, adding nodes to the tree if needed
+    unsafe fn get_or_insert(&self, addr: usize, nodes: &mut NodePool) -> &PageEntry {
+        let page = addr / PAGE_SIZE;
+        let mut node = node_or_insert(&self.root, nodes);
+        for level in (1..=INTERIOR_LEVELS).rev() {
+            let interior = node.cast::<Interior>().as_ref();
+            node = node_or_insert(&interior[Self::index(page, level)], nodes);
+        }
+        &node.cast::<Leaf>().as_ref()[Self::index(page, 0)]
+    }
+
+    /// Records `alloc` as the owner of its stack, or removes it if `remove` is true.
+    ///
+    /// Registering never fails, as long as `reserve` was called beforehand.  Only one thread can
+    /// change the map at a time, since they all need the node pool, but others can still read it.
+    pub unsafe fn set(
+        &self,
+        nodes: &mut NodePool,
+        alloc: NonNull<SizedAllocator>,
+        owner: Owner,
+        remove: bool,
+    ) {
+        let bottom = alloc.as_ref().stack_pointer().as_ptr() as usize;
+        let size = alloc.as_ref().chunk_size() * STACK_SIZE;
+        let value = if remove {
+            ptr::null_mut()
+        } else {
+            alloc.as_ptr()
+        };
+
+        if owner == Owner::Child {
+            let slot = (bottom % PAGE_SIZE) / SLOT_SIZE;
+            let entry = self.get_or_insert(bottom, nodes);
+            let children = match entry.children() {
+                Some(children) => children,
+                None => {
+                    let children = nodes.take_children();
+                    entry.children.store(children.as_ptr(), Ordering::Release);
+                    children.as_ref()
+                }
+            };
+            children[slot].store(value, Ordering::Release);
+            return;
+        }
+
+        for page in (bottom..bottom + size).step_by(PAGE_SIZE) {
+            let entry = self.get_or_insert(page, nodes);
+            match owner {
+                Owner::Block => entry.block.store(value, Ordering::Release),
+                Owner::Medium => entry.stack.store(value, Ordering::Release),
+                Owner::Large if remove => entry.stack.store(value, Ordering::Release),
+                Owner::Large => entry
+                    .stack
+                    .store(value.map_addr(|addr| addr | LARGE_TAG), Ordering::Release),
+                Owner::Metadata => entry.metadata.store(value, Ordering::Release),
+                Owner::Child => unreachable!(),
+            }
+            if (owner == Owner::Medium || owner == Owner::Large) && remove {
+                // All the children are gone, since the stack they're in is empty
+                let children = entry.children.swap(ptr::null_mut(), Ordering::AcqRel);
+                if let Some(children) = NonNull::new(children) {
+                    debug_assert!(children
+                        .as_ref()
+                        .iter()
+                        .all(|child| child.load(Ordering::Relaxed).is_null()));
+                    nodes.give_children(children);
+                }
+            }
+        }
+    }
+
+    /// Returns the allocator registered as `owner` of the memory at `addr`.
+    ///
+    /// For children, `stack_size` is the size of the child stacks to look for.
+    pub fn find(
+        &self,
+        addr: usize,
+        owner: Owner,
+        stack_size: usize,
+    ) -> Option<NonNull<SizedAllocator>> {
+        let entry = self.get(addr)?;
+        match owner {
+            Owner::Block => entry.block(),
+            Owner::Medium => entry.medium(),
+            Owner::Large => entry.large(),
+            Owner::Metadata => entry.metadata(),
+            Owner::Child => {
+                let children = entry.children()?;
+                let slot = (addr % PAGE_SIZE) / stack_size * stack_size / SLOT_SIZE;
+                NonNull::new(children[slot].load(Ordering::Acquire))
+            }
+        }
+    }
+}
@@ -0,0 +1,189 @@
+//! Holding freed memory back for a while, with the `quarantine` feature, to catch use after free.
+//!
+//! Freed memory is filled with `POISON` and put at the back of a queue, instead of going straight
+//! back to its stack.  It stays allocated as far as the stacks know, so nothing else can be given
+//! the same memory, and a dangling pointer reads `POISON` rather than someone else's data.  Once
+//! there's more than `Allocator::set_quarantine_size` bytes in the queue, or it's run out of
+//! slots, the oldest memory comes out of the front, and really gets freed.  On the way out, it's
+//! checked that it's still all `POISON`, and if it isn't, something wrote to it after it was
+//! freed.
+
+use core::alloc::Layout;
+use core::ptr::{self, NonNull};
+
+/// How many bytes are held back unless `Allocator::set_quarantine_size` says otherwise
+pub(crate) const DEFAULT_SIZE: usize = 1 << 20;
+
+/// The most allocations that can be held back at once, however small they are
+const SLOTS: usize = 1024;
+
+/// How many bits of an address's hash pick its place in the index.  The index has twice as many
+/// places as there are slots, so it's never too full to search quickly.
+const INDEX_BITS: u32 = 11;
+const INDEX_MASK: usize = (1 << INDEX_BITS) - 1;
+
+/// What freed memory is filled with
+pub const POISON: u8 = 0xDE;
+
+/// A freed allocation waiting to really be freed
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    address: usize,
+    size: usize,
+    align: usize,
+}
+
+/// The queue of freed memory
+#[derive(Debug)]
+pub(crate) struct Quarantine {
+    start: usize,
+    len: usize,
+    /// How many bytes are in the queue
+    bytes: usize,
+    /// How many bytes it can hold
+    limit: usize,
+    entries: [Entry; SLOTS],
+    /// A hash table of the entries by address, so that looking one up doesn't mean going through
+    /// the whole queue.  Each place holds an entry's slot plus one, or 0 if it's empty, and is
+    /// probed linearly from where the entry's address hashes to.
+    index: [u16; 1 << INDEX_BITS],
+}
+
+impl Quarantine {
+    pub(crate) const fn new() -> Self {
+        Quarantine {
+            start: 0,
+            len: 0,
+            bytes: 0,
+            limit: DEFAULT_SIZE,
+            entries: [Entry {
+                address: 0,
+                size: 0,
+                align: 0,
+            }; SLOTS],
+            index: [0; 1 << INDEX_BITS],
+        }
+    }
+
+    /// Changes how many bytes can be held back.  Anything over the new limit comes out with the
+    /// next `pop_excess`.
+    pub(crate) fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+    }
+
+    /// Puts freed memory at the back of the queue.  It should be poisoned already.  If there wasn't
+    /// a slot for it, the oldest memory is taken out to make room, and returned.
+    pub(crate) fn push(
+        &mut self,
+        ptr: NonNull<u8>,
+        layout: Layout,
+    ) -> Option<(NonNull<u8>, Layout)> {
+        let oldest = if self.len == SLOTS { self.pop() } else { None };
+        let slot = (self.start + self.len) % SLOTS;
+        self.entries[slot] = Entry {
+            address: ptr.as_ptr() as usize,
+            size: layout.size(),
+            align: layout.align(),
+        };
+        self.index_insert(slot);
+        self.len += 1;
+        self.bytes += layout.size();
+        oldest
+    }
+
+    /// Takes the oldest memory out of the queue, if it's holding more than its limit
+    pub(crate) fn pop_excess(&mut self) -> Option<(NonNull<u8>, Layout)> {
+        if self.bytes > self.limit {
+            self.pop()
+        } else {
+            None
+        }
+    }
+
+    fn pop(&mut self) -> Option<(NonNull<u8>, Layout)> {
+        if self.len == 0 {
+            return None;
+        }
+        let entry = self.entries[self.start];
+        self.index_remove(self.start);
+        self.start = (self.start + 1) % SLOTS;
+        self.len -= 1;
+        self.bytes -= entry.size;
+        unsafe {
+            Some((
+                NonNull::new_unchecked(entry.address as *mut u8),
+                Layout::from_size_align_unchecked(entry.size, entry.align),
+            ))
+        }
+    }
+
+    /// Returns the size of the memory starting at `addr` that's waiting in the queue, if there is
+    /// any
+    pub(crate) fn find(&self, addr: usize) -> Option<usize> {
+        let mut place = home(addr);
+        while self.index[place] != 0 {
+            let entry = &self.entries[self.index[place] as usize - 1];
+            if entry.address == addr {
+                return Some(entry.size);
+            }
+            place = (place + 1) & INDEX_MASK;
+        }
+        None
+    }
+
+    fn index_insert(&mut self, slot: usize) {
+        let mut place = home(self.entries[slot].address);
+        while self.index[place] != 0 {
+            place = (place + 1) & INDEX_MASK;
+        }
+        self.index[place] = slot as u16 + 1;
+    }
+
+    fn index_remove(&mut self, slot: usize) {
+        let mut hole = home(self.entries[slot].address);
+        while self.index[hole] as usize != slot + 1 {
+            hole = (hole + 1) & INDEX_MASK;
+        }
+        // Anything after the hole that belongs at or before it moves back into it, so that the
+        // probes for it don't stop short
+        let mut place = hole;
+        loop {
+            place = (place + 1) & INDEX_MASK;
+            if self.index[place] == 0 {
+                break;
+            }
+            let address = self.entries[self.index[place] as usize - 1].address;
+            let from_home = place.wrapping_sub(home(address)) & INDEX_MASK;
+            if from_home >= place.wrapping_sub(hole) & INDEX_MASK {
+                self.index[hole] = self.index[place];
+                hole = place;
+            }
+        }
+        self.index[hole] = 0;
+    }
+}
+
+/// Where `address` goes in the index, if it's not taken
+fn home(address: usize) -> usize {
+    address.wrapping_mul(0x9E37_79B9_7F4A_7C15_u64 as usize) >> (usize::BITS - INDEX_BITS)
+}
+
+/// Fills freed memory with `POISON`
+pub(crate) unsafe fn poison(ptr: NonNull<u8>, size: usize) {
+    ptr::write_bytes(ptr.as_ptr(), POISON, size);
+}
+
+/// Checks that memory coming out of the queue is still all `POISON`, and aborts if it isn't
+pub(crate) unsafe fn assert_poisoned(ptr: NonNull<u8>, size: usize) {
+    let bytes = core::slice::from_raw_parts(ptr.as_ptr(), size);
+    if let Some(offset) = bytes.iter().position(|&byte| byte != POISON) {
+        fatal!(
+            "Use after free: something wrote to {:#x}, {} bytes into the allocation at {:#x} of {} \
+             bytes, after it was freed",
+            ptr.as_ptr() as usize + offset,
+            offset,
+            ptr.as_ptr() as usize,
+            size
+        );
+    }
+}
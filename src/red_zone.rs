@@ -0,0 +1,157 @@
+//! Canaries around every allocation, with the `red_zones` feature, to catch buffer overflows.
+//!
+//! Each allocation is padded at both ends.  The front red zone starts with a header, saying how
+//! big it is and how much was asked for, and the rest of it is filled with canary bytes.  The back
+//! red zone is at least `RED_ZONE` more canary bytes, plus whatever the allocation was rounded up
+//! by to fill its chunks:
+//!
+//! ```text
+//! | front | size | canaries... | what was asked for | canaries...           |
+//! ^ the real allocation        ^ what's handed out  ^ at least RED_ZONE bytes
+//! ```
+//!
+//! The canaries are checked when the memory is freed or reallocated, and by
+//! `Allocator::check_red_zones`, and any that have changed mean something wrote past the ends of
+//! the allocation.  Only the header is needed to find the canaries, so the checks work without a
+//! layout too.
+
+use core::alloc::Layout;
+use core::cmp;
+use core::fmt;
+use core::ptr::{self, NonNull};
+
+/// The fewest canary bytes on each side of an allocation
+pub(crate) const RED_ZONE: usize = 16;
+
+/// What the canary bytes are filled with
+const CANARY: u8 = 0xCB;
+
+/// The size of the header at the start of the front red zone
+const HEADER: usize = 2 * size_of::<usize>();
+
+/// Which end of an allocation was damaged
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Damage {
+    /// The canaries before it were overwritten
+    Underflow,
+    /// The canaries after it were overwritten
+    Overflow,
+    /// The header at the start of its front red zone was overwritten, so even its size is unknown
+    Header,
+}
+
+/// An allocation whose red zones were written over
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RedZoneError {
+    /// The address that was handed out for it, or where its red zone starts if the header's gone
+    pub address: usize,
+    /// The size it was allocated with, or the size of the whole padded allocation if the header's
+    /// gone
+    pub size: usize,
+    /// Which end was damaged
+    pub damage: Damage,
+}
+
+impl fmt::Display for RedZoneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let what = match self.damage {
+            Damage::Underflow => "something wrote before the start of",
+            Damage::Overflow => "something wrote past the end of",
+            Damage::Header => "the red zone header was overwritten for",
+        };
+        write!(
+            f,
+            "{} the allocation at {:#x} of {} bytes",
+            what, self.address, self.size
+        )
+    }
+}
+
+/// Returns the size of the front red zone for allocations with this alignment, which keeps what's
+/// handed out aligned
+pub(crate) fn front(align: usize) -> usize {
+    cmp::max(HEADER + RED_ZONE, align)
+}
+
+/// Returns every size the front red zone can be that's no bigger than `max`, smallest first
+pub(crate) fn fronts(max: usize) -> impl Iterator<Item = usize> {
+    let mut front = front(1);
+    core::iter::from_fn(move || {
+        if front > max {
+            return None;
+        }
+        front *= 2;
+        Some(front / 2)
+    })
+}
+
+/// Returns the layout of the padded allocation for memory with this layout
+pub(crate) fn padded(layout: Layout) -> Option<Layout> {
+    let size = front(layout.align())
+        .checked_add(layout.size())?
+        .checked_add(RED_ZONE)?;
+    Layout::from_size_align(size, layout.align()).ok()
+}
+
+/// Reads the header of a padded allocation: the size of its front red zone, and of what was asked
+/// for
+pub(crate) unsafe fn header(base: NonNull<u8>) -> (usize, usize) {
+    let words = base.as_ptr().cast::<usize>();
+    (words.read(), words.add(1).read())
+}
+
+/// Fills in the red zones of a new padded allocation `usable` bytes long, and returns what to hand
+/// out
+pub(crate) unsafe fn write(base: NonNull<u8>, usable: usize, layout: Layout) -> NonNull<u8> {
+    let front = front(layout.align());
+    let words = base.as_ptr().cast::<usize>();
+    words.write(front);
+    words.add(1).write(layout.size());
+    let user = base.as_ptr().add(front);
+    ptr::write_bytes(base.as_ptr().add(HEADER), CANARY, front - HEADER);
+    let end = front + layout.size();
+    ptr::write_bytes(user.add(layout.size()), CANARY, usable - end);
+    NonNull::new_unchecked(user)
+}
+
+/// Returns the address and size of what was handed out from a padded allocation `usable` bytes
+/// long, or `None` if its header's been written over
+pub(crate) unsafe fn handed_out(base: NonNull<u8>, usable: usize) -> Option<(usize, usize)> {
+    let (front, size) = header(base);
+    let end = front.checked_add(size)?.checked_add(RED_ZONE)?;
+    if !front.is_power_of_two() || front < HEADER + RED_ZONE || end > usable {
+        return None;
+    }
+    Some((base.as_ptr() as usize + front, size))
+}
+
+/// Checks the red zones of a padded allocation `usable` bytes long
+pub(crate) unsafe fn check(base: NonNull<u8>, usable: usize) -> Result<(), RedZoneError> {
+    let (address, size) = handed_out(base, usable).ok_or(RedZoneError {
+        address: base.as_ptr() as usize,
+        size: usable,
+        damage: Damage::Header,
+    })?;
+    let front = address - base.as_ptr() as usize;
+    let bytes = core::slice::from_raw_parts(base.as_ptr(), usable);
+    let damage = if bytes[HEADER..front].iter().any(|&byte| byte != CANARY) {
+        Damage::Underflow
+    } else if bytes[front + size..].iter().any(|&byte| byte != CANARY) {
+        Damage::Overflow
+    } else {
+        return Ok(());
+    };
+    Err(RedZoneError {
+        address,
+        size,
+        damage,
+    })
+}
+
+/// Checks the red zones of a padded allocation before it's freed, and aborts if they've been
+/// written over
+pub(crate) unsafe fn assert_intact(base: NonNull<u8>, usable: usize) {
+    if let Err(error) = check(base, usable) {
+        fatal!("Red zone damaged: {}", error);
+    }
+}
@@ -0,0 +1,138 @@
+//! A memory source over a list of separate regions of memory, like the memory map a bootloader
+//! hands over.
+//!
+//! Each region gets its own `StaticRegionSource`, and blocks are taken from the first region that
+//! still has any.
+
+use core::alloc::Layout;
+use core::cell::UnsafeCell;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use memory_source::MemorySource;
+use static_region_source::StaticRegionSource;
+
+/// Hands out blocks from up to `N` regions of memory, which are added one at a time.  Like
+/// `StaticRegionSource`, it needs no libc.
+///
+/// ```no_run
+/// extern crate stack_alloc;
+/// use stack_alloc::{Allocator, RegionListSource};
+///
+/// #[global_allocator]
+/// static GLOBAL: Allocator<RegionListSource<32>> = Allocator::new(RegionListSource::new());
+///
+/// fn main() {
+///     # let memory_map: [(usize, usize); 0] = [];
+///     for &(start, len) in &memory_map {
+///         unsafe { GLOBAL.source().add_region(start as *mut u8, len) };
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct RegionListSource<const N: usize> {
+    regions: [UnsafeCell<StaticRegionSource>; N],
+    /// How many of `regions` have been filled in
+    count: AtomicUsize,
+    /// The bytes in regions too small to hold a single block, which weren't given a slot
+    skipped: AtomicUsize,
+}
+
+unsafe impl<const N: usize> Send for RegionListSource<N> {}
+unsafe impl<const N: usize> Sync for RegionListSource<N> {}
+
+impl<const N: usize> RegionListSource<N> {
+    /// Creates a source with no regions yet
+    pub const fn new() -> Self {
+        RegionListSource {
+            regions: [const {
+                UnsafeCell::new(unsafe { StaticRegionSource::from_raw_parts(ptr::null_mut(), 0) })
+            }; N],
+            count: AtomicUsize::new(0),
+            skipped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Adds the `len` bytes at `start` to the memory to hand out.  The ends that aren't aligned to
+    /// `BLOCK_ALIGN`, or don't make up a whole block, are skipped.  Returns `false`, and doesn't
+    /// add it, if there are already `N` regions.
+    ///
+    /// # Safety
+    ///
+    /// The memory must be valid to read and write, and not used by anything else, for as long as
+    /// the source and the allocations from it are around.  Only one thread can be adding a region
+    /// at a time.
+    pub unsafe fn add_region(&self, start: *mut u8, len: usize) -> bool {
+        let region = StaticRegionSource::from_raw_parts(start, len);
+        if region.capacity() == 0 {
+            debug_log!(
+                "RegionListSource: no whole blocks in %zu bytes at %#zx\n\0",
+                len,
+                start as usize
+            );
+            self.skipped.fetch_add(len, Ordering::Relaxed);
+            return true;
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        if count == N {
+            return false;
+        }
+        *self.regions[count].get() = region;
+        self.count.store(count + 1, Ordering::Release);
+        true
+    }
+
+    fn regions(&self) -> impl Iterator<Item = &StaticRegionSource> {
+        let count = self.count.load(Ordering::Acquire);
+        self.regions[..count]
+            .iter()
+            .map(|region| unsafe { &*region.get() })
+    }
+
+    fn region_of(&self, ptr: NonNull<u8>) -> Option<&StaticRegionSource> {
+        self.regions().find(|region| region.contains(ptr.as_ptr()))
+    }
+
+    /// Returns how many blocks there are room for in all the regions together
+    pub fn capacity(&self) -> usize {
+        self.regions().map(StaticRegionSource::capacity).sum()
+    }
+
+    /// Returns how many bytes of the regions aren't in any block, because they hold the links for
+    /// the lists of blocks, or they weren't aligned or didn't make up a whole block
+    pub fn wasted(&self) -> usize {
+        self.skipped.load(Ordering::Relaxed)
+            + self
+                .regions()
+                .map(StaticRegionSource::wasted)
+                .sum::<usize>()
+    }
+}
+
+impl<const N: usize> Default for RegionListSource<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl<const N: usize> MemorySource for RegionListSource<N> {
+    unsafe fn get_block(&self) -> Option<NonNull<u8>> {
+        self.regions().find_map(|region| region.get_block())
+    }
+
+    unsafe fn return_block(&self, block: NonNull<u8>) {
+        if let Some(region) = self.region_of(block) {
+            region.return_block(block);
+        }
+    }
+
+    unsafe fn get_huge(&self, layout: Layout) -> Option<NonNull<u8>> {
+        self.regions().find_map(|region| region.get_huge(layout))
+    }
+
+    unsafe fn return_huge(&self, ptr: NonNull<u8>, layout: Layout) {
+        if let Some(region) = self.region_of(ptr) {
+            region.return_huge(ptr, layout);
+        }
+    }
+}
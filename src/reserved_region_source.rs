@@ -0,0 +1,307 @@
+//! A memory source that reserves one big range of address space up front, and commits blocks in it
+//! as they're needed.
+//!
+//! The whole range is mapped `PROT_NONE` with `MAP_NORESERVE` the first time a block is asked for,
+//! which costs nothing but address space.  Blocks are handed out from the bottom up, and made
+//! readable and writable with `mprotect` one at a time.  When a block comes back, its memory is
+//! dropped with `madvise(MADV_DONTNEED)` and it's made `PROT_NONE` again, and its bit is set in a
+//! bitmap of blocks to hand out again, which lives in the first blocks of the range.  Huge
+//! allocations look in the bitmap for enough bits set in a row before taking fresh blocks.
+//!
+//! Since all the blocks are in one range, whether a pointer came from the source is just a range
+//! check, with `ReservedRegionSource::contains`.
+
+use core::alloc::Layout;
+use core::cmp;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use memory_source::{MemorySource, BLOCK_SIZE};
+
+/// How much address space is reserved unless it's given
+pub const DEFAULT_RESERVATION: usize = 64 << 30;
+
+/// Reserves a range of address space, and commits blocks in it on demand.  Only on Linux, with the
+/// `mmap_source` feature.
+///
+/// ```no_run
+/// extern crate stack_alloc;
+/// use stack_alloc::{Allocator, ReservedRegionSource, DEFAULT_RESERVATION};
+///
+/// #[global_allocator]
+/// static GLOBAL: Allocator<ReservedRegionSource> =
+///     Allocator::new(ReservedRegionSource::new(DEFAULT_RESERVATION));
+/// ```
+#[derive(Debug)]
+pub struct ReservedRegionSource {
+    /// How many bytes to reserve
+    size: usize,
+    /// The start of the range, or 0 if it hasn't been reserved yet
+    base: AtomicUsize,
+    /// The first block that's never been handed out
+    next: AtomicUsize,
+    /// How many blocks have their bits set in the bitmap
+    returned: AtomicUsize,
+}
+
+impl ReservedRegionSource {
+    /// Creates a source that'll reserve `size` bytes, rounded down to a whole number of blocks.
+    /// Nothing is reserved until the first block is asked for.
+    pub const fn new(size: usize) -> Self {
+        ReservedRegionSource {
+            size: size / BLOCK_SIZE * BLOCK_SIZE,
+            base: AtomicUsize::new(0),
+            next: AtomicUsize::new(0),
+            returned: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns whether `ptr` is in the reserved range, so that it might have come from this
+    /// source.  Nothing is, before the first block's been handed out.
+    pub fn contains(&self, ptr: *const u8) -> bool {
+        let base = self.base.load(Ordering::Acquire);
+        base != 0 && base <= ptr as usize && (ptr as usize) < base + self.size
+    }
+
+    fn blocks(&self) -> usize {
+        self.size / BLOCK_SIZE
+    }
+
+    /// The number of blocks at the start of the range that the bitmap takes up
+    fn bitmap_blocks(&self) -> usize {
+        (self.blocks().div_ceil(64) * 8).div_ceil(BLOCK_SIZE)
+    }
+
+    fn bitmap(&self, base: usize) -> &[AtomicU64] {
+        unsafe { core::slice::from_raw_parts(base as *const AtomicU64, self.blocks().div_ceil(64)) }
+    }
+
+    /// Returns the start of the range, reserving it if that hasn't been done yet
+    fn base(&self) -> Option<usize> {
+        let base = self.base.load(Ordering::Acquire);
+        if base != 0 {
+            return Some(base);
+        }
+        if self.bitmap_blocks() >= self.blocks() {
+            return None;
+        }
+        // It's mapped with an extra block, so that the blocks can be aligned to their size
+        let mapped_size = self.size.checked_add(BLOCK_SIZE)?;
+        let start = unsafe {
+            ::libc::mmap(
+                ptr::null_mut(),
+                mapped_size,
+                ::libc::PROT_NONE,
+                ::libc::MAP_PRIVATE | ::libc::MAP_ANONYMOUS | ::libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        if start == ::libc::MAP_FAILED {
+            debug_log!(
+                "ReservedRegionSource: couldn't reserve %zu bytes\n\0",
+                mapped_size
+            );
+            return None;
+        }
+        let start = start as usize;
+        let aligned = start.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+        unsafe {
+            if aligned > start {
+                ::libc::munmap(start as *mut ::libc::c_void, aligned - start);
+            }
+            let end = aligned + self.size;
+            if start + mapped_size > end {
+                ::libc::munmap(end as *mut ::libc::c_void, start + mapped_size - end);
+            }
+            commit(aligned, self.bitmap_blocks() * BLOCK_SIZE);
+        }
+
+        match self
+            .base
+            .compare_exchange(0, aligned, Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(_) => {
+                debug_log!(
+                    "ReservedRegionSource: reserved %zu bytes at %#zx\n\0",
+                    self.size,
+                    aligned
+                );
+                Some(aligned)
+            }
+            Err(base) => {
+                // Another thread got there first
+                unsafe { ::libc::munmap(aligned as *mut ::libc::c_void, self.size) };
+                Some(base)
+            }
+        }
+    }
+
+    /// Takes a block that was given back, if there are any
+    fn reuse(&self, base: usize) -> Option<usize> {
+        if self.returned.load(Ordering::Acquire) == 0 {
+            return None;
+        }
+        for (i, word) in self.bitmap(base).iter().enumerate() {
+            let mut bits = word.load(Ordering::Relaxed);
+            while bits != 0 {
+                let bit = bits.trailing_zeros() as usize;
+                let old = word.fetch_and(!(1 << bit), Ordering::Acquire);
+                if old & (1 << bit) != 0 {
+                    self.returned.fetch_sub(1, Ordering::Relaxed);
+                    return Some(i * 64 + bit);
+                }
+                bits = old & !(1 << bit);
+            }
+        }
+        None
+    }
+
+    /// Takes `count` blocks in a row that were given back, starting at a multiple of `align`
+    /// blocks, if there's a run that long
+    fn reuse_run(&self, base: usize, count: usize, align: usize) -> Option<usize> {
+        if self.returned.load(Ordering::Acquire) < count {
+            return None;
+        }
+        let bitmap = self.bitmap(base);
+        let is_set =
+            |block: usize| bitmap[block / 64].load(Ordering::Relaxed) & (1 << (block % 64)) != 0;
+        let mut start = self.bitmap_blocks();
+        loop {
+            start = (base / BLOCK_SIZE + start).div_ceil(align) * align - base / BLOCK_SIZE;
+            if start.checked_add(count)? > self.blocks() {
+                return None;
+            }
+            // Everything before the first gap is set, so the next run can only start after it
+            if let Some(gap) = (start..start + count).find(|&block| !is_set(block)) {
+                start = gap + 1;
+                continue;
+            }
+            match (start..start + count).find(|&block| !self.claim(base, block)) {
+                None => return Some(start),
+                Some(taken) => {
+                    // Someone else got one of them first
+                    for block in start..taken {
+                        self.put_back(base, block);
+                    }
+                    start = taken + 1;
+                }
+            }
+        }
+    }
+
+    /// Clears a block's bit, if it's still set
+    fn claim(&self, base: usize, block: usize) -> bool {
+        let bit = 1 << (block % 64);
+        let old = self.bitmap(base)[block / 64].fetch_and(!bit, Ordering::Acquire);
+        if old & bit == 0 {
+            return false;
+        }
+        self.returned.fetch_sub(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Takes `count` blocks that have never been handed out, starting at a multiple of `align`
+    /// blocks.  The blocks skipped to get there are put in the bitmap.
+    fn take_fresh(&self, base: usize, count: usize, align: usize) -> Option<usize> {
+        let mut next = self.next.load(Ordering::Relaxed);
+        loop {
+            // `next` starts at 0, but the bitmap's blocks are never handed out
+            let from = cmp::max(next, self.bitmap_blocks());
+            let start = (base / BLOCK_SIZE + from).div_ceil(align) * align - base / BLOCK_SIZE;
+            let end = start.checked_add(count)?;
+            if end > self.blocks() {
+                return None;
+            }
+            match self
+                .next
+                .compare_exchange_weak(next, end, Ordering::AcqRel, Ordering::Relaxed)
+            {
+                Ok(_) => {
+                    for skipped in from..start {
+                        self.put_back(base, skipped);
+                    }
+                    return Some(start);
+                }
+                Err(actual) => next = actual,
+            }
+        }
+    }
+
+    /// Marks a decommitted block as free to hand out again
+    fn put_back(&self, base: usize, block: usize) {
+        self.bitmap(base)[block / 64].fetch_or(1 << (block % 64), Ordering::Release);
+        self.returned.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// Makes memory in the range readable and writable
+unsafe fn commit(addr: usize, size: usize) -> bool {
+    ::libc::mprotect(
+        addr as *mut ::libc::c_void,
+        size,
+        ::libc::PROT_READ | ::libc::PROT_WRITE,
+    ) == 0
+}
+
+/// Drops the memory behind part of the range, and makes it inaccessible again
+unsafe fn decommit(addr: usize, size: usize) {
+    ::libc::madvise(addr as *mut ::libc::c_void, size, ::libc::MADV_DONTNEED);
+    ::libc::mprotect(addr as *mut ::libc::c_void, size, ::libc::PROT_NONE);
+}
+
+unsafe impl MemorySource for ReservedRegionSource {
+    unsafe fn get_block(&self) -> Option<NonNull<u8>> {
+        let base = self.base()?;
+        let block = match self.reuse(base) {
+            Some(block) => block,
+            None => self.take_fresh(base, 1, 1)?,
+        };
+        let addr = base + block * BLOCK_SIZE;
+        debug_log!("ReservedRegionSource: committing block %#zx\n\0", addr);
+        if !commit(addr, BLOCK_SIZE) {
+            self.put_back(base, block);
+            return None;
+        }
+        NonNull::new(addr as *mut u8)
+    }
+
+    unsafe fn return_block(&self, block: NonNull<u8>) {
+        let base = self.base.load(Ordering::Acquire);
+        let addr = block.as_ptr() as usize;
+        debug_log!("ReservedRegionSource: decommitting block %#zx\n\0", addr);
+        decommit(addr, BLOCK_SIZE);
+        self.put_back(base, (addr - base) / BLOCK_SIZE);
+    }
+
+    /// Huge allocations get a run of blocks that were given back if there's one, or else fresh
+    /// ones, so they're in the range too
+    unsafe fn get_huge(&self, layout: Layout) -> Option<NonNull<u8>> {
+        let base = self.base()?;
+        let count = layout.size().div_ceil(BLOCK_SIZE);
+        let align = cmp::max(layout.align() / BLOCK_SIZE, 1);
+        let start = match self.reuse_run(base, count, align) {
+            Some(start) => start,
+            None => self.take_fresh(base, count, align)?,
+        };
+        let addr = base + start * BLOCK_SIZE;
+        if !commit(addr, count * BLOCK_SIZE) {
+            for block in start..start + count {
+                self.put_back(base, block);
+            }
+            return None;
+        }
+        NonNull::new(addr as *mut u8)
+    }
+
+    unsafe fn return_huge(&self, ptr: NonNull<u8>, layout: Layout) {
+        let base = self.base.load(Ordering::Acquire);
+        let addr = ptr.as_ptr() as usize;
+        let count = layout.size().div_ceil(BLOCK_SIZE);
+        decommit(addr, count * BLOCK_SIZE);
+        let start = (addr - base) / BLOCK_SIZE;
+        for block in start..start + count {
+            self.put_back(base, block);
+        }
+    }
+}
@@ -1,37 +1,52 @@
 //! This module implements the method for managing linked lists of stacks of a consistent size.
 
 use core::alloc::{self, Layout};
-use core::cmp;
 use core::ptr::NonNull;
 
-use bitmapped_stack::BitmappedStack;
+use bitmapped_stack::{BitmappedStack, STACK_SIZE};
+use check::Problem;
 use metadata_box::MetadataBox;
 
+/// The number of free space bins: one for each power of 2 up to `STACK_SIZE`
+const BINS: usize = STACK_SIZE.trailing_zeros() as usize + 1;
+
+/// The most allocators an allocation tries in each bin.  Only alignment can stop the first one in a
+/// bin that's big enough from fitting it, and then the rest are likely no better, so it's cheaper
+/// to start a new stack than to go through all of them.
+const MAX_PROBES: usize = 4;
+
+/// Returns the bin for stacks with a run of `chunks` free chunks.  `chunks` can't be zero.
+fn bin_for(chunks: usize) -> usize {
+    debug_assert_ne!(chunks, 0);
+    (usize::BITS - 1 - chunks.leading_zeros()) as usize
+}
+
 /// The recommended action after deallocating
 pub enum DeallocResponse {
     /// Do nothing; everything's good
     Nothing,
 
-    /// Remove this allocator and free its stack because it's empty
-    ///
-    /// It should be replaced by its backup allocator, if any
+    /// The allocator's stack is empty, so it can be taken out of its chain and freed
     Collapse,
-
-    /// The given allocator should be freed; both the metadata and its stack.
-    ///
-    /// This happens when another allocator down the line was collapsed and its memory needs to be
-    /// freed.
-    FreeAllocator(MetadataBox<SizedAllocator>),
 }
 
 /// A `SizedAllocator` is a linked list of stacks whose chunk size is the same.
+///
+/// The list is doubly linked, so that an allocator found through the page map can be taken out of
+/// the middle of its chain without walking it.
 #[derive(Debug)]
 pub struct SizedAllocator {
     primary: BitmappedStack,
     /// The backup allocator should have the same size chunk as the primary allocator
     backup: Option<MetadataBox<SizedAllocator>>,
-    /// The largest contiguous group of memory left in both the primary and backup
-    largest_space_left: usize,
+    /// The allocator whose backup this is, or `None` if this is the head of the chain
+    prev: Option<NonNull<SizedAllocator>>,
+    /// The free space bin this allocator is in, if it has any room
+    bin: Option<usize>,
+    /// The next allocator in the same bin
+    next_free: Option<NonNull<SizedAllocator>>,
+    /// The previous allocator in the same bin
+    prev_free: Option<NonNull<SizedAllocator>>,
 }
 
 impl SizedAllocator {
@@ -43,15 +58,22 @@ impl SizedAllocator {
     ///  * The chunk size is a power of 2
     ///  * The memory is a valid pointer with alignment `chunk_size` and size `STACK_SIZE *
     ///  chunk_size`
-    pub unsafe fn from_memory_chunk(
-        chunk_size: usize,
-        memory: NonNull<u8>,
-        backup: Option<MetadataBox<SizedAllocator>>,
-    ) -> Self {
+    pub unsafe fn from_memory_chunk(chunk_size: usize, memory: NonNull<u8>) -> Self {
         SizedAllocator {
             primary: BitmappedStack::new(memory, chunk_size),
-            backup: backup,
-            largest_space_left: 64,
+            backup: None,
+            prev: None,
+            bin: None,
+            next_free: None,
+            prev_free: None,
+        }
+    }
+
+    /// Points the backup allocator back at this one
+    fn link_backup(&mut self) {
+        let this = NonNull::from(&mut *self);
+        if let Some(backup) = &mut self.backup {
+            backup.prev = Some(this);
         }
     }
 
@@ -59,9 +81,9 @@ impl SizedAllocator {
     pub fn chunk_size(&self) -> usize {
         let size = self.primary.chunk_size();
 
-        #[cfg(debug_asserts)]
+        #[cfg(debug_assertions)]
         {
-            if let Some(backup) = self.backup {
+            if let Some(backup) = &self.backup {
                 debug_assert_eq!(size, backup.chunk_size());
             }
         }
@@ -74,136 +96,350 @@ impl SizedAllocator {
         self.primary.pointer()
     }
 
-    /// Returns `true` if it owns the memory
+    /// Returns this allocator's own stack
+    pub fn stack(&self) -> &BitmappedStack {
+        &self.primary
+    }
+
+    /// Returns `true` if this allocator's own stack owns the memory.  The backups aren't checked.
     pub fn owns(&self, ptr: NonNull<u8>) -> bool {
+        self.primary.owns(ptr.as_ptr())
+    }
+
+    /// Returns the number of chunks of this allocator's own stack that are allocated
+    #[cfg(feature = "stats")]
+    pub fn used_chunks(&self) -> usize {
+        self.primary.used_chunks()
+    }
+
+    /// Returns `true` if this is the first allocator in its chain
+    pub fn is_head(&self) -> bool {
+        self.prev.is_none()
+    }
+
+    /// Returns the number of chunks in the allocation that starts at `ptr`, or `None` if no
+    /// allocation starts there.  Only this allocator's own stack is checked.
+    pub fn allocation_chunks(&self, ptr: NonNull<u8>) -> Option<usize> {
         if self.primary.owns(ptr.as_ptr()) {
-            true
-        } else if let Some(backup) = &self.backup {
-            backup.owns(ptr)
+            self.primary.allocation_chunks(ptr.as_ptr())
         } else {
-            false
+            None
         }
     }
 
-    fn set_largest_space_left(&mut self) {
-        let backup_space_left = match &self.backup {
-            Some(backup) => backup.largest_space_left,
-            None => 0,
-        };
-        self.largest_space_left = cmp::max(self.primary.chunks_left(), backup_space_left);
+    /// Returns an iterator over the allocations in this allocator's own stack, as their start and
+    /// length in chunks
+    pub fn allocations(&self) -> impl Iterator<Item = (NonNull<u8>, usize)> + '_ {
+        self.primary.allocations()
     }
 
-    pub unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, alloc::AllocErr> {
+    /// Allocates from this allocator's own stack.
+    ///
+    /// Once the allocator is in a `Chain`, this shouldn't be used anymore, since the chain has to
+    /// keep track of how much room it has.
+    pub unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, alloc::AllocError> {
         debug_log!(
             "SizedAllocator: allocing size %zu, align %zu\n\0",
             layout.size(),
             layout.align()
         );
-        if layout.size() > self.chunk_size() * self.largest_space_left {
-            debug_log!("  (short-circuiting the list because it's too big)\n\0");
-            return Err(alloc::AllocErr);
-        }
-        if let memory @ Ok(_) = self.primary.alloc(layout) {
-            self.set_largest_space_left();
-            memory
-        } else {
-            let backup = self.backup.as_mut().ok_or(alloc::AllocErr)?;
-            let res = backup.alloc(layout);
-            self.set_largest_space_left();
-            res
-        }
+        self.primary.alloc(layout)
     }
 
-    pub unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) -> DeallocResponse {
+    unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) -> DeallocResponse {
         debug_log!(
             "SizedAllocator: deallocing size %zu, align %zu\n\0",
             layout.size(),
             layout.align()
         );
-        if self.primary.owns(ptr.as_ptr()) {
-            debug_log!("    (Primary owns it)\n\0");
-            self.primary.dealloc(ptr, layout);
-            self.set_largest_space_left();
-            if self.primary.is_empty() {
-                DeallocResponse::Collapse
-            } else {
-                DeallocResponse::Nothing
-            }
-        } else if let Some(mut backup) = self.backup.take() {
-            debug_log!("    (Primary does not own it)\n\0");
-            match backup.dealloc(ptr, layout) {
-                DeallocResponse::Collapse => {
-                    backup.primary.debug_assert_empty();
-                    self.backup = backup.backup.take();
-                    self.set_largest_space_left();
-                    DeallocResponse::FreeAllocator(backup)
-                }
-                x => {
-                    self.backup = Some(backup);
-                    self.set_largest_space_left();
-                    x
-                }
-            }
+        debug_assert!(self.owns(ptr));
+        self.primary.dealloc(ptr, layout);
+        if self.primary.is_empty() {
+            DeallocResponse::Collapse
         } else {
-            debug_log!("    (Primary does not own it, and there is no backup)\n\0");
-            unreachable!("If the primary doesn't own the memory to dealloc, there must be a backup")
+            DeallocResponse::Nothing
         }
     }
 
-    pub unsafe fn shrink_in_place(&mut self, ptr: NonNull<u8>, layout: Layout, new_size: usize) {
+    unsafe fn shrink_in_place(&mut self, ptr: NonNull<u8>, layout: Layout, new_size: usize) {
         debug_log!(
             "SizedAllocator: attempting to shrink size %zu align %zu pointer %#zx\n\0",
             layout.size(),
             layout.align(),
             ptr.as_ptr()
         );
-        if self.primary.owns(ptr.as_ptr()) {
-            debug_log!("    (Primary owns it)\n\0");
-            self.primary.shrink_in_place(ptr, layout, new_size);
-            self.set_largest_space_left();
-        } else if let Some(ref mut backup) = self.backup {
-            debug_log!("    (Primary does not own it)\n\0");
-            backup.shrink_in_place(ptr, layout, new_size);
-            self.set_largest_space_left();
-        } else {
-            debug_log!("    (Primary does not own it, and there is no backup)\n\0");
-            unreachable!("If the primary doesn't own the memory to shrink, there must be a backup")
-        }
+        debug_assert!(self.owns(ptr));
+        self.primary.shrink_in_place(ptr, layout, new_size);
     }
 
-    pub unsafe fn grow_in_place(
+    unsafe fn grow_in_place(
         &mut self,
         ptr: NonNull<u8>,
         layout: Layout,
         new_size: usize,
-    ) -> Result<(), alloc::CannotReallocInPlace> {
+    ) -> Result<(), alloc::AllocError> {
         debug_log!(
             "SizedAllocator: attempting to grow size %zu align %zu pointer %#zx\n\0",
             layout.size(),
             layout.align(),
             ptr.as_ptr()
         );
-        if self.primary.owns(ptr.as_ptr()) {
-            debug_log!("    (Primary owns it)\n\0");
-            match self.primary.grow_in_place(ptr, layout, new_size) {
-                Ok(()) => {
-                    self.set_largest_space_left();
-                    Ok(())
-                }
-                err => err,
+        debug_assert!(self.owns(ptr));
+        self.primary.grow_in_place(ptr, layout, new_size)
+    }
+}
+
+/// A chain of `SizedAllocator`s, along with an index of which ones have room left.
+///
+/// The allocators with room are kept in bins by how much room they have: bin `i` has the ones
+/// whose longest run of free chunks is between `2^i` and `2^(i + 1) - 1` chunks long.  Allocating
+/// only looks at the bins that can fit the allocation, and only at the first few allocators in
+/// each, so it doesn't matter how long the chain is.
+#[derive(Debug, Default)]
+pub struct Chain {
+    head: Option<MetadataBox<SizedAllocator>>,
+    bins: [Option<NonNull<SizedAllocator>>; BINS],
+    /// How many times an allocator was tried and couldn't fit the allocation
+    #[cfg(feature = "stats")]
+    missed_probes: usize,
+}
+
+impl Chain {
+    /// Creates a new, empty `Chain`
+    pub const fn new() -> Self {
+        Chain {
+            head: None,
+            bins: [None; BINS],
+            #[cfg(feature = "stats")]
+            missed_probes: 0,
+        }
+    }
+
+    /// Returns the first allocator in the chain
+    pub fn head(&self) -> Option<&SizedAllocator> {
+        self.head.as_deref()
+    }
+
+    /// Returns how many times an allocator in the chain was tried and couldn't fit the allocation
+    #[cfg(feature = "stats")]
+    pub fn missed_probes(&self) -> usize {
+        self.missed_probes
+    }
+
+    /// Returns an iterator over the allocators in the chain, starting at the head
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = &'a SizedAllocator> + 'a {
+        let mut next = self.head();
+        core::iter::from_fn(move || {
+            let alloc = next?;
+            next = alloc.backup.as_deref();
+            Some(alloc)
+        })
+    }
+
+    /// Checks every allocator in the chain: its stack, its links to its neighbours, and its free
+    /// space bin.  Returns the first broken allocator and what's wrong with it.
+    pub fn check(&self) -> Result<(), (&SizedAllocator, Problem)> {
+        let chunk_size = match self.head() {
+            Some(head) => head.primary.chunk_size(),
+            None => return Ok(()),
+        };
+        let mut prev = None;
+        let mut binned = 0;
+        for alloc in self.iter() {
+            if alloc.prev != prev {
+                return Err((alloc, Problem::BrokenChain));
+            }
+            alloc.primary.check().map_err(|problem| (alloc, problem))?;
+            if alloc.primary.chunk_size() != chunk_size {
+                let problem = Problem::WrongChunkSize {
+                    chunk_size: alloc.primary.chunk_size(),
+                    expected: chunk_size,
+                };
+                return Err((alloc, problem));
+            }
+            let free_run = alloc.primary.largest_free_run();
+            let expected = if free_run == 0 {
+                None
+            } else {
+                Some(bin_for(free_run))
+            };
+            if alloc.bin != expected {
+                let problem = Problem::WrongBin {
+                    bin: alloc.bin,
+                    expected,
+                };
+                return Err((alloc, problem));
             }
-        } else if let Some(ref mut backup) = self.backup {
-            debug_log!("    (Primary does not own it)\n\0");
-            match backup.grow_in_place(ptr, layout, new_size) {
-                Ok(()) => {
-                    self.set_largest_space_left();
-                    Ok(())
+            binned += usize::from(alloc.bin.is_some());
+            prev = Some(NonNull::from(alloc));
+        }
+
+        // Every allocator with room has to be on its bin's list, and nothing else can be
+        let mut listed = 0;
+        for (bin, list) in self.bins.iter().enumerate() {
+            let mut prev_free = None;
+            let mut next = *list;
+            while let Some(alloc) = next {
+                let alloc = unsafe { alloc.as_ref() };
+                listed += 1;
+                if alloc.bin != Some(bin) || alloc.prev_free != prev_free || listed > binned {
+                    return Err((alloc, Problem::BrokenBin));
                 }
-                err => err,
+                prev_free = Some(NonNull::from(alloc));
+                next = alloc.next_free;
             }
+        }
+        if listed != binned {
+            return Err((self.head().unwrap(), Problem::BrokenBin));
+        }
+        Ok(())
+    }
+
+    /// Adds a new allocator to the head of the chain
+    pub fn push(&mut self, mut alloc: MetadataBox<SizedAllocator>) {
+        debug_assert!(alloc.backup.is_none());
+        alloc.backup = self.head.take();
+        alloc.link_backup();
+        let alloc_ptr = NonNull::from(&mut *alloc);
+        self.head = Some(alloc);
+        unsafe { self.rebin(alloc_ptr) };
+    }
+
+    /// Takes the allocator out of its bin, if it's in one
+    unsafe fn remove_from_bin(&mut self, mut alloc: NonNull<SizedAllocator>) {
+        let alloc = alloc.as_mut();
+        if let Some(bin) = alloc.bin.take() {
+            match alloc.prev_free {
+                Some(mut prev) => prev.as_mut().next_free = alloc.next_free,
+                None => self.bins[bin] = alloc.next_free,
+            }
+            if let Some(mut next) = alloc.next_free {
+                next.as_mut().prev_free = alloc.prev_free;
+            }
+            alloc.next_free = None;
+            alloc.prev_free = None;
+        }
+    }
+
+    /// Moves the allocator to the right bin for how much room it has now
+    unsafe fn rebin(&mut self, mut alloc_ptr: NonNull<SizedAllocator>) {
+        let free_run = alloc_ptr.as_ref().primary.largest_free_run();
+        let new_bin = if free_run == 0 {
+            None
         } else {
-            debug_log!("    (Primary does not own it, and there is no backup)\n\0");
-            unreachable!("If the primary doesn't own the memory to grow, there must be a backup")
+            Some(bin_for(free_run))
+        };
+        if alloc_ptr.as_ref().bin == new_bin {
+            return;
+        }
+        self.remove_from_bin(alloc_ptr);
+        if let Some(bin) = new_bin {
+            let alloc = alloc_ptr.as_mut();
+            alloc.bin = Some(bin);
+            alloc.next_free = self.bins[bin];
+            if let Some(mut next) = alloc.next_free {
+                next.as_mut().prev_free = Some(alloc_ptr);
+            }
+            self.bins[bin] = Some(alloc_ptr);
+        }
+    }
+
+    /// Allocates from any allocator in the chain that has room
+    pub unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, alloc::AllocError> {
+        let chunk_size = self.head().ok_or(alloc::AllocError)?.chunk_size();
+        let chunks = layout.size().div_ceil(chunk_size);
+        if chunks == 0 || chunks > STACK_SIZE {
+            return Err(alloc::AllocError);
+        }
+
+        // Everything from `fits` up has enough room, so unless the alignment gets in the way, the
+        // first allocator tried works.  Only some of the ones in the bin below are big enough.
+        let fits = bin_for(chunks.next_power_of_two());
+        let might_fit = bin_for(chunks);
+        for bin in (fits..BINS).chain(might_fit..fits) {
+            let mut next = self.bins[bin];
+            for _ in 0..MAX_PROBES {
+                let mut alloc = match next {
+                    Some(alloc) => alloc,
+                    None => break,
+                };
+                if let Ok(memory) = alloc.as_mut().alloc(layout) {
+                    self.rebin(alloc);
+                    return Ok(memory);
+                }
+                #[cfg(feature = "stats")]
+                {
+                    self.missed_probes += 1;
+                }
+                next = alloc.as_ref().next_free;
+            }
+        }
+        debug_log!("Chain: no allocator has room\n\0");
+        Err(alloc::AllocError)
+    }
+
+    /// Deallocates memory from `alloc`, which has to be the allocator in this chain that owns it
+    pub unsafe fn dealloc(
+        &mut self,
+        mut alloc: NonNull<SizedAllocator>,
+        ptr: NonNull<u8>,
+        layout: Layout,
+    ) -> DeallocResponse {
+        let response = alloc.as_mut().dealloc(ptr, layout);
+        self.rebin(alloc);
+        response
+    }
+
+    /// Shrinks memory from `alloc`, which has to be the allocator in this chain that owns it
+    pub unsafe fn shrink_in_place(
+        &mut self,
+        mut alloc: NonNull<SizedAllocator>,
+        ptr: NonNull<u8>,
+        layout: Layout,
+        new_size: usize,
+    ) {
+        alloc.as_mut().shrink_in_place(ptr, layout, new_size);
+        self.rebin(alloc);
+    }
+
+    /// Grows memory from `alloc`, which has to be the allocator in this chain that owns it
+    pub unsafe fn grow_in_place(
+        &mut self,
+        mut alloc: NonNull<SizedAllocator>,
+        ptr: NonNull<u8>,
+        layout: Layout,
+        new_size: usize,
+    ) -> Result<(), alloc::AllocError> {
+        alloc.as_mut().grow_in_place(ptr, layout, new_size)?;
+        self.rebin(alloc);
+        Ok(())
+    }
+
+    /// Takes the empty allocator out of the chain, and returns the box it was in.
+    ///
+    /// # Safety
+    ///
+    /// The allocator has to be in this chain.
+    pub unsafe fn unlink(&mut self, alloc: NonNull<SizedAllocator>) -> MetadataBox<SizedAllocator> {
+        self.remove_from_bin(alloc);
+        let prev = alloc.as_ref().prev;
+        let link = match prev {
+            Some(mut prev) => &mut prev.as_mut().backup,
+            None => &mut self.head,
+        };
+        let mut alloc_box = link.take().unwrap();
+        debug_assert_eq!(NonNull::from(&*alloc_box), alloc);
+        alloc_box.primary.debug_assert_empty();
+        *link = alloc_box.backup.take();
+        match prev {
+            Some(mut prev) => prev.as_mut().link_backup(),
+            None => {
+                if let Some(head) = &mut self.head {
+                    head.prev = None;
+                }
+            }
         }
+        alloc_box.prev = None;
+        alloc_box
     }
 }
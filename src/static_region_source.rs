@@ -0,0 +1,280 @@
+//! A memory source that carves blocks out of one fixed region of memory, for when there's no
+//! kernel to ask: embedded, bare metal, or the inside of a kernel.
+//!
+//! Blocks are handed out from the bottom of the region up, with an atomic index, and blocks that
+//! come back go on a lock-free list to be handed out again before anything new.  Each entry is a
+//! run of blocks in a row, so that the blocks of a huge allocation can be handed out together
+//! again.  The list is linked through an array at the start of the region, with a link and a
+//! length for each block, rather than through the blocks themselves, since a block might already
+//! belong to someone else by the time a thread reads its link.  The head of the list is an index
+//! packed with a counter that goes up every time the head changes, so that a block being popped
+//! and pushed again in between can't fool the compare-and-swap.
+//!
+//! Taking a block off the front of a run, or looking for a run that fits a huge allocation, takes
+//! runs off the list and puts some back after.  Anyone who finds nothing while that's happening
+//! waits for it to finish and looks again, rather than giving up when there's memory left.
+
+use core::alloc::Layout;
+use core::cmp;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use memory_source::{MemorySource, BLOCK_ALIGN, BLOCK_SIZE};
+
+/// How many bits of the list head are the block index; the rest are the counter
+const INDEX_BITS: u32 = usize::BITS / 2;
+const INDEX_MASK: usize = (1 << INDEX_BITS) - 1;
+
+/// The room each block needs in the array of links: the next run on the list, and the length of
+/// the run it starts
+const LINK_SIZE: usize = 2 * size_of::<AtomicUsize>();
+
+/// Hands out blocks from a region of memory it's given.  Needs no libc, or anything else.
+///
+/// ```no_run
+/// extern crate stack_alloc;
+/// use stack_alloc::{Allocator, StaticRegionSource};
+///
+/// static mut HEAP: [u8; 16 << 20] = [0; 16 << 20];
+///
+/// #[global_allocator]
+/// static GLOBAL: Allocator<StaticRegionSource> = Allocator::new(unsafe {
+///     StaticRegionSource::from_raw_parts(&raw mut HEAP as *mut u8, 16 << 20)
+/// });
+/// ```
+#[derive(Debug)]
+pub struct StaticRegionSource {
+    start: *mut u8,
+    len: usize,
+    /// The first block that's never been handed out
+    next: AtomicUsize,
+    /// The list of blocks that have come back: the index of the first one plus 1 (or 0 if there
+    /// aren't any) in the low `INDEX_BITS`, and a counter in the rest
+    free: AtomicUsize,
+    /// How many threads have taken runs off the list that they're going to put back
+    reshuffling: AtomicUsize,
+}
+
+unsafe impl Send for StaticRegionSource {}
+unsafe impl Sync for StaticRegionSource {}
+
+impl StaticRegionSource {
+    /// Creates a source that hands out the blocks that fit in `region`.  The links for its list of
+    /// blocks go at the start, and the ends of it that aren't aligned to `BLOCK_ALIGN`, or don't
+    /// make up a whole block, aren't used.
+    pub const fn new(region: &'static mut [u8]) -> Self {
+        StaticRegionSource {
+            start: region.as_mut_ptr(),
+            len: region.len(),
+            next: AtomicUsize::new(0),
+            free: AtomicUsize::new(0),
+            reshuffling: AtomicUsize::new(0),
+        }
+    }
+
+    /// Creates a source that hands out the blocks that fit in `len` bytes at `start`, like a region
+    /// set aside by the linker script.
+    ///
+    /// # Safety
+    ///
+    /// The memory must be valid to read and write, and not used by anything else, for as long as
+    /// the source and the allocations from it are around.
+    pub const unsafe fn from_raw_parts(start: *mut u8, len: usize) -> Self {
+        StaticRegionSource {
+            start,
+            len,
+            next: AtomicUsize::new(0),
+            free: AtomicUsize::new(0),
+            reshuffling: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns where the array of links starts
+    fn links(&self) -> usize {
+        self.start as usize + self.start.align_offset(align_of::<AtomicUsize>())
+    }
+
+    /// Returns the address of the first block, and how many blocks fit.  The first block is the
+    /// first aligned address after the links for all of them.
+    fn blocks(&self) -> (usize, usize) {
+        let end = self.start as usize + self.len;
+        let first =
+            |blocks: usize| (self.links() + blocks * LINK_SIZE).next_multiple_of(BLOCK_ALIGN);
+        let mut blocks = cmp::min(end.saturating_sub(first(0)) / BLOCK_SIZE, INDEX_MASK - 1);
+        while blocks > 0 && first(blocks) + blocks * BLOCK_SIZE > end {
+            blocks -= 1;
+        }
+        (first(blocks), blocks)
+    }
+
+    /// Returns how many blocks the region has room for altogether
+    pub fn capacity(&self) -> usize {
+        self.blocks().1
+    }
+
+    /// Returns how many bytes of the region don't fit in any block, because they hold the links,
+    /// or they're before the first aligned address or after the last whole block
+    pub fn wasted(&self) -> usize {
+        self.len - self.capacity() * BLOCK_SIZE
+    }
+
+    /// Returns whether `ptr` is in one of the region's blocks
+    pub fn contains(&self, ptr: *const u8) -> bool {
+        let (first, blocks) = self.blocks();
+        first <= ptr as usize && (ptr as usize) < first + blocks * BLOCK_SIZE
+    }
+
+    /// The first word of a block's links is the next run on the list after the one it starts, and
+    /// the second is that run's length
+    fn word(&self, block: usize, word: usize) -> &AtomicUsize {
+        unsafe { &*((self.links() + block * LINK_SIZE) as *const AtomicUsize).add(word) }
+    }
+
+    /// Takes the first run off the list of ones that came back, if there are any, and returns its
+    /// first block and its length
+    fn pop(&self) -> Option<(usize, usize)> {
+        let mut head = self.free.load(Ordering::Acquire);
+        loop {
+            let block = (head & INDEX_MASK).checked_sub(1)?;
+            // The block might be handed out by someone else before the swap, in which case this
+            // reads a stale link, but then the counter's changed and the swap fails
+            let next = self.word(block, 0).load(Ordering::Relaxed);
+            let new = (head & !INDEX_MASK).wrapping_add(1 << INDEX_BITS) | next;
+            match self
+                .free
+                .compare_exchange_weak(head, new, Ordering::Acquire, Ordering::Acquire)
+            {
+                Ok(_) => return Some((block, self.word(block, 1).load(Ordering::Relaxed))),
+                Err(actual) => head = actual,
+            }
+        }
+    }
+
+    /// Puts `count` blocks in a row, starting at `block`, on the list of ones to hand out again
+    fn push(&self, block: usize, count: usize) {
+        if count == 0 {
+            return;
+        }
+        self.word(block, 1).store(count, Ordering::Relaxed);
+        let mut head = self.free.load(Ordering::Relaxed);
+        loop {
+            self.word(block, 0)
+                .store(head & INDEX_MASK, Ordering::Relaxed);
+            let new = (head & !INDEX_MASK).wrapping_add(1 << INDEX_BITS) | (block + 1);
+            match self
+                .free
+                .compare_exchange_weak(head, new, Ordering::Release, Ordering::Relaxed)
+            {
+                Ok(_) => return,
+                Err(actual) => head = actual,
+            }
+        }
+    }
+
+    /// Runs `take`, which might have runs off the list for a while, and if it doesn't find
+    /// anything while someone else does that, waits for them and runs it again
+    fn retrying<T>(&self, mut take: impl FnMut() -> Option<T>) -> Option<T> {
+        loop {
+            self.reshuffling.fetch_add(1, Ordering::Acquire);
+            let found = take();
+            self.reshuffling.fetch_sub(1, Ordering::Release);
+            if found.is_some() || self.reshuffling.load(Ordering::Acquire) == 0 {
+                return found;
+            }
+            while self.reshuffling.load(Ordering::Acquire) != 0 {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// Takes `count` blocks in a row that came back, with the first at an address that's a multiple
+    /// of `align`.  Runs are taken off the list until there's one that fits, and the others are put
+    /// back after, so for a moment other threads won't find them.
+    fn reuse_run(&self, first: usize, count: usize, align: usize) -> Option<usize> {
+        // The runs that didn't fit, linked like on the list
+        let mut passed = 0;
+        let found = loop {
+            let Some((run, len)) = self.pop() else {
+                break None;
+            };
+            let start = (run..run + len)
+                .find(|start| (first + start * BLOCK_SIZE).is_multiple_of(align))
+                .filter(|&start| start + count <= run + len);
+            if let Some(start) = start {
+                self.push(run, start - run);
+                self.push(start + count, run + len - start - count);
+                break Some(start);
+            }
+            self.word(run, 0).store(passed, Ordering::Relaxed);
+            passed = run + 1;
+        };
+        while let Some(run) = passed.checked_sub(1) {
+            passed = self.word(run, 0).load(Ordering::Relaxed);
+            self.push(run, self.word(run, 1).load(Ordering::Relaxed));
+        }
+        found
+    }
+
+    /// Takes `count` blocks that have never been handed out, with the first at an address that's a
+    /// multiple of `align`.  The blocks skipped to get there go on the list, so until they do,
+    /// other threads won't find them.
+    fn take_fresh(&self, count: usize, align: usize) -> Option<usize> {
+        let (first, blocks) = self.blocks();
+        let mut next = self.next.load(Ordering::Relaxed);
+        loop {
+            let start =
+                (next..blocks).find(|start| (first + start * BLOCK_SIZE).is_multiple_of(align))?;
+            let end = start.checked_add(count)?;
+            if end > blocks {
+                return None;
+            }
+            match self
+                .next
+                .compare_exchange_weak(next, end, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => {
+                    self.push(next, start - next);
+                    return Some(start);
+                }
+                Err(actual) => next = actual,
+            }
+        }
+    }
+}
+
+unsafe impl MemorySource for StaticRegionSource {
+    unsafe fn get_block(&self) -> Option<NonNull<u8>> {
+        let first = self.blocks().0;
+        let block = self.retrying(|| match self.pop() {
+            Some((block, count)) => {
+                self.push(block + 1, count - 1);
+                Some(block)
+            }
+            None => self.take_fresh(1, 1),
+        })?;
+        NonNull::new((first + block * BLOCK_SIZE) as *mut u8)
+    }
+
+    unsafe fn return_block(&self, block: NonNull<u8>) {
+        let first = self.blocks().0;
+        self.push((block.as_ptr() as usize - first) / BLOCK_SIZE, 1);
+    }
+
+    /// Huge allocations get a run of blocks that came back if there's one long enough, or else
+    /// ones that have never been handed out
+    unsafe fn get_huge(&self, layout: Layout) -> Option<NonNull<u8>> {
+        let first = self.blocks().0;
+        let count = layout.size().div_ceil(BLOCK_SIZE);
+        let start = self.retrying(|| {
+            self.reuse_run(first, count, layout.align())
+                .or_else(|| self.take_fresh(count, layout.align()))
+        })?;
+        NonNull::new((first + start * BLOCK_SIZE) as *mut u8)
+    }
+
+    unsafe fn return_huge(&self, ptr: NonNull<u8>, layout: Layout) {
+        let first = self.blocks().0;
+        let start = (ptr.as_ptr() as usize - first) / BLOCK_SIZE;
+        self.push(start, layout.size().div_ceil(BLOCK_SIZE));
+    }
+}
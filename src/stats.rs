@@ -0,0 +1,103 @@
+//! Statistics about an `Allocator`'s heap, from `Allocator::stats`.
+//!
+//! The counters are only kept with the `stats` feature on, so they cost nothing otherwise.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A snapshot of what's in an `Allocator`'s heap, and what's been done with it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Blocks gotten from the memory source, including the ones given back since
+    pub blocks_obtained: usize,
+    /// Blocks given back to the memory source
+    pub blocks_returned: usize,
+
+    /// The 1-byte chunks
+    pub very_small: BucketStats,
+    /// The 8-byte chunks
+    pub small: BucketStats,
+    /// The 64-byte chunks
+    pub medium: BucketStats,
+    /// The 512-byte chunks
+    pub large: BucketStats,
+    /// The 4 KiB chunks
+    pub very_large: BucketStats,
+    /// The 64-byte chunks used for the allocator's own bookkeeping
+    pub metadata: BucketStats,
+    /// Allocations too big for a block, which get their memory straight from the memory source
+    pub huge_allocations: usize,
+
+    /// The total size of every allocation and reallocation asked for so far
+    pub bytes_requested: usize,
+    /// The total size handed out for them, after rounding up to whole chunks.  The difference from
+    /// `bytes_requested` is the internal fragmentation.
+    pub bytes_allocated: usize,
+
+    /// Allocations so far
+    pub allocs: usize,
+    /// Deallocations so far
+    pub frees: usize,
+    /// Reallocations so far, both growing and shrinking
+    pub reallocs: usize,
+    /// Reallocations that could resize the memory where it was
+    pub reallocs_in_place: usize,
+    /// Reallocations that had to copy the memory somewhere else
+    pub reallocs_moved: usize,
+}
+
+/// The stacks of one size of chunk
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BucketStats {
+    /// The number of stacks, each of which is a `SizedAllocator`
+    pub stacks: usize,
+    /// The number of chunks in the stacks that are in use.  Chunks that a thread has cached count
+    /// as in use, and so do the chunks that the smaller sizes' stacks are made of.
+    pub chunks_in_use: usize,
+    /// How many times a stack was tried for an allocation and couldn't fit it, usually because of
+    /// its alignment
+    pub missed_probes: usize,
+}
+
+/// The running totals behind `Stats`
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    pub(crate) blocks_obtained: AtomicUsize,
+    pub(crate) blocks_returned: AtomicUsize,
+    pub(crate) bytes_requested: AtomicUsize,
+    pub(crate) bytes_allocated: AtomicUsize,
+    pub(crate) allocs: AtomicUsize,
+    pub(crate) frees: AtomicUsize,
+    pub(crate) reallocs: AtomicUsize,
+    pub(crate) reallocs_in_place: AtomicUsize,
+    pub(crate) reallocs_moved: AtomicUsize,
+}
+
+impl Counters {
+    pub(crate) const fn new() -> Self {
+        Counters {
+            blocks_obtained: AtomicUsize::new(0),
+            blocks_returned: AtomicUsize::new(0),
+            bytes_requested: AtomicUsize::new(0),
+            bytes_allocated: AtomicUsize::new(0),
+            allocs: AtomicUsize::new(0),
+            frees: AtomicUsize::new(0),
+            reallocs: AtomicUsize::new(0),
+            reallocs_in_place: AtomicUsize::new(0),
+            reallocs_moved: AtomicUsize::new(0),
+        }
+    }
+
+    /// Fills in the parts of `stats` that come from the counters
+    pub(crate) fn read(&self, stats: &mut Stats) {
+        let read = |counter: &AtomicUsize| counter.load(Ordering::Relaxed);
+        stats.blocks_obtained = read(&self.blocks_obtained);
+        stats.blocks_returned = read(&self.blocks_returned);
+        stats.bytes_requested = read(&self.bytes_requested);
+        stats.bytes_allocated = read(&self.bytes_allocated);
+        stats.allocs = read(&self.allocs);
+        stats.frees = read(&self.frees);
+        stats.reallocs = read(&self.reallocs);
+        stats.reallocs_in_place = read(&self.reallocs_in_place);
+        stats.reallocs_moved = read(&self.reallocs_moved);
+    }
+}
@@ -1,10 +1,12 @@
 //! A simple memory source for testing
 
+use core::alloc::Layout;
+use core::cmp;
 use core::ptr;
 
 use memory_source::{MemorySource, BLOCK_ALIGN, BLOCK_SIZE};
 
-/// A test memory source that gets memory using `libc::memalign`
+/// A test memory source that gets memory using `libc::memalign`, and returns it with `libc::free`
 #[derive(Clone, Copy, Debug)]
 pub struct TestMemorySource;
 
@@ -14,4 +16,20 @@ unsafe impl MemorySource for TestMemorySource {
         let ptr = ::libc::memalign(BLOCK_ALIGN, BLOCK_SIZE);
         ptr::NonNull::new(ptr).map(ptr::NonNull::cast)
     }
+
+    unsafe fn return_block(&self, block: ptr::NonNull<u8>) {
+        debug_log!("TestMemorySource: returning memory with libc::free\n\0");
+        ::libc::free(block.as_ptr() as *mut ::libc::c_void);
+    }
+
+    unsafe fn get_huge(&self, layout: Layout) -> Option<ptr::NonNull<u8>> {
+        debug_log!("TestMemorySource: getting huge memory from libc::memalign\n\0");
+        let ptr = ::libc::memalign(cmp::max(layout.align(), BLOCK_ALIGN), layout.size());
+        ptr::NonNull::new(ptr).map(ptr::NonNull::cast)
+    }
+
+    unsafe fn return_huge(&self, ptr: ptr::NonNull<u8>, _layout: Layout) {
+        debug_log!("TestMemorySource: returning huge memory with libc::free\n\0");
+        ::libc::free(ptr.as_ptr() as *mut ::libc::c_void);
+    }
 }
@@ -0,0 +1,241 @@
+//! Per-thread caches of free chunks, so small allocations don't have to take a lock every time.
+//!
+//! Each thread keeps a few lists of small chunks that it's freed, one list per usable size.  When a
+//! list runs dry, it's refilled with a whole batch of chunks under a single trip through its lock,
+//! and when it gets too full, half of it is handed back the same way.  When the thread exits,
+//! whatever's left over goes back to the heap.
+//!
+//! A thread's cache only ever holds memory from one `Allocator`: the first one with caching turned
+//! on that the thread uses.  Any others just go straight to their heaps.
+
+use core::alloc::{AllocError, Layout};
+use core::cell::UnsafeCell;
+use core::ptr::{self, NonNull};
+
+use bucketed;
+use global_allocator::Allocator;
+use lock::RawLock;
+use memory_source::MemorySource;
+
+/// The number of chunks moved between a cache and the heap at once
+const BATCH: usize = 16;
+
+/// The most chunks a single list can hold
+const CAPACITY: usize = 2 * BATCH;
+
+/// One list for each usable size below 64 bytes: 1 to 7 bytes for the very small chunks, and 8
+/// to 56 bytes plus 63 for the small ones
+const CLASSES: usize = 15;
+
+/// The biggest alignment that's cached.  It's enough for anything but over-aligned types.
+const MAX_ALIGN: usize = 16;
+
+/// Returns the list for allocations with this layout, or `None` if they aren't cached
+fn class_of(layout: Layout) -> Option<usize> {
+    if layout.size() == 0 || layout.size() >= 64 || layout.align() > MAX_ALIGN {
+        return None;
+    }
+    let usable = bucketed::usable_size(layout);
+    if usable < 8 {
+        Some(usable - 1)
+    } else {
+        Some(6 + usable.div_ceil(8))
+    }
+}
+
+/// Returns the layout to give the chunks in a list back to the heap with.  Only the size matters
+/// when deallocating, and every chunk in the list has the same usable size.
+fn class_layout(class: usize) -> Layout {
+    let size = match class {
+        0..=6 => class + 1,
+        7..=13 => (class - 6) * 8,
+        _ => 63,
+    };
+    unsafe { Layout::from_size_align_unchecked(size, 1) }
+}
+
+/// Hands chunks from a cache back to the allocator at `owner`
+type Flush = unsafe fn(owner: *const (), chunks: &[NonNull<u8>], layout: Layout);
+
+/// The free chunks of a single usable size
+#[derive(Clone, Copy)]
+struct FreeList {
+    len: usize,
+    chunks: [*mut u8; CAPACITY],
+}
+
+impl FreeList {
+    const fn new() -> Self {
+        FreeList {
+            len: 0,
+            chunks: [ptr::null_mut(); CAPACITY],
+        }
+    }
+
+    fn chunks(&self, range: core::ops::Range<usize>) -> &[NonNull<u8>] {
+        // `NonNull<u8>` has the same layout as `*mut u8`, and everything below `len` is non-null
+        unsafe {
+            core::slice::from_raw_parts(
+                self.chunks.as_ptr().add(range.start).cast::<NonNull<u8>>(),
+                range.end - range.start,
+            )
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// The thread hasn't used the cache yet
+    Unused,
+    /// The exit hook is being set up, which might allocate
+    Registering,
+    /// The cache is in use
+    Active,
+    /// The thread is exiting, and the cache has been emptied
+    Exited,
+}
+
+struct ThreadCache {
+    state: State,
+    /// The allocator that the cached chunks belong to
+    owner: *const (),
+    flush: Option<Flush>,
+    lists: [FreeList; CLASSES],
+}
+
+impl ThreadCache {
+    const fn new() -> Self {
+        ThreadCache {
+            state: State::Unused,
+            owner: ptr::null(),
+            flush: None,
+            lists: [FreeList::new(); CLASSES],
+        }
+    }
+
+    /// Gives every cached chunk back to its allocator
+    unsafe fn flush_all(&mut self) {
+        if let Some(flush) = self.flush {
+            for (class, list) in self.lists.iter_mut().enumerate() {
+                flush(self.owner, list.chunks(0..list.len), class_layout(class));
+                list.len = 0;
+            }
+        }
+    }
+}
+
+/// Empties the cache when the thread exits
+struct ExitHook;
+
+impl Drop for ExitHook {
+    fn drop(&mut self) {
+        let _ = CACHE.try_with(|cache| unsafe {
+            let cache = &mut *cache.get();
+            debug_log!("ThreadCache: thread exiting, flushing the cache\n\0");
+            cache.flush_all();
+            cache.state = State::Exited;
+        });
+    }
+}
+
+std::thread_local! {
+    static CACHE: UnsafeCell<ThreadCache> = const { UnsafeCell::new(ThreadCache::new()) };
+    static EXIT_HOOK: ExitHook = const { ExitHook };
+}
+
+/// Returns this thread's cache if it can be used for memory from `heap`.
+///
+/// The first time, this sets up the exit hook and ties the cache to `heap`.  Setting up the hook
+/// can allocate, so the cache isn't used until it's done.
+unsafe fn cache_for<S: MemorySource, L: RawLock>(
+    heap: &Allocator<S, L>,
+) -> Option<&'static mut ThreadCache> {
+    let cache = CACHE.try_with(UnsafeCell::get).ok()?;
+    let owner = ptr::from_ref(heap).cast::<()>();
+    match (*cache).state {
+        State::Active if (*cache).owner == owner => Some(&mut *cache),
+        State::Unused => {
+            (*cache).state = State::Registering;
+            if EXIT_HOOK.try_with(|_| ()).is_err() {
+                // The thread is already exiting
+                (*cache).state = State::Exited;
+                return None;
+            }
+            (*cache).owner = owner;
+            (*cache).flush = Some(flush::<S, L>);
+            (*cache).state = State::Active;
+            Some(&mut *cache)
+        }
+        _ => None,
+    }
+}
+
+unsafe fn flush<S: MemorySource, L: RawLock>(
+    owner: *const (),
+    chunks: &[NonNull<u8>],
+    layout: Layout,
+) {
+    let heap = &*owner.cast::<Allocator<S, L>>();
+    debug_log!("ThreadCache: flushing %zu chunks\n\0", chunks.len());
+    heap.get_alloc().dealloc_batch(chunks, layout);
+}
+
+/// Allocates from this thread's cache, refilling it if it's empty.
+///
+/// Returns `None` if the allocation can't come from the cache, and has to go to the heap instead.
+pub(crate) unsafe fn alloc<S: MemorySource, L: RawLock>(
+    heap: &Allocator<S, L>,
+    layout: Layout,
+) -> Option<Result<NonNull<u8>, AllocError>> {
+    let class = class_of(layout)?;
+    let list = &mut cache_for(heap)?.lists[class];
+
+    if list.len == 0 {
+        // Every chunk in the batch is aligned for this allocation
+        let batch_layout =
+            Layout::from_size_align_unchecked(bucketed::usable_size(layout), layout.align());
+        debug_log!("ThreadCache: refilling size %zu\n\0", batch_layout.size());
+        list.len = heap
+            .get_alloc()
+            .alloc_batch(batch_layout, &mut list.chunks[..BATCH]);
+        if list.len == 0 {
+            return Some(Err(AllocError));
+        }
+    }
+
+    // Chunks freed by other code might not be aligned enough
+    let ptr = list.chunks[list.len - 1];
+    if !(ptr as usize).is_multiple_of(layout.align()) {
+        return None;
+    }
+    list.len -= 1;
+    Some(Ok(NonNull::new_unchecked(ptr)))
+}
+
+/// Puts freed memory in this thread's cache, handing half of it back to the heap if it's full.
+///
+/// Returns `false` if the memory can't go in the cache, and has to be given back to the heap.
+pub(crate) unsafe fn dealloc<S: MemorySource, L: RawLock>(
+    heap: &Allocator<S, L>,
+    ptr: NonNull<u8>,
+    layout: Layout,
+) -> bool {
+    let class = match class_of(layout) {
+        Some(class) => class,
+        None => return false,
+    };
+    let cache = match cache_for(heap) {
+        Some(cache) => cache,
+        None => return false,
+    };
+    let list = &mut cache.lists[class];
+    if list.len == CAPACITY {
+        // The oldest chunks go back, since the newest are more likely to still be in the CPU cache
+        flush::<S, L>(cache.owner, list.chunks(0..BATCH), class_layout(class));
+        list.chunks.copy_within(BATCH.., 0);
+        list.len -= BATCH;
+    }
+    list.chunks[list.len] = ptr.as_ptr();
+    list.len += 1;
+    true
+}
@@ -0,0 +1,379 @@
+//! Recording every allocation, free and reallocation to a trace file, and reading it back.
+//!
+//! With the `trace` feature, `Allocator::start_trace` starts recording into a ring buffer of
+//! events, which is written out to a file descriptor whenever it fills up, and when the trace is
+//! flushed or stopped.  The `stack_alloc-replay` tool reads the trace back, and runs it against a
+//! fresh heap.  Recording needs Linux, for the thread IDs, so the feature does nothing anywhere
+//! else; reading works anywhere.
+//!
+//! The trace's lock is only taken to record each event, after the operation's done, so events
+//! from different threads can be written out of order.  Their times put them back in order: a
+//! free or reallocation is timed before it starts, and an allocation after it's finished, so
+//! memory is always freed before the same memory is handed out again.  The new memory from a
+//! reallocation can still be timed before it was freed by another thread, if the two race.
+//!
+//! A trace is a 16-byte header, then 64 bytes for each event.  Everything is a little-endian
+//! `u64`:
+//!
+//! ```text
+//! header:   "STACKTRC"           version
+//! event:    op | thread << 32    time  ptr  size  align  new_ptr  new_size  new_align
+//! ```
+//!
+//! `op` is 1 for an allocation, 2 for a free, and 3 for a reallocation.  `time` is in nanoseconds,
+//! from the monotonic clock.  For an allocation, `ptr` is what it returned, or 0 if it failed.
+//! For a free, `size` is 0 if it wasn't given one.  For a reallocation, `ptr`, `size` and `align`
+//! are the old allocation, and `new_ptr`, `new_size` and `new_align` are the new one.  The new
+//! alignment is only different when it's from `Allocator::grow` or `shrink`.
+
+use core::alloc::Layout;
+use core::fmt;
+use core::ptr::NonNull;
+#[cfg(all(feature = "trace", target_os = "linux"))]
+use core::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(all(feature = "trace", target_os = "linux"))]
+use lock::{Locked, RawLock};
+
+/// The first 8 bytes of every trace
+pub const MAGIC: [u8; 8] = *b"STACKTRC";
+
+/// The version of the format written by this version of the crate
+pub const VERSION: u64 = 1;
+
+/// The number of words in each event
+const EVENT_WORDS: usize = 8;
+
+/// The number of events that are kept before they're written out
+#[cfg(all(feature = "trace", target_os = "linux"))]
+const RING_SIZE: usize = 128;
+
+/// What happened
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    /// Memory was allocated
+    Alloc = 1,
+    /// Memory was freed
+    Dealloc = 2,
+    /// Memory was resized, maybe moving it
+    Realloc = 3,
+}
+
+/// One thing that happened to the heap
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Event {
+    /// What happened
+    pub op: Op,
+    /// The ID of the thread it happened on
+    pub thread: u32,
+    /// When it happened, in nanoseconds
+    pub time: u64,
+    /// The memory that was allocated or freed, or the old memory for a reallocation
+    pub ptr: usize,
+    /// The size of the memory at `ptr`
+    pub size: usize,
+    /// Its alignment
+    pub align: usize,
+    /// The new memory, for a reallocation
+    pub new_ptr: usize,
+    /// The size of the new memory, for a reallocation
+    pub new_size: usize,
+    /// Its alignment
+    pub new_align: usize,
+}
+
+fn address<E>(ptr: &Result<NonNull<u8>, E>) -> usize {
+    ptr.as_ref().map_or(0, |ptr| ptr.as_ptr() as usize)
+}
+
+impl Event {
+    fn new(op: Op, ptr: usize, layout: Layout) -> Self {
+        Event {
+            op,
+            thread: 0,
+            time: 0,
+            ptr,
+            size: layout.size(),
+            align: layout.align(),
+            new_ptr: 0,
+            new_size: 0,
+            new_align: 0,
+        }
+    }
+
+    /// An allocation that returned `ptr`.  The thread and time are filled in when it's recorded.
+    pub(crate) fn alloc<E>(layout: Layout, ptr: &Result<NonNull<u8>, E>) -> Self {
+        Event::new(Op::Alloc, address(ptr), layout)
+    }
+
+    /// A free of the memory at `ptr`
+    pub(crate) fn dealloc(ptr: NonNull<u8>, layout: Layout) -> Self {
+        Event::new(Op::Dealloc, ptr.as_ptr() as usize, layout)
+    }
+
+    /// A reallocation of the memory at `ptr` to `new_layout`, which returned `new_ptr`
+    pub(crate) fn realloc<E>(
+        ptr: NonNull<u8>,
+        layout: Layout,
+        new_layout: Layout,
+        new_ptr: &Result<NonNull<u8>, E>,
+    ) -> Self {
+        Event {
+            new_ptr: address(new_ptr),
+            new_size: new_layout.size(),
+            new_align: new_layout.align(),
+            ..Event::new(Op::Realloc, ptr.as_ptr() as usize, layout)
+        }
+    }
+
+    #[cfg(all(feature = "trace", target_os = "linux"))]
+    fn to_words(self) -> [u64; EVENT_WORDS] {
+        [
+            self.op as u64 | (self.thread as u64) << 32,
+            self.time,
+            self.ptr as u64,
+            self.size as u64,
+            self.align as u64,
+            self.new_ptr as u64,
+            self.new_size as u64,
+            self.new_align as u64,
+        ]
+    }
+
+    fn from_words(words: [u64; EVENT_WORDS]) -> Result<Self, TraceError> {
+        let op = match words[0] as u32 {
+            1 => Op::Alloc,
+            2 => Op::Dealloc,
+            3 => Op::Realloc,
+            _ => return Err(TraceError::BadEvent),
+        };
+        Ok(Event {
+            op,
+            thread: (words[0] >> 32) as u32,
+            time: words[1],
+            ptr: words[2] as usize,
+            size: words[3] as usize,
+            align: words[4] as usize,
+            new_ptr: words[5] as usize,
+            new_size: words[6] as usize,
+            new_align: words[7] as usize,
+        })
+    }
+}
+
+/// The events that haven't been written out yet, and where to write them
+#[cfg(all(feature = "trace", target_os = "linux"))]
+#[derive(Debug)]
+struct Ring {
+    fd: ::libc::c_int,
+    start: usize,
+    len: usize,
+    events: [[u64; EVENT_WORDS]; RING_SIZE],
+}
+
+/// Writes all of `bytes` to the trace file at `fd`.  If it can't, the trace is cut short, and `fd`
+/// is set to -1.  Writes interrupted by a signal are tried again.
+#[cfg(all(feature = "trace", target_os = "linux"))]
+fn write_all(fd: &mut ::libc::c_int, mut bytes: &[u8]) {
+    while !bytes.is_empty() && *fd >= 0 {
+        let written = unsafe { ::libc::write(*fd, bytes.as_ptr().cast(), bytes.len()) };
+        if written < 0 && unsafe { *::libc::__errno_location() } == ::libc::EINTR {
+            continue;
+        }
+        if written < 0 {
+            debug_log!("Trace: couldn't write to the trace, stopping\n\0");
+            *fd = -1;
+            return;
+        }
+        bytes = &bytes[written as usize..];
+    }
+}
+
+#[cfg(all(feature = "trace", target_os = "linux"))]
+impl Ring {
+    /// Writes out the events, oldest first
+    fn flush(&mut self) {
+        while self.len > 0 {
+            let end = (self.start + self.len).min(RING_SIZE);
+            // The events are stored little-endian already
+            let events = &self.events[self.start..end];
+            let bytes = unsafe {
+                core::slice::from_raw_parts(events.as_ptr().cast::<u8>(), size_of_val(events))
+            };
+            write_all(&mut self.fd, bytes);
+            self.len -= end - self.start;
+            self.start = end % RING_SIZE;
+        }
+    }
+
+    fn push(&mut self, event: Event) {
+        if self.len == RING_SIZE {
+            self.flush();
+        }
+        let mut words = event.to_words();
+        for word in words.iter_mut() {
+            *word = word.to_le();
+        }
+        self.events[(self.start + self.len) % RING_SIZE] = words;
+        self.len += 1;
+    }
+}
+
+/// An `Allocator`'s trace: whether one's being taken, and the events not written out yet
+#[cfg(all(feature = "trace", target_os = "linux"))]
+#[derive(Debug)]
+pub(crate) struct Trace<L: RawLock> {
+    on: AtomicBool,
+    ring: Locked<Ring, L>,
+}
+
+#[cfg(all(feature = "trace", target_os = "linux"))]
+impl<L: RawLock> Trace<L> {
+    pub(crate) const fn new() -> Self {
+        Trace {
+            on: AtomicBool::new(false),
+            ring: Locked::new(Ring {
+                fd: -1,
+                start: 0,
+                len: 0,
+                events: [[0; EVENT_WORDS]; RING_SIZE],
+            }),
+        }
+    }
+
+    /// Starts writing a trace to `fd`, stopping any trace that was already being taken
+    pub(crate) fn start(&self, fd: ::libc::c_int) {
+        let mut ring = self.ring.lock();
+        ring.flush();
+        ring.fd = fd;
+        write_all(&mut ring.fd, &MAGIC);
+        write_all(&mut ring.fd, &VERSION.to_le_bytes());
+        self.on.store(true, Ordering::Relaxed);
+    }
+
+    /// Writes out the events recorded so far
+    pub(crate) fn flush(&self) {
+        self.ring.lock().flush();
+    }
+
+    /// Writes out the events recorded so far, and stops recording.  Returns whether everything got
+    /// written.
+    pub(crate) fn stop(&self) -> bool {
+        let mut ring = self.ring.lock();
+        ring.flush();
+        let written = ring.fd >= 0;
+        ring.fd = -1;
+        self.on.store(false, Ordering::Relaxed);
+        written
+    }
+
+    /// Runs `op`, and records the event it makes, if a trace is being taken.
+    ///
+    /// The lock isn't held while `op` runs, so that if it panics, and the panic allocates, it
+    /// doesn't deadlock.
+    pub(crate) fn record<T>(&self, op: impl FnOnce() -> T, event: impl FnOnce(&T) -> Event) -> T {
+        if !self.on.load(Ordering::Relaxed) {
+            return op();
+        }
+        let before = now();
+        let result = op();
+        let mut event = event(&result);
+        event.thread = unsafe { ::libc::syscall(::libc::SYS_gettid) } as u32;
+        event.time = match event.op {
+            Op::Alloc => now(),
+            Op::Dealloc | Op::Realloc => before,
+        };
+        let mut ring = self.ring.lock();
+        if ring.fd >= 0 {
+            ring.push(event);
+        }
+        result
+    }
+}
+
+/// Returns the time from the monotonic clock, in nanoseconds
+#[cfg(all(feature = "trace", target_os = "linux"))]
+fn now() -> u64 {
+    let mut time = ::libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { ::libc::clock_gettime(::libc::CLOCK_MONOTONIC, &mut time) };
+    time.tv_sec as u64 * 1_000_000_000 + time.tv_nsec as u64
+}
+
+/// Reads a word out of 8 bytes
+fn read_word(bytes: &[u8]) -> u64 {
+    let mut word = [0; 8];
+    word.copy_from_slice(bytes);
+    u64::from_le_bytes(word)
+}
+
+/// What's wrong with a trace that can't be read
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceError {
+    /// It doesn't start with `MAGIC`, so it isn't a trace
+    BadMagic,
+    /// It's from a different version of the format
+    UnsupportedVersion(u64),
+    /// It ends partway through an event
+    Truncated,
+    /// An event doesn't make sense
+    BadEvent,
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceError::BadMagic => write!(f, "not an allocation trace"),
+            TraceError::UnsupportedVersion(version) => {
+                write!(f, "unsupported trace version {}", version)
+            }
+            TraceError::Truncated => write!(f, "the trace is cut off"),
+            TraceError::BadEvent => write!(f, "the trace has a broken event"),
+        }
+    }
+}
+
+/// Reads the events out of a trace, without copying it
+#[derive(Clone, Debug)]
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    /// Starts reading a trace, checking its header
+    pub fn new(bytes: &'a [u8]) -> Result<Self, TraceError> {
+        if bytes.len() < 16 || bytes[..8] != MAGIC {
+            return Err(TraceError::BadMagic);
+        }
+        let version = read_word(&bytes[8..16]);
+        if version != VERSION {
+            return Err(TraceError::UnsupportedVersion(version));
+        }
+        Ok(Reader {
+            bytes: &bytes[16..],
+        })
+    }
+}
+
+impl<'a> Iterator for Reader<'a> {
+    type Item = Result<Event, TraceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.bytes.is_empty() {
+            return None;
+        }
+        if self.bytes.len() < EVENT_WORDS * 8 {
+            self.bytes = &[];
+            return Some(Err(TraceError::Truncated));
+        }
+        let (event, rest) = self.bytes.split_at(EVENT_WORDS * 8);
+        self.bytes = rest;
+        let mut words = [0; EVENT_WORDS];
+        for (word, chunk) in words.iter_mut().zip(event.chunks_exact(8)) {
+            *word = read_word(chunk);
+        }
+        Some(Event::from_words(words))
+    }
+}
@@ -0,0 +1,22 @@
+//! Walking the heap's live allocations, for `Allocator::visit`.
+//!
+//! It goes through every stack in every chain, and reports each allocation that was handed out by
+//! the heap.  The allocations that hold the allocator's own stacks and metadata are skipped, so
+//! what's left is what the program is using.  Chunks sitting in a thread's cache still count as
+//! live, since as far as the heap knows, they are.
+
+use check::Bucket;
+
+/// A single live allocation
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Allocation {
+    /// The address of the start of the allocation
+    pub address: usize,
+    /// Its usable size: what it was asked for, rounded up to a whole number of chunks
+    pub size: usize,
+    /// Which chain it came from
+    pub bucket: Bucket,
+    /// The address of the bottom of the stack it's in.  Huge allocations have no stack, so it's
+    /// their own address.
+    pub stack: usize,
+}
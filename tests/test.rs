@@ -1,11 +1,25 @@
-#![feature(const_fn)]
+#![feature(allocator_api)]
 
 extern crate stack_alloc;
 
-use stack_alloc::{Allocator, TestMemorySource};
+use std::alloc::{Allocator as _, GlobalAlloc, Layout};
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(all(feature = "futex", target_os = "linux"))]
+use stack_alloc::lock::FutexLock;
+use stack_alloc::lock::{BackoffLock, NoLock, RawLock};
+use stack_alloc::{Allocator, MemorySource, TestMemorySource};
 
 #[global_allocator]
-static GLOBAL: Allocator<TestMemorySource> = Allocator(TestMemorySource);
+static GLOBAL: Allocator<TestMemorySource> = Allocator::new(TestMemorySource);
+
+/// Makes freed memory reusable straight away, like it is without the quarantine
+fn without_quarantine<S: MemorySource, L: RawLock>(heap: &Allocator<S, L>) {
+    #[cfg(feature = "quarantine")]
+    heap.set_quarantine_size(0);
+    let _ = heap;
+}
 
 #[test]
 fn vecs() {
@@ -22,3 +36,1124 @@ fn string() {
     my_string += "\nYes it is.";
     println!("{}", my_string);
 }
+
+#[test]
+fn huge_vecs() {
+    let mut my_vec = vec![7_u8; 300 * 1024];
+    for i in 0..1024 * 1024 {
+        my_vec.push(i as u8);
+    }
+    assert_eq!(my_vec.len(), 1324 * 1024);
+    assert!(my_vec[..300 * 1024].iter().all(|&x| x == 7));
+    my_vec.truncate(10);
+    my_vec.shrink_to_fit();
+    assert_eq!(my_vec, [7; 10]);
+}
+
+#[cfg(all(feature = "mmap_source", target_os = "linux"))]
+#[test]
+fn mmap_source() {
+    use stack_alloc::memory_source::{BLOCK_ALIGN, BLOCK_SIZE};
+    use stack_alloc::MmapSource;
+
+    let sources = [
+        MmapSource::new(),
+        MmapSource::new().noreserve(true),
+        MmapSource::new().populate(true),
+    ];
+    for source in sources.iter() {
+        unsafe {
+            let block = source.get_block().unwrap();
+            assert_eq!(block.as_ptr() as usize % BLOCK_ALIGN, 0);
+            block.as_ptr().write_bytes(1, BLOCK_SIZE);
+            source.return_block(block);
+
+            let layout = Layout::from_size_align(300 * 1024, 1 << 20).unwrap();
+            let huge = source.get_huge(layout).unwrap();
+            assert_eq!(huge.as_ptr() as usize % (1 << 20), 0);
+            huge.as_ptr().write_bytes(1, layout.size());
+            source.return_huge(huge, layout);
+        }
+
+        let heap = Allocator::new(*source);
+        let mut my_vec = Vec::new_in(&heap);
+        my_vec.extend(0..100_000);
+        assert!(my_vec.iter().copied().eq(0..100_000));
+    }
+}
+
+#[cfg(all(feature = "mmap_source", target_os = "linux"))]
+#[test]
+fn reserved_region_source() {
+    use stack_alloc::memory_source::BLOCK_SIZE;
+    use stack_alloc::ReservedRegionSource;
+
+    // 64 blocks, and the first holds the bitmap
+    let source = ReservedRegionSource::new(64 * BLOCK_SIZE);
+    unsafe {
+        let blocks: Vec<_> = std::iter::from_fn(|| source.get_block()).collect();
+        assert_eq!(blocks.len(), 63);
+        for pair in blocks.windows(2) {
+            assert_eq!(
+                pair[1].as_ptr() as usize - pair[0].as_ptr() as usize,
+                BLOCK_SIZE
+            );
+        }
+        assert!(blocks.iter().all(|block| source.contains(block.as_ptr())));
+        assert!(!source.contains(&source as *const _ as *const u8));
+
+        blocks[10].as_ptr().write_bytes(1, BLOCK_SIZE);
+        source.return_block(blocks[10]);
+        source.return_block(blocks[11]);
+        source.return_block(blocks[12]);
+        // They come back zeroed
+        let again = source.get_block().unwrap();
+        assert_eq!(again, blocks[10]);
+        assert!(std::slice::from_raw_parts(again.as_ptr(), BLOCK_SIZE)
+            .iter()
+            .all(|&x| x == 0));
+        // Huge allocations need blocks in a row, and there are only two now
+        let layout = Layout::from_size_align(3 * BLOCK_SIZE, 8).unwrap();
+        assert!(source.get_huge(layout).is_none());
+        let layout = Layout::from_size_align(2 * BLOCK_SIZE, 8).unwrap();
+        let huge = source.get_huge(layout).unwrap();
+        assert_eq!(huge, blocks[11]);
+        source.return_huge(huge, layout);
+        for &block in blocks
+            .iter()
+            .filter(|&&block| block != blocks[11] && block != blocks[12])
+        {
+            source.return_block(block);
+        }
+    }
+
+    // The same blocks get used again and again
+    let source = ReservedRegionSource::new(16 * BLOCK_SIZE);
+    let layout = Layout::from_size_align(4 * BLOCK_SIZE, 8).unwrap();
+    for _ in 0..100 {
+        unsafe {
+            let huge = source.get_huge(layout).unwrap();
+            huge.as_ptr().write_bytes(1, layout.size());
+            source.return_huge(huge, layout);
+        }
+    }
+
+    let heap = Allocator::new(ReservedRegionSource::new(1 << 30));
+    let mut my_vec = Vec::new_in(&heap);
+    my_vec.extend(0..1_000_000);
+    assert!(my_vec.iter().copied().eq(0..1_000_000));
+    assert!(heap.source().contains(my_vec.as_ptr().cast()));
+}
+
+#[test]
+fn static_region_source() {
+    use stack_alloc::memory_source::{BLOCK_ALIGN, BLOCK_SIZE};
+    use stack_alloc::StaticRegionSource;
+
+    // Misaligned on purpose, so there's only room for 8 whole blocks
+    let region = vec![0u8; 9 * BLOCK_SIZE].leak();
+    let offset = region.as_ptr().align_offset(BLOCK_ALIGN) + 1;
+    let source = StaticRegionSource::new(&mut region[offset..]);
+    assert_eq!(source.capacity(), 8);
+    unsafe {
+        let blocks: Vec<_> = std::iter::from_fn(|| source.get_block()).collect();
+        assert_eq!(blocks.len(), 8);
+        assert!(blocks.iter().all(
+            |block| (block.as_ptr() as usize).is_multiple_of(BLOCK_ALIGN)
+                && source.contains(block.as_ptr())
+        ));
+
+        source.return_block(blocks[2]);
+        source.return_block(blocks[5]);
+        assert_eq!(source.get_block(), Some(blocks[5]));
+        assert_eq!(source.get_block(), Some(blocks[2]));
+        assert_eq!(source.get_block(), None);
+        for &block in &blocks {
+            source.return_block(block);
+        }
+    }
+
+    // A huge allocation's blocks go back together, so they can be handed out together again
+    let source = StaticRegionSource::new(vec![0u8; 8 * BLOCK_SIZE].leak());
+    let layout = Layout::from_size_align(3 * BLOCK_SIZE, BLOCK_ALIGN).unwrap();
+    unsafe {
+        for _ in 0..100 {
+            let huge = source.get_huge(layout).unwrap();
+            huge.as_ptr().write_bytes(1, layout.size());
+            source.return_huge(huge, layout);
+        }
+        // Taking a block from the front leaves the rest of the run
+        let huge = source.get_huge(layout).unwrap();
+        source.return_huge(huge, layout);
+        assert_eq!(source.get_block(), Some(huge));
+        let smaller = Layout::from_size_align(2 * BLOCK_SIZE, BLOCK_ALIGN).unwrap();
+        assert_eq!(
+            source.get_huge(smaller),
+            NonNull::new(huge.as_ptr().add(BLOCK_SIZE))
+        );
+    }
+
+    let heap = Allocator::new(StaticRegionSource::new(vec![0u8; 64 * BLOCK_SIZE].leak()));
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                for _ in 0..100 {
+                    let mut my_vec = Vec::new_in(&heap);
+                    my_vec.extend(0..20_000);
+                    assert!(my_vec.iter().copied().eq(0..20_000));
+                }
+            });
+        }
+    });
+}
+
+#[test]
+fn region_list_source() {
+    use stack_alloc::memory_source::{BLOCK_ALIGN, BLOCK_SIZE};
+    use stack_alloc::RegionListSource;
+
+    let memory = vec![0u8; 12 * BLOCK_SIZE + BLOCK_ALIGN].leak();
+    let start = memory.as_mut_ptr();
+    let aligned = start.align_offset(BLOCK_ALIGN) + BLOCK_SIZE;
+    let source = RegionListSource::<2>::new();
+    unsafe {
+        // 3 blocks, with 99 bytes before and 100 after
+        assert!(source.add_region(start.add(aligned - 99), 3 * BLOCK_SIZE + 199));
+        // Too small for a block, so it doesn't take a slot
+        assert!(source.add_region(start.add(aligned + 4 * BLOCK_SIZE), 1000));
+        // 2 blocks, with 100 bytes before for their links
+        assert!(source.add_region(
+            start.add(aligned + 5 * BLOCK_SIZE - 100),
+            2 * BLOCK_SIZE + 100
+        ));
+        assert!(!source.add_region(start.add(aligned + 8 * BLOCK_SIZE), 2 * BLOCK_SIZE));
+    }
+    assert_eq!(source.capacity(), 5);
+    assert_eq!(source.wasted(), 199 + 1000 + 100);
+
+    unsafe {
+        let blocks: Vec<_> = std::iter::from_fn(|| source.get_block()).collect();
+        assert_eq!(blocks.len(), 5);
+        source.return_block(blocks[0]);
+        source.return_block(blocks[3]);
+        assert_eq!(source.get_block(), Some(blocks[0]));
+        assert_eq!(source.get_block(), Some(blocks[3]));
+        for &block in &blocks {
+            source.return_block(block);
+        }
+    }
+
+    let heap = Allocator::new(source);
+    let mut my_vec = Vec::new_in(&heap);
+    my_vec.extend(0..20_000);
+    assert!(my_vec.iter().copied().eq(0..20_000));
+}
+
+#[test]
+fn global_alloc_source() {
+    use stack_alloc::memory_source::{GlobalAllocSource, BLOCK_SIZE};
+    use std::alloc::System;
+
+    // The blocks and huge allocations that haven't been given back yet
+    static BLOCKS: AtomicUsize = AtomicUsize::new(0);
+    static HUGE: AtomicUsize = AtomicUsize::new(0);
+    struct Counted;
+    fn count(layout: Layout) -> &'static AtomicUsize {
+        if layout.size() == BLOCK_SIZE {
+            &BLOCKS
+        } else {
+            &HUGE
+        }
+    }
+    unsafe impl GlobalAlloc for Counted {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            count(layout).fetch_add(1, Ordering::SeqCst);
+            System.alloc(layout)
+        }
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            count(layout).fetch_sub(1, Ordering::SeqCst);
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    // One heap, over and over, like one for each request
+    let mut heap = Allocator::new(GlobalAllocSource(Counted));
+    for _ in 0..10 {
+        let mut my_vec = Vec::new_in(&heap);
+        my_vec.extend(0..1_000_000);
+        assert!(my_vec.iter().copied().eq(0..1_000_000));
+        let mut small = Vec::new_in(&heap);
+        small.extend(my_vec.iter().map(|x| Box::new_in(*x, &heap)).take(1000));
+        assert!(small.iter().map(|x| **x).eq(0..1000));
+        assert!(BLOCKS.load(Ordering::SeqCst) > 0);
+        assert_eq!(HUGE.load(Ordering::SeqCst), 1);
+
+        // Whatever's still allocated goes back too
+        std::mem::forget(small);
+        std::mem::forget(my_vec);
+        unsafe { heap.release_all() };
+        assert_eq!(BLOCKS.load(Ordering::SeqCst), 0);
+        assert_eq!(HUGE.load(Ordering::SeqCst), 0);
+    }
+}
+
+struct CountingSource {
+    got: AtomicUsize,
+    returned: AtomicUsize,
+}
+
+impl CountingSource {
+    const fn new() -> Self {
+        CountingSource {
+            got: AtomicUsize::new(0),
+            returned: AtomicUsize::new(0),
+        }
+    }
+    fn got(&self) -> usize {
+        self.got.load(Ordering::SeqCst)
+    }
+    fn returned(&self) -> usize {
+        self.returned.load(Ordering::SeqCst)
+    }
+}
+
+unsafe impl MemorySource for CountingSource {
+    unsafe fn get_block(&self) -> Option<NonNull<u8>> {
+        self.got.fetch_add(1, Ordering::SeqCst);
+        TestMemorySource.get_block()
+    }
+    unsafe fn return_block(&self, block: NonNull<u8>) {
+        self.returned.fetch_add(1, Ordering::SeqCst);
+        TestMemorySource.return_block(block)
+    }
+    unsafe fn get_huge(&self, layout: Layout) -> Option<NonNull<u8>> {
+        TestMemorySource.get_huge(layout)
+    }
+    unsafe fn return_huge(&self, ptr: NonNull<u8>, layout: Layout) {
+        TestMemorySource.return_huge(ptr, layout)
+    }
+}
+
+#[test]
+fn separate_heaps() {
+    let first = Allocator::new(CountingSource::new());
+    let second = Allocator::new(CountingSource::new());
+    let layout = Layout::from_size_align(100, 8).unwrap();
+    unsafe {
+        let ptr = first.alloc(layout);
+        assert!(!ptr.is_null());
+        assert!(first.source().got() > 0);
+        assert_eq!(second.source().got(), 0);
+
+        let other_ptr = second.alloc(layout);
+        assert!(!other_ptr.is_null());
+        assert!(second.source().got() > 0);
+
+        first.dealloc(ptr, layout);
+        second.dealloc(other_ptr, layout);
+    }
+}
+
+#[test]
+fn empty_blocks_are_returned() {
+    let heap = Allocator::new(CountingSource::new());
+    without_quarantine(&heap);
+    let layout = Layout::from_size_align(200 * 1024, 8).unwrap();
+    unsafe {
+        let first = heap.alloc(layout);
+        let got = heap.source().got();
+        let second = heap.alloc(layout);
+        let third = heap.alloc(layout);
+        assert_eq!(heap.source().got(), got + 2);
+        assert_eq!(heap.source().returned(), 0);
+
+        // The second block is now completely empty, and isn't at the head of the chain
+        heap.dealloc(second, layout);
+        assert_eq!(heap.source().returned(), 1);
+
+        heap.dealloc(first, layout);
+        heap.dealloc(third, layout);
+    }
+}
+
+#[test]
+fn holes_are_reused() {
+    let heap = Allocator::new(CountingSource::new());
+    without_quarantine(&heap);
+    let layout = Layout::from_size_align(100 * 1024, 8).unwrap();
+    unsafe {
+        let first = heap.alloc(layout);
+        let second = heap.alloc(layout);
+        let got = heap.source().got();
+
+        // There's no room left at the top of the block, but there's a hole where `first` was
+        heap.dealloc(first, layout);
+        let third = heap.alloc(layout);
+        assert_eq!(third, first);
+        assert_eq!(heap.source().got(), got);
+
+        heap.dealloc(second, layout);
+        heap.dealloc(third, layout);
+    }
+}
+
+#[test]
+fn full_stacks_are_not_reused() {
+    let heap = Allocator::new(CountingSource::new());
+    let small_layout = Layout::from_size_align(100 * 1024, 8).unwrap();
+    // Every chunk of a block
+    let layout = Layout::from_size_align(255 * 1024, 8).unwrap();
+    unsafe {
+        // The first block has some metadata in it, so this makes sure there's room for it
+        let small = heap.alloc(small_layout);
+        heap.dealloc(small, small_layout);
+
+        let first = heap.alloc(layout);
+        let second = heap.alloc(layout);
+        assert!(!first.is_null());
+        assert!(!second.is_null());
+        assert_ne!(first, second);
+
+        heap.dealloc(first, layout);
+        heap.dealloc(second, layout);
+    }
+}
+
+#[test]
+fn long_chains() {
+    let heap = Allocator::new(CountingSource::new());
+    without_quarantine(&heap);
+    let layout = Layout::from_size_align(200 * 1024, 8).unwrap();
+    let small_layout = Layout::from_size_align(4, 4).unwrap();
+    unsafe {
+        let blocks: Vec<_> = (0..100).map(|_| heap.alloc(layout)).collect();
+        let smalls: Vec<_> = (0..1000).map(|_| heap.alloc(small_layout)).collect();
+
+        // Freeing the oldest ones first means going all the way down the chains
+        for &ptr in smalls.iter() {
+            heap.dealloc(ptr, small_layout);
+        }
+        for &ptr in blocks[..99].iter() {
+            heap.dealloc(ptr, layout);
+        }
+        // A few blocks are kept for the metadata and the heads of the smaller chains
+        assert!(heap.source().returned() > 90);
+        heap.dealloc(blocks[99], layout);
+    }
+}
+
+#[test]
+fn check() {
+    let heap = Allocator::new(TestMemorySource);
+    assert_eq!(heap.check(), Ok(()));
+    let sizes = [3, 20, 100, 1000, 5000, 300 * 1024];
+    unsafe {
+        let pointers: Vec<_> = (0..600)
+            .map(|i| {
+                let layout = Layout::from_size_align(sizes[i % sizes.len()], 1).unwrap();
+                (heap.alloc(layout), layout)
+            })
+            .collect();
+        assert_eq!(heap.check(), Ok(()));
+
+        // Leave holes in every stack, and empty some of them out
+        for &(ptr, layout) in pointers.iter().step_by(3) {
+            heap.dealloc(ptr, layout);
+        }
+        assert_eq!(heap.check(), Ok(()));
+        for (i, &(ptr, layout)) in pointers.iter().enumerate() {
+            if i % 3 != 0 {
+                heap.dealloc(ptr, layout);
+            }
+        }
+        assert_eq!(heap.check(), Ok(()));
+    }
+}
+
+#[test]
+fn visit() {
+    use stack_alloc::check::Bucket;
+
+    let heap = Allocator::new(TestMemorySource);
+    let sizes = [3, 20, 100, 1000, 5000, 300 * 1024];
+    // Red zones make everything bigger, so most of it goes in the next bucket up
+    let buckets = if cfg!(feature = "red_zones") {
+        [
+            Bucket::Small,
+            Bucket::Medium,
+            Bucket::Medium,
+            Bucket::Large,
+            Bucket::VeryLarge,
+            Bucket::Huge,
+        ]
+    } else {
+        [
+            Bucket::VerySmall,
+            Bucket::Small,
+            Bucket::Medium,
+            Bucket::Large,
+            Bucket::VeryLarge,
+            Bucket::Huge,
+        ]
+    };
+    unsafe {
+        let pointers: Vec<_> = sizes
+            .iter()
+            .map(|&size| heap.alloc(Layout::from_size_align(size, 1).unwrap()))
+            .collect();
+
+        // The stacks and metadata are allocations too, but they shouldn't show up
+        let mut live = Vec::new();
+        heap.visit(|allocation| live.push(*allocation));
+        assert_eq!(live.len(), sizes.len());
+        for (&ptr, &bucket) in pointers.iter().zip(buckets.iter()) {
+            let allocation = live
+                .iter()
+                .find(|allocation| allocation.address == ptr as usize)
+                .unwrap();
+            assert_eq!(allocation.bucket, bucket);
+            assert_eq!(
+                allocation.size,
+                heap.usable_size(NonNull::new(ptr).unwrap()).unwrap()
+            );
+        }
+
+        for (&ptr, &size) in pointers.iter().zip(sizes.iter()) {
+            heap.dealloc(ptr, Layout::from_size_align(size, 1).unwrap());
+        }
+        let mut count = 0;
+        heap.visit(|_| count += 1);
+        assert_eq!(count, 0);
+    }
+}
+
+#[test]
+fn dump() {
+    use stack_alloc::check::Bucket;
+    use stack_alloc::dump::{Reader, Record};
+
+    let heap = Allocator::new(TestMemorySource);
+    without_quarantine(&heap);
+    let small = Layout::from_size_align(20, 8).unwrap();
+    let huge = Layout::from_size_align(300 * 1024, 8).unwrap();
+    unsafe {
+        let first = heap.alloc(small);
+        let second = heap.alloc(small);
+        let third = heap.alloc(huge);
+        heap.dealloc(first, small);
+
+        let mut bytes = Vec::new();
+        heap.dump(|piece| {
+            bytes.extend_from_slice(piece);
+            Ok::<(), ()>(())
+        })
+        .unwrap();
+        let records: Vec<_> = Reader::new(&bytes).unwrap().map(Result::unwrap).collect();
+
+        // The dump has the padded allocations, with red zones: 32 bytes in front, and 16 after,
+        // which pushes the small ones up into medium chunks
+        let (bucket, map, front, padding): (_, &[u8], _, _) = if cfg!(feature = "red_zones") {
+            (Bucket::Medium, b"..#", 32, 48)
+        } else {
+            (Bucket::Small, b"...#", 0, 0)
+        };
+        let small_stacks: Vec<_> = records
+            .iter()
+            .filter_map(|record| match record {
+                Record::Stack(stack) if stack.bucket == bucket => Some(stack),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(small_stacks.len(), 1);
+        assert_eq!(&small_stacks[0].map()[..map.len()], map);
+        assert!(small_stacks[0].contains(second as usize));
+        assert!(records.contains(&Record::Huge {
+            record: match records.last().unwrap() {
+                Record::Huge { record, .. } => *record,
+                _ => unreachable!(),
+            },
+            address: third as usize - front,
+            size: huge.size() + padding,
+        }));
+
+        // It stops at the first error
+        assert_eq!(heap.dump(|_| Err(5)), Err(5));
+        assert!(Reader::new(&bytes[..bytes.len() - 1])
+            .unwrap()
+            .any(|record| record.is_err()));
+
+        heap.dealloc(second, small);
+        heap.dealloc(third, huge);
+    }
+}
+
+#[cfg(all(feature = "trace", target_os = "linux"))]
+#[test]
+fn trace() {
+    use stack_alloc::trace::{Op, Reader};
+    use std::os::unix::io::AsRawFd;
+
+    let path = std::env::temp_dir().join(format!("stack_alloc-{}.trace", std::process::id()));
+    let file = std::fs::File::create(&path).unwrap();
+    let heap = Allocator::new(TestMemorySource);
+    let layout = Layout::from_size_align(20, 8).unwrap();
+    unsafe {
+        let before = heap.alloc(layout);
+        heap.start_trace(file.as_raw_fd());
+        // Enough to fill the buffer a few times
+        for _ in 0..300 {
+            let ptr = heap.alloc(layout);
+            let ptr = heap.realloc(ptr, layout, 100);
+            heap.dealloc(ptr, Layout::from_size_align(100, 8).unwrap());
+        }
+        heap.free(NonNull::new(before).unwrap());
+        // Growing to a stricter alignment records both
+        let aligned = Layout::from_size_align(100, 64).unwrap();
+        let ptr = NonNull::new(heap.alloc(layout)).unwrap();
+        let grown = heap.grow(ptr, layout, aligned).unwrap();
+        heap.deallocate(grown.cast(), aligned);
+        assert!(heap.stop_trace());
+        heap.dealloc(heap.alloc(layout), layout);
+    }
+
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let events: Vec<_> = Reader::new(&bytes).unwrap().map(Result::unwrap).collect();
+    assert_eq!(events.len(), 3 * 300 + 4);
+    for round in events[..900].chunks(3) {
+        assert_eq!(round[0].op, Op::Alloc);
+        assert_eq!((round[0].size, round[0].align), (20, 8));
+        assert_eq!(round[1].op, Op::Realloc);
+        assert_eq!(round[1].ptr, round[0].ptr);
+        assert_eq!((round[1].new_size, round[1].new_align), (100, 8));
+        assert_eq!(round[2].op, Op::Dealloc);
+        assert_eq!(round[2].ptr, round[1].new_ptr);
+    }
+    assert_eq!(events[900].op, Op::Dealloc);
+    assert_eq!(events[900].size, 0);
+    assert_eq!(events[902].op, Op::Realloc);
+    assert_eq!((events[902].align, events[902].new_align), (8, 64));
+    assert_eq!(events[903].ptr, events[902].new_ptr);
+    assert!(events.windows(2).all(|pair| pair[0].time <= pair[1].time));
+}
+
+#[cfg(feature = "checked")]
+#[test]
+fn checked() {
+    use stack_alloc::checked::FreeError;
+    use std::sync::Mutex;
+
+    static ERRORS: Mutex<Vec<FreeError>> = Mutex::new(Vec::new());
+    fn record(error: &FreeError) {
+        ERRORS.lock().unwrap().push(*error);
+    }
+
+    let heap = Allocator::new(TestMemorySource);
+    heap.set_error_handler(record);
+    let small = Layout::from_size_align(20, 8).unwrap();
+    let big = Layout::from_size_align(100, 8).unwrap();
+    let huge = Layout::from_size_align(300 * 1024, 8).unwrap();
+    let mut outside = 0u64;
+    let outside = &mut outside as *mut u64 as *mut u8;
+    unsafe {
+        let ptr = heap.alloc(small);
+        heap.dealloc(ptr, small);
+        heap.dealloc(ptr, small);
+
+        heap.dealloc(outside, small);
+        heap.free(NonNull::new(outside).unwrap());
+
+        let ptr = heap.alloc(small);
+        heap.dealloc(ptr, big);
+        assert!(heap.realloc(ptr, big, 200).is_null());
+        heap.dealloc(ptr, small);
+
+        let ptr = heap.alloc(big);
+        heap.dealloc(ptr.add(8), big);
+        let huge_ptr = heap.alloc(huge);
+        heap.free(NonNull::new(huge_ptr.add(4096)).unwrap());
+        heap.dealloc(ptr, big);
+        heap.dealloc(huge_ptr, huge);
+
+        // A huge allocation shrunk in place is freed with its new size
+        let bigger = Layout::from_size_align(1 << 20, 8).unwrap();
+        let ptr = heap.alloc(bigger);
+        let shrunk = heap.realloc(ptr, bigger, 600 * 1024);
+        if !cfg!(feature = "red_zones") {
+            assert_eq!(shrunk, ptr);
+        }
+        heap.dealloc(shrunk, Layout::from_size_align(600 * 1024, 8).unwrap());
+    }
+    let address = |ptr: *mut u8| ptr as usize;
+    let errors = ERRORS.lock().unwrap();
+    assert!(matches!(errors[0], FreeError::DoubleFree { .. }));
+    assert_eq!(
+        errors[1],
+        FreeError::NotOwned {
+            address: address(outside)
+        }
+    );
+    assert_eq!(
+        errors[2],
+        FreeError::NotOwned {
+            address: address(outside)
+        }
+    );
+    assert!(matches!(errors[3], FreeError::WrongSize { size: 100, .. }));
+    assert!(matches!(errors[4], FreeError::WrongSize { size: 100, .. }));
+    assert!(matches!(errors[5], FreeError::InteriorPointer { .. }));
+    assert!(matches!(errors[6], FreeError::InteriorPointer { .. }));
+    assert_eq!(errors.len(), 7);
+    assert_eq!(heap.check(), Ok(()));
+}
+
+/// Runs `op` in a copy of this test's process, and checks that it aborted with `message`.  The
+/// heap's errors abort rather than panic, so they can't be caught in here.
+#[cfg(any(feature = "checked", feature = "red_zones", feature = "quarantine"))]
+fn assert_aborts(test: &str, message: &str, op: impl FnOnce()) {
+    if std::env::var_os("STACK_ALLOC_CHILD").is_some() {
+        op();
+        return;
+    }
+    let output = std::process::Command::new(std::env::current_exe().unwrap())
+        .args([test, "--exact", "--nocapture"])
+        .env("STACK_ALLOC_CHILD", "1")
+        .output()
+        .unwrap();
+    // Killed by a signal, rather than exiting like a failed test
+    assert_eq!(output.status.code(), None);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains(message), "{}", stderr);
+}
+
+#[cfg(feature = "checked")]
+#[test]
+fn checked_aborts_by_default() {
+    let heap = Allocator::new(TestMemorySource);
+    let layout = Layout::from_size_align(20, 8).unwrap();
+    assert_aborts("checked_aborts_by_default", "isn't allocated", || unsafe {
+        let ptr = heap.alloc(layout);
+        heap.dealloc(ptr, layout);
+        heap.dealloc(ptr, layout);
+    });
+}
+
+#[cfg(feature = "checked")]
+#[test]
+fn checked_in_parallel() {
+    use stack_alloc::checked::FreeError;
+
+    static ERRORS: AtomicUsize = AtomicUsize::new(0);
+    fn record(error: &FreeError) {
+        assert!(matches!(error, FreeError::DoubleFree { .. }));
+        ERRORS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    let heap = Allocator::new(TestMemorySource);
+    heap.set_error_handler(record);
+    let layout = Layout::from_size_align(20, 8).unwrap();
+    // Two threads free the same memory at once, and only one of them can get it
+    for round in 1..=200 {
+        let ptr = unsafe { heap.alloc(layout) } as usize;
+        let barrier = std::sync::Barrier::new(2);
+        std::thread::scope(|scope| {
+            for _ in 0..2 {
+                scope.spawn(|| {
+                    barrier.wait();
+                    unsafe { heap.dealloc(ptr as *mut u8, layout) };
+                });
+            }
+        });
+        assert_eq!(ERRORS.load(Ordering::Relaxed), round);
+    }
+    assert_eq!(heap.check(), Ok(()));
+}
+
+#[cfg(feature = "red_zones")]
+#[test]
+fn red_zones() {
+    use stack_alloc::red_zone::{Damage, RedZoneError};
+
+    let heap = Allocator::new(TestMemorySource);
+    let sizes = [3, 20, 100, 1000, 5000, 300 * 1024];
+    unsafe {
+        for &size in sizes.iter() {
+            for &align in [1, 8, 64, 4096].iter() {
+                let layout = Layout::from_size_align(size, align).unwrap();
+                let ptr = heap.alloc(layout);
+                assert_eq!(ptr as usize % align, 0);
+                assert_eq!(heap.usable_size(NonNull::new(ptr).unwrap()), Some(size));
+                ptr.write_bytes(0xFF, size);
+                assert_eq!(heap.check_red_zones(), Ok(()));
+                if align == 1 {
+                    heap.free(NonNull::new(ptr).unwrap());
+                } else {
+                    let ptr = heap.realloc(ptr, layout, size + 1);
+                    heap.dealloc(ptr, Layout::from_size_align(size + 1, align).unwrap());
+                }
+            }
+        }
+
+        let layout = Layout::from_size_align(20, 8).unwrap();
+        let ptr = heap.alloc(layout);
+        let mut live = Vec::new();
+        heap.visit(|allocation| live.push((allocation.address, allocation.size)));
+        assert_eq!(live, [(ptr as usize, 20)]);
+
+        // One byte too far either way
+        ptr.add(20).write(0);
+        let overflow = RedZoneError {
+            address: ptr as usize,
+            size: 20,
+            damage: Damage::Overflow,
+        };
+        assert_eq!(heap.check_red_zones(), Err(overflow));
+        ptr.add(20).write(0xCB);
+        ptr.sub(1).write(0);
+        assert_eq!(
+            heap.check_red_zones(),
+            Err(RedZoneError {
+                damage: Damage::Underflow,
+                ..overflow
+            })
+        );
+        assert_aborts("red_zones", "Red zone damaged", || {
+            heap.dealloc(ptr, layout)
+        });
+    }
+}
+
+#[cfg(feature = "quarantine")]
+#[test]
+fn quarantine() {
+    use stack_alloc::quarantine::POISON;
+
+    let heap = Allocator::new(TestMemorySource);
+    let layout = Layout::from_size_align(100, 8).unwrap();
+    unsafe {
+        heap.set_quarantine_size(1000);
+        let first = heap.alloc(layout);
+        first.write_bytes(1, 100);
+        heap.dealloc(first, layout);
+        // It's poisoned, and not handed out again while it's held back
+        assert!((0..100).all(|i| *first.add(i) == POISON));
+        assert_eq!(heap.usable_size(NonNull::new(first).unwrap()), None);
+        let mut count = 0;
+        heap.visit(|_| count += 1);
+        assert_eq!(count, 0);
+        let pointers: Vec<_> = (0..4).map(|_| heap.alloc(layout)).collect();
+        assert!(!pointers.contains(&first));
+        for &ptr in pointers.iter() {
+            heap.dealloc(ptr, layout);
+        }
+        // Only 1000 bytes are held back, so the oldest have come out
+        heap.set_quarantine_size(0);
+        assert_eq!(heap.check(), Ok(()));
+
+        // Only so many allocations are held back, however small they are
+        heap.set_quarantine_size(1 << 20);
+        let small = Layout::from_size_align(16, 8).unwrap();
+        let freed: Vec<_> = (0..3000).map(|_| heap.alloc(small)).collect();
+        for &ptr in freed.iter() {
+            heap.dealloc(ptr, small);
+        }
+        heap.visit(|_| count += 1);
+        assert_eq!(count, 0);
+        for &ptr in freed.iter() {
+            assert_eq!(heap.usable_size(NonNull::new(ptr).unwrap()), None);
+        }
+        let again: Vec<_> = (0..1000).map(|_| heap.alloc(small)).collect();
+        assert!(again.iter().all(|ptr| !freed[3000 - 1024..].contains(ptr)));
+        for ptr in again {
+            heap.dealloc(ptr, small);
+        }
+        heap.set_quarantine_size(0);
+        assert_eq!(heap.check(), Ok(()));
+        heap.set_quarantine_size(1000);
+
+        // Writing to it after it's freed is caught when it comes out
+        let ptr = heap.alloc(layout);
+        heap.dealloc(ptr, layout);
+        ptr.add(50).write(0);
+        assert_aborts("quarantine", "Use after free", || {
+            heap.set_quarantine_size(0)
+        });
+    }
+}
+
+#[cfg(all(feature = "stats", not(feature = "red_zones")))]
+#[test]
+fn misaligned_holes() {
+    let heap = Allocator::new(TestMemorySource);
+    without_quarantine(&heap);
+    let layout = Layout::from_size_align(64, 64).unwrap();
+    let aligned = Layout::from_size_align(64, 128).unwrap();
+    unsafe {
+        // Every other chunk is free, but none of them are aligned enough
+        let (kept, freed): (Vec<_>, Vec<_>) = (0..64 * 50)
+            .map(|_| heap.alloc(layout))
+            .partition(|&ptr| (ptr as usize).is_multiple_of(128));
+        for &ptr in freed.iter() {
+            heap.dealloc(ptr, layout);
+        }
+        let stacks = heap.stats().medium.stacks;
+        assert!(stacks >= 50);
+
+        // Only a few of the stacks are tried before a new one's started
+        let ptr = heap.alloc(aligned);
+        assert_eq!(ptr as usize % 128, 0);
+        let stats = heap.stats();
+        assert!(stats.medium.missed_probes < 10);
+        assert_eq!(stats.medium.stacks, stacks + 1);
+        heap.dealloc(ptr, aligned);
+        for &ptr in kept.iter() {
+            heap.dealloc(ptr, layout);
+        }
+    }
+}
+
+#[cfg(feature = "stats")]
+#[test]
+fn stats() {
+    use stack_alloc::stats::BucketStats;
+
+    let heap = Allocator::new(CountingSource::new());
+    without_quarantine(&heap);
+    let small = Layout::from_size_align(20, 8).unwrap();
+    let big = Layout::from_size_align(5000, 8).unwrap();
+    let huge = Layout::from_size_align(300 * 1024, 8).unwrap();
+    unsafe {
+        let first = heap.alloc(small);
+        let second = heap.alloc(big);
+        let third = heap.alloc(huge);
+        // It's at the top of its stack, so it can grow where it is, unless there are red zones
+        // after it that would have to move
+        let red_zones = cfg!(feature = "red_zones");
+        let grown = heap.realloc(first, small, 30);
+        assert_eq!(grown == first, !red_zones);
+
+        let stats = heap.stats();
+        assert_eq!(stats.allocs, 3);
+        assert_eq!(stats.reallocs, 1);
+        assert_eq!(stats.reallocs_in_place, !red_zones as usize);
+        assert_eq!(stats.reallocs_moved, red_zones as usize);
+        assert_eq!(stats.bytes_requested, 20 + 5000 + 300 * 1024 + 30);
+        assert_eq!(stats.bytes_allocated, 24 + 8192 + 300 * 1024 + 32);
+        assert_eq!(stats.blocks_obtained, heap.source().got());
+        // With red zones, the small allocations are padded into medium chunks: 78 bytes is 2
+        let (bucket, chunks) = if red_zones {
+            (stats.medium, 2)
+        } else {
+            (stats.small, 4)
+        };
+        assert_eq!(
+            bucket,
+            BucketStats {
+                stacks: 1,
+                chunks_in_use: chunks,
+                missed_probes: 0,
+            }
+        );
+        assert_eq!(stats.huge_allocations, 1);
+
+        heap.dealloc(grown, Layout::from_size_align(30, 8).unwrap());
+        heap.dealloc(second, big);
+        heap.dealloc(third, huge);
+        let stats = heap.stats();
+        assert_eq!(stats.frees, 3);
+        assert_eq!(stats.small.chunks_in_use, 0);
+        assert_eq!(stats.medium.chunks_in_use, 0);
+        assert_eq!(stats.huge_allocations, 0);
+    }
+}
+
+#[test]
+fn allocator_api() {
+    let heap = Allocator::new(TestMemorySource);
+
+    let mut my_vec = Vec::new_in(&heap);
+    for i in 0..1000 {
+        my_vec.push(i);
+    }
+    my_vec.shrink_to_fit();
+    assert!(my_vec.iter().copied().eq(0..1000));
+
+    let my_box = Box::new_in([1_u8; 100], &heap);
+    assert_eq!(*my_box, [1; 100]);
+
+    // The real size is rounded up to a whole number of 64-byte chunks, unless the rest is canaries
+    let layout = Layout::from_size_align(100, 8).unwrap();
+    let memory = heap.allocate_zeroed(layout).unwrap();
+    let expected = if cfg!(feature = "red_zones") {
+        100
+    } else {
+        128
+    };
+    assert_eq!(memory.len(), expected);
+    unsafe {
+        assert!(memory.as_ref().iter().all(|&x| x == 0));
+        heap.deallocate(memory.cast(), layout);
+    }
+}
+
+#[test]
+fn free_without_layout() {
+    let heap = Allocator::new(TestMemorySource);
+    let sizes = [3, 20, 100, 1000, 5000, 300 * 1024];
+    let mut pointers = Vec::new();
+    for &size in sizes.iter() {
+        let ptr = unsafe { heap.alloc(Layout::from_size_align(size, 1).unwrap()) };
+        pointers.push(NonNull::new(ptr).unwrap());
+    }
+    // Sizes are rounded up to whole chunks, but stay in the same size category.  With red zones,
+    // the rest is canaries, so it's just what was asked for.
+    let usable_sizes: Vec<_> = pointers.iter().map(|&ptr| heap.usable_size(ptr)).collect();
+    let expected = if cfg!(feature = "red_zones") {
+        [3, 20, 100, 1000, 5000, 300 * 1024]
+    } else {
+        [3, 24, 128, 1024, 8192, 300 * 1024]
+    };
+    assert_eq!(usable_sizes, expected.map(Some));
+    for &ptr in pointers.iter() {
+        unsafe { heap.free(ptr) };
+        assert_eq!(heap.usable_size(ptr), None);
+    }
+}
+
+/// Has a few threads fight over the heap
+fn contend<L: RawLock + Sync>(heap: &'static Allocator<TestMemorySource, L>) {
+    let threads: Vec<_> = (0..4)
+        .map(|thread| {
+            std::thread::spawn(move || {
+                let mut vecs = Vec::new();
+                for i in 0..2000_usize {
+                    let mut v = Vec::new_in(heap);
+                    v.extend(0..i % 100);
+                    vecs.push((thread, v));
+                    if i % 2 == 0 {
+                        vecs.swap_remove(i % vecs.len());
+                    }
+                }
+                for (t, v) in vecs.iter() {
+                    assert_eq!(*t, thread);
+                    assert!(v.iter().copied().eq(0..v.len()));
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
+
+#[test]
+fn locks() {
+    static SPIN: Allocator<TestMemorySource> = Allocator::new(TestMemorySource);
+    static BACKOFF: Allocator<TestMemorySource, BackoffLock> =
+        Allocator::with_lock(TestMemorySource);
+    contend(&SPIN);
+    contend(&BACKOFF);
+
+    #[cfg(all(feature = "futex", target_os = "linux"))]
+    {
+        static FUTEX: Allocator<TestMemorySource, FutexLock> =
+            Allocator::with_lock(TestMemorySource);
+        contend(&FUTEX);
+    }
+
+    let heap: Allocator<_, NoLock> = Allocator::with_lock(TestMemorySource);
+    let mut my_vec = Vec::new_in(&heap);
+    my_vec.extend(0..1000);
+    assert!(my_vec.iter().copied().eq(0..1000));
+}
+
+#[test]
+fn size_classes_in_parallel() {
+    static HEAP: Allocator<TestMemorySource> = Allocator::new(TestMemorySource);
+    // One thread for each size category, each using a different lock
+    let sizes = [3, 20, 100, 1000, 5000, 300 * 1024];
+    let threads: Vec<_> = sizes
+        .iter()
+        .map(|&size| {
+            std::thread::spawn(move || {
+                let layout = Layout::from_size_align(size, 1).unwrap();
+                let mut kept = Vec::new();
+                for i in 0..500_usize {
+                    let ptr = unsafe { HEAP.alloc(layout) };
+                    assert!(!ptr.is_null());
+                    unsafe { ptr.write_bytes(i as u8, size) };
+                    if i % 4 == 0 {
+                        kept.push((ptr as usize, i as u8));
+                    } else {
+                        unsafe { HEAP.dealloc(ptr, layout) };
+                    }
+                }
+                kept
+            })
+        })
+        .collect();
+    for (thread, &size) in threads.into_iter().zip(sizes.iter()) {
+        // Freed on a different thread than they were allocated on
+        for (ptr, byte) in thread.join().unwrap() {
+            let ptr = NonNull::new(ptr as *mut u8).unwrap();
+            assert!(HEAP.usable_size(ptr).unwrap() >= size);
+            let memory = unsafe { std::slice::from_raw_parts(ptr.as_ptr(), size) };
+            assert!(memory.iter().all(|&x| x == byte));
+            unsafe { HEAP.free(ptr) };
+        }
+    }
+}
+
+#[cfg(feature = "thread_cache")]
+static CACHED: Allocator<TestMemorySource> =
+    unsafe { Allocator::with_thread_cache(TestMemorySource) };
+
+#[cfg(feature = "thread_cache")]
+#[test]
+fn thread_caches_are_flushed_on_exit() {
+    // With `checked`, `red_zones` or `quarantine`, frees skip the cache and go straight back to the
+    // heap, and past the quarantine if it's turned off
+    let bypassed = cfg!(any(
+        feature = "checked",
+        feature = "red_zones",
+        feature = "quarantine"
+    ));
+    without_quarantine(&CACHED);
+    let layout = Layout::from_size_align(16, 8).unwrap();
+    let freed = std::thread::spawn(move || unsafe {
+        let ptr = NonNull::new(CACHED.alloc(layout)).unwrap();
+        CACHED.dealloc(ptr.as_ptr(), layout);
+        // Otherwise it's only gone as far as the thread's cache
+        let cached = if bypassed { None } else { Some(16) };
+        assert_eq!(CACHED.usable_size(ptr), cached);
+        ptr.as_ptr() as usize
+    })
+    .join()
+    .unwrap();
+    let freed = NonNull::new(freed as *mut u8).unwrap();
+    assert_eq!(CACHED.usable_size(freed), None);
+}
+
+#[cfg(feature = "thread_cache")]
+#[test]
+fn thread_caches() {
+    let threads: Vec<_> = (0..8)
+        .map(|thread| {
+            std::thread::spawn(move || {
+                let mut boxes = Vec::new();
+                for i in 0..10000_usize {
+                    boxes.push(Box::new_in([thread, i], &CACHED));
+                    if i % 3 == 0 {
+                        boxes.swap_remove(i % boxes.len());
+                    }
+                }
+                for b in boxes.iter() {
+                    assert_eq!(b[0], thread);
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}